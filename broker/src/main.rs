@@ -1,104 +1,44 @@
-mod broker_connection;
-mod data_structures;
-mod handlers;
-mod majordomo_context;
-mod mmi_handler;
-
-use data_structures::SocketType;
-use domolib::errors::RustydomoError;
+use domolib::broker::{Broker, BrokerConfig, OtlpFileExporter};
 use env_logger::Env;
-use log::info;
-use majordomo_context::MajordomoContext;
-use zmq::Context;
+use log::{info, LevelFilter};
+use tracing_subscriber::layer::SubscriberExt;
+
+fn main() {
+    // records of all levels go through the logger so that the level can be changed at runtime
+    // by the admin endpoint, the actual level being the max level set below
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .parse_env(Env::default())
+        .init();
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(LevelFilter::Info);
+    }
 
-fn main() -> ! {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let config = match BrokerConfig::from_args(std::env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => return,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
     info!("Welcome to The Majordomo Broker");
-    let zmq_ctx = Context::new();
-    info!("Creating clients related connection...");
-    let clients_connection = broker_connection::bind_router_connection(
-        &zmq_ctx,
-        "tcp://*:5000",
-        "inproc://monitor_clients_router",
-    )
-    .expect("Failed to create clients connection");
-
-    info!("Creating services related connection...");
-    let workers_connection = broker_connection::bind_router_connection(
-        &zmq_ctx,
-        "tcp://*:6000",
-        "inproc://monitor_services_router",
-    )
-    .expect("Failed to create services related connection");
-
-    let mut ctx = MajordomoContext::new();
-
-    loop {
-        let sockets_stimulated = {
-            let mut poll_list = [
-                clients_connection.connection.as_poll_item(zmq::POLLIN),
-                clients_connection
-                    .monitor_connection
-                    .as_poll_item(zmq::POLLIN),
-                workers_connection.connection.as_poll_item(zmq::POLLIN),
-                workers_connection
-                    .monitor_connection
-                    .as_poll_item(zmq::POLLIN),
-            ];
-
-            match zmq::poll(&mut poll_list, 400) {
-                Ok(_) => {
-                    // filter_map directly
-                    Ok(poll_list
-                        .into_iter()
-                        .enumerate()
-                        .filter_map(|(idx, entry)| -> Option<data_structures::SocketType> {
-                            // if there are events on curent connection, just save the socket type
-                            // so that it can be fetched afterwards
-
-                            if entry.get_revents() & zmq::POLLIN == zmq::POLLIN {
-                                Some(
-                                    data_structures::SocketType::try_from(idx)
-                                        .expect("invalid socket type"),
-                                )
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<data_structures::SocketType>>())
-                }
-                Err(err) => Err(RustydomoError::Unknown(err.to_string())),
-            }
-        };
-
-        match sockets_stimulated {
-            Ok(sockets_to_use) => sockets_to_use.iter().for_each(|type_| match type_ {
-                SocketType::ClientSocket => handlers::handle_client_messages(
-                    &clients_connection,
-                    &workers_connection,
-                    &mut ctx,
-                )
-                .expect("Failed to handle client message"),
-                SocketType::ClientMonitorSocket => {
-                    handlers::handle_client_monitor_messages(&clients_connection)
-                        .expect("Failed to handle client monitor message")
-                }
-                SocketType::ServiceSocket => handlers::handle_worker_messages(
-                    &clients_connection,
-                    &workers_connection,
-                    &mut ctx,
-                )
-                .expect("Failed to handle service message"),
-                SocketType::WorkerMonitorSocket => {
-                    handlers::handle_worker_monitor_messages(&workers_connection)
-                        .expect("Failed to handle service monitor message")
-                }
-            }),
-            _ => log::debug!("Erreur hein ?"),
-        };
-
-        ctx.check_expired_workers();
-        ctx.send_heartbeat(&workers_connection.connection).unwrap();
+    if let Some(path) = &config.trace_file {
+        let exporter = OtlpFileExporter::create(path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(exporter))
+            .expect("Failed to install the traces exporter");
     }
+
+    let broker = Broker::builder()
+        .with_config(config)
+        .build()
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+    broker.run().expect("Broker failure");
 }
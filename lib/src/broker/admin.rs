@@ -0,0 +1,421 @@
+use crate::broker::data_structures::ClientInteractionType;
+use crate::broker::dead_letters::DeadLetterKind;
+use crate::broker::dispatch::DispatchStrategy;
+use crate::broker::handlers;
+use crate::broker::majordomo_context::{MajordomoContext, Task};
+use crate::errors::RustydomoError;
+use log::LevelFilter;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use zmq::{Context, SocketType};
+
+// status codes sent as first frame of every admin answer, as done for MMI answers
+const STATUS_OK: &str = "200";
+const STATUS_BAD_REQUEST: &str = "400";
+const STATUS_NOT_FOUND: &str = "404";
+const STATUS_UNKNOWN_COMMAND: &str = "501";
+const STATUS_UNAVAILABLE: &str = "503";
+
+///
+/// Control endpoint used to manage the broker at runtime
+///
+/// Each request is a multipart message whose first frame is the command name, the next frames
+/// being its arguments. Answers start with a status frame, optionally followed by a JSON
+/// document:
+///
+/// * `services` - state of every known service
+/// * `workers` - state of every registered worker
+/// * `evict <IDENTITY>` - disconnects the worker with the given hexadecimal identity
+/// * `pause <SERVICE>` - queues the requests of a service instead of dispatching them
+/// * `resume <SERVICE>` - dispatches the queued requests and the new ones again
+/// * `drain <SERVICE>` - rejects new requests, then disconnects the service workers once idle
+/// * `dispatch <SERVICE> <STRATEGY>` - changes the dispatch strategy of a service
+/// * `log-level <LEVEL>` - changes the broker log level (`off`, `error` ... `trace`)
+/// * `dump` - whole broker state, including the requests being handled
+/// * `dead-letters` - requests and answers the broker could not deliver, without their frames
+/// * `dead-letter <ID>` - a dead letter with its frames, as hexadecimal strings
+/// * `replay <ID>` - dispatches an expired (without deadline) or undeliverable request again, or
+///   sends a lost answer again to its client
+///
+pub struct AdminServer {
+    pub connection: zmq::Socket,
+}
+
+type AdminAnswer = (&'static str, Option<String>);
+
+fn parse_identity(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(value.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+///
+/// Indicates whether or not only the local host can connect to the given endpoint, which is the
+/// case of `ipc://` and `inproc://` endpoints and of `tcp://` ones bound to a loopback address
+///
+pub fn is_local_endpoint(endpoint: &str) -> bool {
+    if endpoint.starts_with("ipc://") || endpoint.starts_with("inproc://") {
+        return true;
+    }
+    let Some((host, _port)) = endpoint
+        .strip_prefix("tcp://")
+        .and_then(|address| address.rsplit_once(':'))
+    else {
+        return false;
+    };
+    if host == "localhost" {
+        return true;
+    }
+    match host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        Some(host) => host.parse::<Ipv6Addr>().is_ok_and(|ip| ip.is_loopback()),
+        None => host.parse::<Ipv4Addr>().is_ok_and(|ip| ip.is_loopback()),
+    }
+}
+
+impl AdminServer {
+    pub fn bind(ctx: &Context, endpoint: &str) -> Result<Self, RustydomoError> {
+        let connection = ctx
+            .socket(SocketType::REP)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+        connection
+            .bind(endpoint)
+            .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
+        log::info!("Admin commands accepted on '{}'", endpoint);
+
+        Ok(AdminServer { connection })
+    }
+
+    ///
+    /// Reads an admin command, applies it and sends its answer
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - context linked to Majordomo handling
+    /// * `workers_connection` - connection used to send commands to workers
+    /// * `clients_connection` - connection used to answer clients
+    ///
+    pub fn handle_command(
+        &self,
+        ctx: &mut MajordomoContext,
+        workers_connection: &zmq::Socket,
+        clients_connection: &zmq::Socket,
+    ) -> Result<(), RustydomoError> {
+        let frames = self
+            .connection
+            .recv_multipart(0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        let args: Vec<String> = frames
+            .iter()
+            .map(|frame| String::from_utf8_lossy(frame).into_owned())
+            .collect();
+        log::info!("Admin command received : {:?}", args);
+
+        let (status, content) = execute_command(&args, ctx, workers_connection, clients_connection);
+
+        let mut answer = vec![status.to_string()];
+        answer.extend(content);
+        self.connection
+            .send_multipart(answer.iter(), 0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+    }
+}
+
+fn execute_command(
+    args: &[String],
+    ctx: &mut MajordomoContext,
+    workers_connection: &zmq::Socket,
+    clients_connection: &zmq::Socket,
+) -> AdminAnswer {
+    let argument = args.get(1).map(String::as_str);
+
+    match (args.first().map(String::as_str), argument) {
+        (Some("services"), _) => (STATUS_OK, Some(ctx.services_state().to_string())),
+        (Some("workers"), _) => (STATUS_OK, Some(ctx.workers_state().to_string())),
+        (Some("dump"), _) => (STATUS_OK, Some(ctx.dump_state().to_string())),
+        (Some("dead-letters"), _) => (STATUS_OK, Some(ctx.dead_letters.list().to_string())),
+        (Some("dead-letter"), Some(id)) => match id
+            .parse::<u64>()
+            .ok()
+            .and_then(|id| ctx.dead_letters.get(id))
+        {
+            Some(letter) => (STATUS_OK, Some(letter.details().to_string())),
+            None => (STATUS_NOT_FOUND, None),
+        },
+        (Some("replay"), Some(id)) => {
+            replay_dead_letter(ctx, workers_connection, clients_connection, id)
+        }
+        (Some("evict"), Some(identity)) => match parse_identity(identity) {
+            Some(identity) => match ctx.evict_worker(workers_connection, &identity) {
+                Ok(()) => (STATUS_OK, None),
+                Err(RustydomoError::ServiceNotAvailable(_)) => (STATUS_NOT_FOUND, None),
+                Err(err) => (STATUS_BAD_REQUEST, Some(err.to_string())),
+            },
+            None => (STATUS_BAD_REQUEST, Some("Invalid identity".into())),
+        },
+        (Some("pause"), Some(service_name)) => {
+            ctx.pause_service(service_name);
+            (STATUS_OK, None)
+        }
+        (Some("resume"), Some(service_name)) => {
+            match ctx.resume_service(workers_connection, service_name) {
+                Ok(()) => (STATUS_OK, None),
+                Err(err) => (STATUS_BAD_REQUEST, Some(err.to_string())),
+            }
+        }
+        (Some("drain"), Some(service_name)) => {
+            match ctx.drain_service(workers_connection, service_name) {
+                Ok(()) => (STATUS_OK, None),
+                Err(RustydomoError::ServiceNotAvailable(_)) => (STATUS_NOT_FOUND, None),
+                Err(err) => (STATUS_BAD_REQUEST, Some(err.to_string())),
+            }
+        }
+        (Some("dispatch"), Some(service_name)) => {
+            match args
+                .get(2)
+                .map(|strategy| strategy.parse::<DispatchStrategy>())
+            {
+                Some(Ok(strategy)) => {
+                    ctx.set_dispatch_strategy(service_name, strategy);
+                    (STATUS_OK, None)
+                }
+                Some(Err(err)) => (STATUS_BAD_REQUEST, Some(err.to_string())),
+                None => (STATUS_BAD_REQUEST, Some("Missing argument".into())),
+            }
+        }
+        (Some("log-level"), Some(level)) => match LevelFilter::from_str(level) {
+            Ok(level) => {
+                log::set_max_level(level);
+                (STATUS_OK, None)
+            }
+            Err(err) => (STATUS_BAD_REQUEST, Some(err.to_string())),
+        },
+        (
+            Some(
+                "evict" | "pause" | "resume" | "drain" | "dispatch" | "log-level" | "dead-letter"
+                | "replay",
+            ),
+            None,
+        ) => (STATUS_BAD_REQUEST, Some("Missing argument".into())),
+        _ => (STATUS_UNKNOWN_COMMAND, None),
+    }
+}
+
+///
+/// Replays a dead letter, which is forgotten once delivered
+///
+/// Expired requests are dispatched again without deadline, and undeliverable ones as they were
+/// received, their answers going to the client that sent them. Lost answers are sent again to
+/// their client, which has to be connected with the same identity.
+///
+fn replay_dead_letter(
+    ctx: &mut MajordomoContext,
+    workers_connection: &zmq::Socket,
+    clients_connection: &zmq::Socket,
+    id: &str,
+) -> AdminAnswer {
+    let letter = match id
+        .parse::<u64>()
+        .ok()
+        .and_then(|id| ctx.dead_letters.get(id))
+    {
+        Some(letter) => letter.clone(),
+        None => return (STATUS_NOT_FOUND, None),
+    };
+    let id = letter.id;
+    let replayed = match letter.kind {
+        DeadLetterKind::ExpiredRequest | DeadLetterKind::UndeliverableRequest => {
+            let mut options = letter.options;
+            if letter.kind == DeadLetterKind::ExpiredRequest {
+                options.deadline = None;
+            }
+            let task = Task::new(
+                letter.service_name.unwrap_or_default(),
+                letter.envelope,
+                letter.payload.into_iter().map(zmq::Message::from).collect(),
+                options,
+            );
+            ctx.send_task_to_worker(workers_connection, task)
+                .map(|()| true)
+        }
+        DeadLetterKind::LostPartial => handlers::send_client_answer(
+            clients_connection,
+            &letter.envelope,
+            ClientInteractionType::Partial,
+            &letter.payload,
+        ),
+        DeadLetterKind::LostFinal => handlers::send_client_answer(
+            clients_connection,
+            &letter.envelope,
+            ClientInteractionType::Final,
+            &letter.payload,
+        ),
+    };
+    match replayed {
+        Ok(true) => {
+            ctx.dead_letters.remove(id);
+            (STATUS_OK, None)
+        }
+        Ok(false) => (STATUS_UNAVAILABLE, Some("Client not connected".into())),
+        Err(err @ RustydomoError::ServiceNotAvailable(_)) => {
+            (STATUS_UNAVAILABLE, Some(err.to_string()))
+        }
+        Err(err) => (STATUS_BAD_REQUEST, Some(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::config::BrokerConfig;
+
+    ///
+    /// Runs a command against an empty broker state
+    ///
+    fn execute(ctx: &mut MajordomoContext, args: &[&str]) -> AdminAnswer {
+        let zmq_ctx = Context::new();
+        let workers_connection = zmq_ctx.socket(SocketType::ROUTER).unwrap();
+        let clients_connection = zmq_ctx.socket(SocketType::ROUTER).unwrap();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        execute_command(&args, ctx, &workers_connection, &clients_connection)
+    }
+
+    #[test]
+    fn identities_are_parsed_from_hexadecimal() {
+        assert_eq!(parse_identity("00ff10"), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(parse_identity(""), Some(vec![]));
+        assert_eq!(parse_identity("0ff"), None);
+        assert_eq!(parse_identity("zz"), None);
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        let mut ctx = MajordomoContext::new();
+        assert_eq!(execute(&mut ctx, &["restart"]).0, STATUS_UNKNOWN_COMMAND);
+        assert_eq!(execute(&mut ctx, &[]).0, STATUS_UNKNOWN_COMMAND);
+    }
+
+    #[test]
+    fn commands_without_their_argument_are_rejected() {
+        let mut ctx = MajordomoContext::new();
+        for command in [
+            "evict",
+            "pause",
+            "resume",
+            "drain",
+            "dispatch",
+            "log-level",
+            "dead-letter",
+            "replay",
+        ] {
+            assert_eq!(
+                execute(&mut ctx, &[command]),
+                (STATUS_BAD_REQUEST, Some("Missing argument".into())),
+                "{command}"
+            );
+        }
+        assert_eq!(
+            execute(&mut ctx, &["dispatch", "echo"]),
+            (STATUS_BAD_REQUEST, Some("Missing argument".into()))
+        );
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        let mut ctx = MajordomoContext::new();
+        assert_eq!(
+            execute(&mut ctx, &["evict", "not-hex"]),
+            (STATUS_BAD_REQUEST, Some("Invalid identity".into()))
+        );
+        assert_eq!(
+            execute(&mut ctx, &["dispatch", "echo", "fastest"]).0,
+            STATUS_BAD_REQUEST
+        );
+        assert_eq!(
+            execute(&mut ctx, &["log-level", "verbose"]).0,
+            STATUS_BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn unknown_targets_are_not_found() {
+        let mut ctx = MajordomoContext::new();
+        assert_eq!(execute(&mut ctx, &["evict", "00ff"]).0, STATUS_NOT_FOUND);
+        assert_eq!(
+            execute(&mut ctx, &["dead-letter", "42"]).0,
+            STATUS_NOT_FOUND
+        );
+        assert_eq!(execute(&mut ctx, &["dead-letter", "x"]).0, STATUS_NOT_FOUND);
+        assert_eq!(execute(&mut ctx, &["replay", "42"]).0, STATUS_NOT_FOUND);
+    }
+
+    #[test]
+    fn state_commands_answer_json() {
+        let mut ctx = MajordomoContext::new();
+        for command in ["services", "workers", "dump", "dead-letters"] {
+            let (status, content) = execute(&mut ctx, &[command]);
+            assert_eq!(status, STATUS_OK, "{command}");
+            let content = content.unwrap();
+            assert!(
+                serde_json::from_str::<serde_json::Value>(&content).is_ok(),
+                "{command} : {content}"
+            );
+        }
+    }
+
+    #[test]
+    fn services_are_paused_and_resumed() {
+        let mut ctx = MajordomoContext::new();
+        assert_eq!(execute(&mut ctx, &["pause", "echo"]), (STATUS_OK, None));
+        assert_eq!(execute(&mut ctx, &["resume", "echo"]), (STATUS_OK, None));
+        assert_eq!(execute(&mut ctx, &["drain", "echo"]).0, STATUS_NOT_FOUND);
+    }
+
+    #[test]
+    fn local_endpoints_are_recognized() {
+        for endpoint in [
+            "ipc:///tmp/broker-admin",
+            "inproc://admin",
+            "tcp://127.0.0.1:5555",
+            "tcp://127.0.0.2:*",
+            "tcp://localhost:5555",
+            "tcp://[::1]:5555",
+        ] {
+            assert!(is_local_endpoint(endpoint), "{endpoint}");
+        }
+        for endpoint in [
+            "tcp://*:5555",
+            "tcp://0.0.0.0:5555",
+            "tcp://192.168.1.10:5555",
+            "tcp://[::]:5555",
+            "tcp://eth0:5555",
+            "tcp://127.0.0.1",
+            "udp://127.0.0.1:5555",
+        ] {
+            assert!(!is_local_endpoint(endpoint), "{endpoint}");
+        }
+    }
+
+    #[test]
+    fn remote_admin_endpoints_need_to_be_insecure() {
+        let mut config = BrokerConfig {
+            admin_endpoint: Some("tcp://*:5555".into()),
+            ..BrokerConfig::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(RustydomoError::ConfigurationError(_))
+        ));
+        config.admin_insecure = true;
+        assert!(config.validate().is_ok());
+        config.admin_insecure = false;
+        config.admin_endpoint = Some("tcp://127.0.0.1:5555".into());
+        assert!(config.validate().is_ok());
+    }
+}
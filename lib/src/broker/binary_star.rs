@@ -0,0 +1,405 @@
+use crate::errors::RustydomoError;
+use std::time::{Duration, Instant};
+use zmq::{Context, SocketType};
+
+/// Period used to publish the broker state to its peer
+const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
+
+///
+/// Role given to a broker of a Binary Star pair on startup
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryStarRole {
+    Primary,
+    Backup,
+}
+
+///
+/// States of the Binary Star finite state machine
+///
+/// The values are the ones published to the peer broker
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryStarState {
+    /// Primary, waiting for peer to connect
+    Primary = 1,
+    /// Backup, waiting for peer to connect
+    Backup = 2,
+    /// Active, accepting connections
+    Active = 3,
+    /// Passive, not accepting connections
+    Passive = 4,
+}
+
+///
+/// Events handled by the Binary Star finite state machine
+///
+/// The peer related values match the state published by the peer
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinaryStarEvent {
+    PeerPrimary = 1,
+    PeerBackup = 2,
+    PeerActive = 3,
+    PeerPassive = 4,
+    ClientRequest = 5,
+}
+
+impl TryFrom<u8> for BinaryStarEvent {
+    type Error = RustydomoError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            x if x == BinaryStarEvent::PeerPrimary as u8 => Ok(BinaryStarEvent::PeerPrimary),
+            x if x == BinaryStarEvent::PeerBackup as u8 => Ok(BinaryStarEvent::PeerBackup),
+            x if x == BinaryStarEvent::PeerActive as u8 => Ok(BinaryStarEvent::PeerActive),
+            x if x == BinaryStarEvent::PeerPassive as u8 => Ok(BinaryStarEvent::PeerPassive),
+            val => Err(RustydomoError::Unknown(std::format!(
+                "Unknown peer state : {}",
+                val
+            ))),
+        }
+    }
+}
+
+///
+/// Options of the Binary Star mode
+///
+#[derive(Clone)]
+pub struct BinaryStarConfig {
+    pub role: BinaryStarRole,
+    /// Endpoint the state publisher is bound to
+    pub local_endpoint: String,
+    /// State publisher endpoint of the peer broker
+    pub remote_endpoint: String,
+}
+
+///
+/// Binary Star high availability pair member
+///
+/// Both brokers of the pair publish their state periodically. Only the active one accepts client
+/// requests and workers. The passive broker takes over when the active one stopped publishing its
+/// state and a client request comes in.
+///
+pub struct BinaryStar {
+    state: BinaryStarState,
+    /// Socket used to publish the broker state
+    state_publisher: zmq::Socket,
+    /// Socket used to receive the peer state
+    pub state_subscriber: zmq::Socket,
+    /// The peer is considered dead after that date
+    peer_expiry: Instant,
+    /// Last time the state was published
+    last_publication: Option<Instant>,
+}
+
+impl BinaryStar {
+    pub fn new(ctx: &Context, config: &BinaryStarConfig) -> Result<Self, RustydomoError> {
+        let state_publisher = ctx
+            .socket(SocketType::PUB)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+        state_publisher
+            .bind(&config.local_endpoint)
+            .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
+
+        let state_subscriber = ctx
+            .socket(SocketType::SUB)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+        state_subscriber
+            .set_subscribe(b"")
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+        state_subscriber
+            .connect(&config.remote_endpoint)
+            .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
+
+        let state = match config.role {
+            BinaryStarRole::Primary => BinaryStarState::Primary,
+            BinaryStarRole::Backup => BinaryStarState::Backup,
+        };
+        log::info!(
+            "Binary Star {:?} publishing state on '{}', peer expected on '{}'",
+            config.role,
+            config.local_endpoint,
+            config.remote_endpoint
+        );
+
+        Ok(BinaryStar {
+            state,
+            state_publisher,
+            state_subscriber,
+            peer_expiry: Instant::now() + 2 * HEARTBEAT_PERIOD,
+            last_publication: None,
+        })
+    }
+
+    ///
+    /// Indicates whether or not workers can currently register on this broker
+    ///
+    /// Workers are only accepted by the broker serving clients, unless the peer went silent, in
+    /// which case they are accepted in advance so that they are available when a client request
+    /// makes this broker active.
+    ///
+    pub fn accepts_workers(&self) -> bool {
+        match self.state {
+            BinaryStarState::Primary | BinaryStarState::Active => true,
+            BinaryStarState::Backup | BinaryStarState::Passive => self.is_peer_expired(),
+        }
+    }
+
+    fn is_peer_expired(&self) -> bool {
+        Instant::now() >= self.peer_expiry
+    }
+
+    ///
+    /// Runs the state machine for a new client request
+    ///
+    /// Returns whether or not the request can be handled by this broker
+    ///
+    pub fn accept_client_request(&mut self) -> bool {
+        self.execute(BinaryStarEvent::ClientRequest)
+            .unwrap_or(false)
+    }
+
+    ///
+    /// Reads and handles the state published by the peer broker
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if both brokers consider themselves active or passive
+    /// at the same time, which cannot be recovered from. Unknown states are logged and dropped.
+    ///
+    pub fn handle_peer_state(&mut self) -> Result<(), RustydomoError> {
+        let message = self
+            .state_subscriber
+            .recv_bytes(0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        // a malformed state says nothing about the peer, which is not considered alive for it
+        let Some(event) = message
+            .first()
+            .and_then(|&state| BinaryStarEvent::try_from(state).ok())
+        else {
+            log::warn!("Dropping unknown Binary Star peer state {:?}", message);
+            return Ok(());
+        };
+
+        self.execute(event)?;
+        self.peer_expiry = Instant::now() + 2 * HEARTBEAT_PERIOD;
+        Ok(())
+    }
+
+    ///
+    /// Publishes the broker state if the heartbeat period elapsed
+    ///
+    pub fn publish_state(&mut self) -> Result<(), RustydomoError> {
+        if let Some(last_publication) = self.last_publication {
+            if last_publication.elapsed() < HEARTBEAT_PERIOD {
+                return Ok(());
+            }
+        }
+        self.last_publication = Some(Instant::now());
+
+        let state: [u8; 1] = [self.state as u8];
+        self.state_publisher
+            .send(state.as_slice(), 0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+    }
+
+    ///
+    /// Applies the given event to the state machine
+    ///
+    /// Returns whether or not a client request may be handled
+    ///
+    fn execute(&mut self, event: BinaryStarEvent) -> Result<bool, RustydomoError> {
+        let previous_state = self.state;
+        let mut accepted = true;
+
+        match self.state {
+            BinaryStarState::Primary => match event {
+                // peer is waiting for us, we become active
+                BinaryStarEvent::PeerBackup => self.state = BinaryStarState::Active,
+                // peer is already active, we become passive
+                BinaryStarEvent::PeerActive => self.state = BinaryStarState::Passive,
+                // client requests are accepted while waiting for the backup
+                _ => (),
+            },
+            BinaryStarState::Backup => match event {
+                BinaryStarEvent::PeerActive => self.state = BinaryStarState::Passive,
+                // client requests are only accepted once the primary is known
+                BinaryStarEvent::ClientRequest => accepted = false,
+                _ => (),
+            },
+            BinaryStarState::Active => {
+                if event == BinaryStarEvent::PeerActive {
+                    return Err(RustydomoError::Unknown(
+                        "Binary Star failure : dual active brokers".into(),
+                    ));
+                }
+            }
+            BinaryStarState::Passive => match event {
+                // peer is restarting or just started as backup, we become active
+                BinaryStarEvent::PeerPrimary | BinaryStarEvent::PeerBackup => {
+                    self.state = BinaryStarState::Active
+                }
+                BinaryStarEvent::PeerPassive => {
+                    return Err(RustydomoError::Unknown(
+                        "Binary Star failure : dual passive brokers".into(),
+                    ));
+                }
+                BinaryStarEvent::ClientRequest => {
+                    if self.is_peer_expired() {
+                        // peer went silent and clients moved to us : fail over
+                        log::warn!("Peer broker is not responding, taking over as active broker");
+                        self.state = BinaryStarState::Active;
+                    } else {
+                        // peer is alive, the client has to use it
+                        accepted = false;
+                    }
+                }
+                BinaryStarEvent::PeerActive => (),
+            },
+        }
+
+        if previous_state != self.state {
+            log::info!(
+                "Binary Star state changed from {:?} to {:?}",
+                previous_state,
+                self.state
+            );
+        }
+
+        Ok(accepted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_PAIR_ID: AtomicUsize = AtomicUsize::new(0);
+
+    ///
+    /// Creates a pair member whose peer state publisher is returned along with it
+    ///
+    fn binary_star(role: BinaryStarRole) -> (BinaryStar, zmq::Socket) {
+        let id = NEXT_PAIR_ID.fetch_add(1, Ordering::Relaxed);
+        let ctx = Context::new();
+        let peer_endpoint = format!("inproc://binary-star-test-{id}-peer");
+        let peer = ctx.socket(SocketType::PUB).unwrap();
+        peer.bind(&peer_endpoint).unwrap();
+        let bstar = BinaryStar::new(
+            &ctx,
+            &BinaryStarConfig {
+                role,
+                local_endpoint: format!("inproc://binary-star-test-{id}-local"),
+                remote_endpoint: peer_endpoint,
+            },
+        )
+        .unwrap();
+        (bstar, peer)
+    }
+
+    ///
+    /// Publishes the given peer state until the pair member receives it
+    ///
+    fn publish_peer_state(bstar: &BinaryStar, peer: &zmq::Socket, state: &[u8]) {
+        // the subscription reaches the publisher asynchronously, first states may be dropped
+        for _ in 0..100 {
+            peer.send(state, 0).unwrap();
+            if bstar.state_subscriber.poll(zmq::POLLIN, 10).unwrap() > 0 {
+                return;
+            }
+        }
+        panic!("Peer state never received");
+    }
+
+    #[test]
+    fn primary_becomes_active_once_backup_is_seen() {
+        let (mut bstar, _peer) = binary_star(BinaryStarRole::Primary);
+        assert!(bstar.execute(BinaryStarEvent::ClientRequest).unwrap());
+        assert_eq!(bstar.state, BinaryStarState::Primary);
+
+        assert!(bstar.execute(BinaryStarEvent::PeerBackup).unwrap());
+        assert_eq!(bstar.state, BinaryStarState::Active);
+        assert!(bstar.accepts_workers());
+    }
+
+    #[test]
+    fn primary_becomes_passive_when_peer_is_active() {
+        let (mut bstar, _peer) = binary_star(BinaryStarRole::Primary);
+        bstar.execute(BinaryStarEvent::PeerActive).unwrap();
+        assert_eq!(bstar.state, BinaryStarState::Passive);
+    }
+
+    #[test]
+    fn backup_rejects_clients_until_primary_is_active() {
+        let (mut bstar, _peer) = binary_star(BinaryStarRole::Backup);
+        assert!(!bstar.execute(BinaryStarEvent::ClientRequest).unwrap());
+        assert_eq!(bstar.state, BinaryStarState::Backup);
+
+        bstar.execute(BinaryStarEvent::PeerActive).unwrap();
+        assert_eq!(bstar.state, BinaryStarState::Passive);
+    }
+
+    #[test]
+    fn passive_fails_over_on_client_request_once_peer_expired() {
+        let (mut bstar, _peer) = binary_star(BinaryStarRole::Backup);
+        bstar.execute(BinaryStarEvent::PeerActive).unwrap();
+        bstar.peer_expiry = Instant::now();
+
+        assert!(bstar.accepts_workers());
+        assert!(bstar.execute(BinaryStarEvent::ClientRequest).unwrap());
+        assert_eq!(bstar.state, BinaryStarState::Active);
+    }
+
+    #[test]
+    fn passive_rejects_client_requests_while_peer_is_alive() {
+        let (mut bstar, _peer) = binary_star(BinaryStarRole::Backup);
+        bstar.execute(BinaryStarEvent::PeerActive).unwrap();
+        bstar.peer_expiry = Instant::now() + Duration::from_secs(60);
+
+        assert!(!bstar.accepts_workers());
+        assert!(!bstar.execute(BinaryStarEvent::ClientRequest).unwrap());
+        assert_eq!(bstar.state, BinaryStarState::Passive);
+    }
+
+    #[test]
+    fn passive_becomes_active_when_peer_restarts() {
+        let (mut bstar, _peer) = binary_star(BinaryStarRole::Backup);
+        bstar.execute(BinaryStarEvent::PeerActive).unwrap();
+        bstar.execute(BinaryStarEvent::PeerPrimary).unwrap();
+        assert_eq!(bstar.state, BinaryStarState::Active);
+    }
+
+    #[test]
+    fn dual_active_and_dual_passive_are_fatal() {
+        let (mut active, _peer) = binary_star(BinaryStarRole::Primary);
+        active.execute(BinaryStarEvent::PeerBackup).unwrap();
+        assert!(active.execute(BinaryStarEvent::PeerActive).is_err());
+
+        let (mut passive, _peer) = binary_star(BinaryStarRole::Backup);
+        passive.execute(BinaryStarEvent::PeerActive).unwrap();
+        assert!(passive.execute(BinaryStarEvent::PeerPassive).is_err());
+    }
+
+    #[test]
+    fn unknown_peer_states_are_dropped() {
+        let (mut bstar, peer) = binary_star(BinaryStarRole::Backup);
+        bstar.peer_expiry = Instant::now();
+        for state in [&[9u8][..], &[BinaryStarEvent::ClientRequest as u8], &[]] {
+            publish_peer_state(&bstar, &peer, state);
+            bstar.handle_peer_state().unwrap();
+            // drains the copies published while waiting for the subscription
+            while bstar.state_subscriber.poll(zmq::POLLIN, 0).unwrap() > 0 {
+                bstar.handle_peer_state().unwrap();
+            }
+        }
+        assert_eq!(bstar.state, BinaryStarState::Backup);
+        assert!(bstar.is_peer_expired());
+
+        publish_peer_state(&bstar, &peer, &[BinaryStarState::Active as u8]);
+        bstar.handle_peer_state().unwrap();
+        assert_eq!(bstar.state, BinaryStarState::Passive);
+        assert!(!bstar.is_peer_expired());
+    }
+}
@@ -1,5 +1,5 @@
-use crate::data_structures::ConnectionData;
-use domolib::errors::RustydomoError;
+use crate::broker::data_structures::ConnectionData;
+use crate::errors::RustydomoError;
 
 use log::{debug, info, log_enabled, Level};
 use zmq::{Context, SocketEvent, SocketType};
@@ -12,11 +12,19 @@ pub fn bind_router_connection(
     ctx: &Context,
     router_connection_string: &str,
     monitor_connection_string: &str,
+    max_message_size: Option<i64>,
 ) -> Result<ConnectionData, RustydomoError> {
     let router_socket = ctx
         .socket(SocketType::ROUTER)
         .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
 
+    // connections accepted by the socket get the options it had when it was bound
+    if let Some(size) = max_message_size {
+        router_socket
+            .set_maxmsgsize(size)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+    }
+
     router_socket
         .bind(router_connection_string)
         .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
@@ -46,8 +54,8 @@ pub fn bind_router_connection(
             monitor_connection_string
         );
     }
-    return Ok(ConnectionData {
+    Ok(ConnectionData {
         connection: router_socket,
         monitor_connection,
-    });
+    })
 }
@@ -0,0 +1,386 @@
+use crate::broker::admin::is_local_endpoint;
+use crate::broker::binary_star::{BinaryStarConfig, BinaryStarRole};
+use crate::broker::dead_letters::DEFAULT_DEAD_LETTERS_CAPACITY;
+use crate::broker::dispatch::{DispatchStrategy, UnmatchedLabelsPolicy};
+use crate::broker::majordomo_context::{DEFAULT_PRIORITY_AGING, DEFAULT_WORKER_CREDIT};
+use crate::broker::rate_limit::Limit;
+use crate::broker::size_limits::SizeLimits;
+use crate::errors::RustydomoError;
+use std::time::Duration;
+
+const DEFAULT_CLIENTS_ENDPOINT: &str = "tcp://*:5000";
+const DEFAULT_WORKERS_ENDPOINT: &str = "tcp://*:6000";
+const DEFAULT_PEER_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "Usage: broker [OPTIONS]
+
+Options:
+    --clients <ENDPOINT>        endpoint clients connect to (default: tcp://*:5000)
+    --workers <ENDPOINT>        endpoint workers connect to (default: tcp://*:6000)
+    --peer <ENDPOINT>           clients endpoint of a peer broker requests can be forwarded to
+                                (can be repeated)
+    --peer-refresh-ms <MS>      period of the services discovery sent to peers (default: 1000)
+    --bstar <ROLE>              run as the 'primary' or 'backup' broker of a Binary Star pair
+    --bstar-local <ENDPOINT>    endpoint the Binary Star state is published on
+    --bstar-remote <ENDPOINT>   endpoint the peer of the Binary Star pair publishes its state on
+    --metrics-port <PORT>       serve Prometheus metrics on http://127.0.0.1:<PORT>/metrics
+    --admin <ENDPOINT>          endpoint admin commands are accepted on, which has no
+                                authentication and must be local (ipc://, inproc:// or loopback
+                                tcp://) unless --admin-insecure is given
+    --admin-insecure            accept admin commands on any endpoint
+    --grace-period-ms <MS>      time given to in-flight requests to complete on SIGINT/SIGTERM
+                                (default: 5000)
+    --dispatch <STRATEGY>       default dispatch strategy : round-robin (default),
+                                least-outstanding, weighted, random-of-two or least-latency
+    --service-dispatch <SERVICE>=<STRATEGY>
+                                dispatch strategy of a given service (can be repeated)
+    --service-limit <SERVICE> <LIMIT>
+                                limits of the requests sent to a service (can be repeated)
+    --client-limit <IDENTITY> <LIMIT>
+                                limits of the requests sent by the client using the given
+                                identity (can be repeated)
+    --default-client-limit <LIMIT>
+                                limits of the requests sent by each other client
+                                LIMIT is a comma separated list of rate=<REQUESTS PER SECOND>,
+                                burst=<REQUESTS> and concurrent=<REQUESTS>
+    --trace-file <PATH>         append the spans of every request to the given file, as OTLP/JSON
+                                lines
+    --unmatched-labels <POLICY> what is done with requests no worker has the labels of : reject
+                                (default) or queue
+    --fallback-service <SERVICE>
+                                service handling the requests for services no worker nor peer
+                                broker handles
+    --events <ENDPOINT>         endpoint the broker lifecycle events are published on
+    --queue-high-water <DEPTH>  requests queued or being handled for a service above which a
+                                queue.high_water event is published
+    --priority-aging-ms <MS>    waiting time worth one priority level for queued requests, so that
+                                low priority requests are not starved (default: 1000)
+    --worker-credit <REQUESTS>  requests a worker is sent before answering them, the others being
+                                queued by the broker by priority (default: 1)
+    --max-message-size <BYTES>  largest frame accepted from clients and workers, peers sending a
+                                larger one being disconnected
+    --max-request-frames <COUNT>
+                                largest number of body frames of a request
+    --max-request-bytes <BYTES> largest body of a request
+    --max-service-queued-bytes <BYTES>
+                                bytes of the requests queued for a service above which requests
+                                are rejected
+    --max-queued-bytes <BYTES>  bytes of the requests queued for all services above which requests
+                                are rejected
+    --dead-letters <COUNT>      undelivered requests and answers kept in memory for the admin
+                                endpoint (default: 1000)
+    --dead-letter-file <PATH>   append every undelivered request and answer to the given file, as
+                                JSON lines
+    --shards <COUNT>            broker threads the services are split between (default: 1), not
+                                available with admin, metrics, events, peers, Binary Star nor
+                                fallback service
+    -h, --help                  print this help";
+
+///
+/// Options the broker is started with
+///
+#[derive(Clone)]
+pub struct BrokerConfig {
+    /// Endpoint the clients router is bound to
+    pub clients_endpoint: String,
+    /// Endpoint the workers router is bound to
+    pub workers_endpoint: String,
+    /// Clients endpoints of peer brokers requests are forwarded to when no local worker is
+    /// available for the requested service
+    pub peers: Vec<String>,
+    /// Period used to ask peers which services they can handle
+    pub peer_refresh_interval: Duration,
+    /// Binary Star options, when the broker is part of a primary/backup pair
+    pub binary_star: Option<BinaryStarConfig>,
+    /// Local port the Prometheus metrics are served on, if any
+    pub metrics_port: Option<u16>,
+    /// Endpoint the admin REP socket is bound to, if any
+    pub admin_endpoint: Option<String>,
+    /// Whether or not the admin endpoint may be reachable from other hosts
+    pub admin_insecure: bool,
+    /// Time given to in-flight requests to complete once a shutdown is requested
+    pub grace_period: Duration,
+    /// Dispatch strategy of the services not listed in `service_dispatch`
+    pub default_dispatch: DispatchStrategy,
+    /// Dispatch strategy of specific services
+    pub service_dispatch: Vec<(String, DispatchStrategy)>,
+    /// Limits of the requests sent to specific services
+    pub service_limits: Vec<(String, Limit)>,
+    /// Limits of the requests sent by clients using specific identities
+    pub client_limits: Vec<(Vec<u8>, Limit)>,
+    /// Limits of the requests sent by each client without specific limits
+    pub default_client_limit: Option<Limit>,
+    /// File the request spans are exported to, if any
+    pub trace_file: Option<String>,
+    /// What is done with requests no worker has the labels of
+    pub unmatched_labels_policy: UnmatchedLabelsPolicy,
+    /// Service handling the requests no worker nor peer broker can handle, if any
+    pub fallback_service: Option<String>,
+    /// Endpoint the events PUB socket is bound to, if any
+    pub events_endpoint: Option<String>,
+    /// Requests queued or being handled for a service above which an event is raised, if any
+    pub queue_high_water: Option<usize>,
+    /// Waiting time worth one priority level for queued requests
+    pub priority_aging: Duration,
+    /// Requests a worker is sent before answering them
+    pub worker_credit: usize,
+    /// Largest frame accepted from clients and workers, if any
+    pub max_message_size: Option<i64>,
+    /// Limits on the size of the requests and of the queued requests
+    pub size_limits: SizeLimits,
+    /// Number of dead letters kept in memory
+    pub dead_letters_capacity: usize,
+    /// File the dead letters are appended to, if any
+    pub dead_letter_file: Option<String>,
+    /// Number of broker threads the services are split between
+    pub shards: usize,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            clients_endpoint: DEFAULT_CLIENTS_ENDPOINT.into(),
+            workers_endpoint: DEFAULT_WORKERS_ENDPOINT.into(),
+            peers: Vec::new(),
+            peer_refresh_interval: DEFAULT_PEER_REFRESH_INTERVAL,
+            binary_star: None,
+            metrics_port: None,
+            admin_endpoint: None,
+            admin_insecure: false,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            default_dispatch: DispatchStrategy::default(),
+            service_dispatch: Vec::new(),
+            service_limits: Vec::new(),
+            client_limits: Vec::new(),
+            default_client_limit: None,
+            trace_file: None,
+            unmatched_labels_policy: UnmatchedLabelsPolicy::default(),
+            fallback_service: None,
+            events_endpoint: None,
+            queue_high_water: None,
+            priority_aging: DEFAULT_PRIORITY_AGING,
+            worker_credit: DEFAULT_WORKER_CREDIT,
+            max_message_size: None,
+            size_limits: SizeLimits::default(),
+            dead_letters_capacity: DEFAULT_DEAD_LETTERS_CAPACITY,
+            dead_letter_file: None,
+            shards: 1,
+        }
+    }
+}
+
+fn next_value(
+    args: &mut impl Iterator<Item = String>,
+    option: &str,
+) -> Result<String, RustydomoError> {
+    args.next()
+        .ok_or_else(|| RustydomoError::ConfigurationError(format!("missing value for '{option}'")))
+}
+
+fn parse_millis(value: &str, option: &str) -> Result<Duration, RustydomoError> {
+    value
+        .parse::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|err| RustydomoError::ConfigurationError(format!("{option} '{value}' : {err}")))
+}
+
+fn parse_count(value: &str, option: &str) -> Result<usize, RustydomoError> {
+    value
+        .parse::<usize>()
+        .map_err(|err| RustydomoError::ConfigurationError(format!("{option} '{value}' : {err}")))
+}
+
+impl BrokerConfig {
+    ///
+    /// Builds the configuration from command line arguments (program name excluded)
+    ///
+    /// Returns `Ok(None)` when only the help was requested
+    ///
+    pub fn from_args(
+        mut args: impl Iterator<Item = String>,
+    ) -> Result<Option<Self>, RustydomoError> {
+        let mut config = BrokerConfig::default();
+        let mut bstar_role: Option<BinaryStarRole> = None;
+        let mut bstar_local: Option<String> = None;
+        let mut bstar_remote: Option<String> = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--clients" => config.clients_endpoint = next_value(&mut args, &arg)?,
+                "--workers" => config.workers_endpoint = next_value(&mut args, &arg)?,
+                "--peer" => config.peers.push(next_value(&mut args, &arg)?),
+                "--peer-refresh-ms" => {
+                    config.peer_refresh_interval =
+                        parse_millis(&next_value(&mut args, &arg)?, &arg)?
+                }
+                "--bstar" => {
+                    bstar_role = match next_value(&mut args, &arg)?.as_str() {
+                        "primary" => Some(BinaryStarRole::Primary),
+                        "backup" => Some(BinaryStarRole::Backup),
+                        role => {
+                            return Err(RustydomoError::ConfigurationError(format!(
+                                "unknown Binary Star role '{role}'"
+                            )))
+                        }
+                    }
+                }
+                "--bstar-local" => bstar_local = Some(next_value(&mut args, &arg)?),
+                "--bstar-remote" => bstar_remote = Some(next_value(&mut args, &arg)?),
+                "--metrics-port" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.metrics_port = Some(value.parse::<u16>().map_err(|err| {
+                        RustydomoError::ConfigurationError(format!("{arg} '{value}' : {err}"))
+                    })?)
+                }
+                "--admin" => config.admin_endpoint = Some(next_value(&mut args, &arg)?),
+                "--admin-insecure" => config.admin_insecure = true,
+                "--trace-file" => config.trace_file = Some(next_value(&mut args, &arg)?),
+                "--unmatched-labels" => {
+                    config.unmatched_labels_policy = next_value(&mut args, &arg)?.parse()?
+                }
+                "--fallback-service" => {
+                    config.fallback_service = Some(next_value(&mut args, &arg)?)
+                }
+                "--events" => config.events_endpoint = Some(next_value(&mut args, &arg)?),
+                "--queue-high-water" => {
+                    config.queue_high_water =
+                        Some(parse_count(&next_value(&mut args, &arg)?, &arg)?)
+                }
+                "--priority-aging-ms" => {
+                    config.priority_aging = parse_millis(&next_value(&mut args, &arg)?, &arg)?
+                }
+                "--worker-credit" => {
+                    config.worker_credit = parse_count(&next_value(&mut args, &arg)?, &arg)?
+                }
+                "--max-message-size" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.max_message_size = Some(value.parse::<i64>().map_err(|err| {
+                        RustydomoError::ConfigurationError(format!("{arg} '{value}' : {err}"))
+                    })?)
+                }
+                "--max-request-frames" => {
+                    config.size_limits.request_frames =
+                        Some(parse_count(&next_value(&mut args, &arg)?, &arg)?)
+                }
+                "--max-request-bytes" => {
+                    config.size_limits.request_bytes =
+                        Some(parse_count(&next_value(&mut args, &arg)?, &arg)?)
+                }
+                "--max-service-queued-bytes" => {
+                    config.size_limits.service_queued_bytes =
+                        Some(parse_count(&next_value(&mut args, &arg)?, &arg)?)
+                }
+                "--max-queued-bytes" => {
+                    config.size_limits.queued_bytes =
+                        Some(parse_count(&next_value(&mut args, &arg)?, &arg)?)
+                }
+                "--dead-letters" => {
+                    config.dead_letters_capacity = parse_count(&next_value(&mut args, &arg)?, &arg)?
+                }
+                "--dead-letter-file" => {
+                    config.dead_letter_file = Some(next_value(&mut args, &arg)?)
+                }
+                "--shards" => config.shards = parse_count(&next_value(&mut args, &arg)?, &arg)?,
+                "--grace-period-ms" => {
+                    config.grace_period = parse_millis(&next_value(&mut args, &arg)?, &arg)?
+                }
+                "--dispatch" => config.default_dispatch = next_value(&mut args, &arg)?.parse()?,
+                "--service-dispatch" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match value.split_once('=') {
+                        Some((service_name, strategy)) => config
+                            .service_dispatch
+                            .push((service_name.to_string(), strategy.parse()?)),
+                        None => {
+                            return Err(RustydomoError::ConfigurationError(format!(
+                                "{arg} '{value}' : expected <SERVICE>=<STRATEGY>"
+                            )))
+                        }
+                    }
+                }
+                "--service-limit" => {
+                    let service_name = next_value(&mut args, &arg)?;
+                    let limit = next_value(&mut args, &arg)?.parse()?;
+                    config.service_limits.push((service_name, limit));
+                }
+                "--client-limit" => {
+                    let identity = next_value(&mut args, &arg)?.into_bytes();
+                    let limit = next_value(&mut args, &arg)?.parse()?;
+                    config.client_limits.push((identity, limit));
+                }
+                "--default-client-limit" => {
+                    config.default_client_limit = Some(next_value(&mut args, &arg)?.parse()?)
+                }
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    return Ok(None);
+                }
+                unknown => {
+                    return Err(RustydomoError::ConfigurationError(format!(
+                        "unknown option '{unknown}'\n\n{USAGE}"
+                    )))
+                }
+            }
+        }
+
+        config.binary_star = match (bstar_role, bstar_local, bstar_remote) {
+            (Some(role), Some(local_endpoint), Some(remote_endpoint)) => Some(BinaryStarConfig {
+                role,
+                local_endpoint,
+                remote_endpoint,
+            }),
+            (None, None, None) => None,
+            _ => {
+                return Err(RustydomoError::ConfigurationError(
+                    "--bstar, --bstar-local and --bstar-remote must be given together".into(),
+                ))
+            }
+        };
+
+        config.validate()?;
+
+        Ok(Some(config))
+    }
+
+    ///
+    /// Checks that the options can be used together
+    ///
+    pub fn validate(&self) -> Result<(), RustydomoError> {
+        if self.shards == 0 {
+            return Err(RustydomoError::ConfigurationError(
+                "--shards '0' : at least one shard is needed".into(),
+            ));
+        }
+        if self.worker_credit == 0 {
+            return Err(RustydomoError::ConfigurationError(
+                "--worker-credit '0' : workers need to be sent at least one request".into(),
+            ));
+        }
+        // admin commands are not authenticated, only local users may send them by default
+        if let Some(endpoint) = &self.admin_endpoint {
+            if !self.admin_insecure && !is_local_endpoint(endpoint) {
+                return Err(RustydomoError::ConfigurationError(format!(
+                    "--admin '{endpoint}' : not a local endpoint, use --admin-insecure to \
+                     accept admin commands from other hosts"
+                )));
+            }
+        }
+        // these components need a view of every service, which no shard has
+        if self.shards > 1
+            && (self.admin_endpoint.is_some()
+                || self.metrics_port.is_some()
+                || self.events_endpoint.is_some()
+                || !self.peers.is_empty()
+                || self.binary_star.is_some()
+                || self.fallback_service.is_some())
+        {
+            return Err(RustydomoError::ConfigurationError(
+                "--shards can not be combined with --admin, --metrics-port, --events, --peer, \
+                 --bstar nor --fallback-service"
+                    .into(),
+            ));
+        }
+        Ok(())
+    }
+}
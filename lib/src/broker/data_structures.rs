@@ -0,0 +1,178 @@
+use crate::errors::RustydomoError;
+use semver::{Version, VersionReq};
+pub use zmq::Socket;
+
+#[allow(clippy::enum_variant_names)]
+pub enum SocketType {
+    ClientSocket = 0,
+    ClientMonitorSocket,
+    ServiceSocket,
+    WorkerMonitorSocket,
+}
+
+impl TryFrom<usize> for SocketType {
+    type Error = RustydomoError;
+
+    fn try_from(val: usize) -> Result<Self, Self::Error> {
+        match val {
+            x if x == SocketType::ClientSocket as usize => Ok(SocketType::ClientSocket),
+            x if x == SocketType::ClientMonitorSocket as usize => {
+                Ok(SocketType::ClientMonitorSocket)
+            }
+            x if x == SocketType::ServiceSocket as usize => Ok(SocketType::ServiceSocket),
+
+            x if x == SocketType::WorkerMonitorSocket as usize => {
+                Ok(SocketType::WorkerMonitorSocket)
+            }
+            val => Err(RustydomoError::Unknown(std::format!(
+                "Unknown socket type : {}",
+                val
+            ))),
+        }
+    }
+}
+
+pub struct ConnectionData {
+    pub connection: Socket,
+    pub monitor_connection: Socket,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub value: Vec<u8>, // the only members that matter
+    _private: (),       // make sure this can not be instanciated directly
+}
+
+impl TryFrom<&[u8]> for Identity {
+    type Error = RustydomoError;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        // identity is sent as an array of X bytes
+
+        Ok(Identity {
+            value: value.to_vec(),
+            _private: (),
+        })
+    }
+}
+
+impl Identity {
+    ///
+    /// Returns the identity as an hexadecimal string, as displayed to broker administrators
+    ///
+    pub fn to_hex(&self) -> String {
+        self.value
+            .iter()
+            .map(|val| std::format!("{:02x}", val))
+            .collect()
+    }
+}
+
+///
+/// Returns the frames of an envelope as hexadecimal strings
+///
+pub fn envelope_to_hex(envelope: &[Vec<u8>]) -> Vec<String> {
+    envelope
+        .iter()
+        .map(|frame| Identity::try_from(frame.as_slice()).unwrap().to_hex())
+        .collect()
+}
+
+///
+/// Returns the names workers can register with to handle the given service, from the most to the
+/// least specific : the name itself, then the wildcard patterns of its parents (for
+/// `images.resize.v2` : `images.resize.*` and `images.*`)
+///
+pub fn matching_service_names(service_name: &str) -> impl Iterator<Item = String> + '_ {
+    std::iter::once(service_name.to_string()).chain(
+        service_name
+            .rmatch_indices('.')
+            .map(move |(idx, _)| format!("{}.*", &service_name[..idx])),
+    )
+}
+
+///
+/// Splits the name of a service registered by a worker with a version (`resize@1.4.2`) into its
+/// base name and its version
+///
+pub fn parse_versioned_service(service_name: &str) -> Option<(&str, Version)> {
+    let (base_name, version) = service_name.split_once('@')?;
+    Some((base_name, Version::parse(version).ok()?))
+}
+
+///
+/// Splits the name of a service requested by a client into its base name and its version
+/// requirement (`resize@^1.3`), if any
+///
+/// Names whose requirement is not a valid semver requirement are taken as they are
+///
+pub fn parse_service_requirement(service_name: &str) -> (&str, Option<VersionReq>) {
+    service_name
+        .split_once('@')
+        .and_then(|(base_name, requirement)| {
+            VersionReq::parse(requirement)
+                .ok()
+                .map(|requirement| (base_name, Some(requirement)))
+        })
+        .unwrap_or((service_name, None))
+}
+
+impl From<Identity> for Vec<u8> {
+    fn from(val: Identity) -> Self {
+        val.value
+    }
+}
+
+///
+/// Commands exchanged with clients
+///
+/// Frames sent by a client before "MDPC02" are kept as envelope and sent back before "MDPC02" in
+/// every answer, so that clients can tag their requests to tell concurrent answers apart.
+///
+/// `Error` is an extension of MDP/Client v0.2 sent by the broker when a request cannot be
+/// answered, instead of leaving the client waiting:
+///
+/// * Frame 0: "MDPC02"
+/// * Frame 1: 0x04 (ERROR)
+/// * Frame 2: Service name
+/// * Frame 3: Status code (e.g. "503")
+/// * Frame 4: Reason (printable string)
+///
+/// `Cancel` is an extension sent by clients abandoning a request, the broker forgetting it if it
+/// is still queued or asking its worker to stop otherwise:
+///
+/// * Frame 0: "MDPC02"
+/// * Frame 1: 0x05 (CANCEL)
+/// * Frame 2: Service name
+/// * Frame 3: Request ID (the `request_id` option of the request)
+///
+pub enum ClientInteractionType {
+    Request = 0x01,
+    Partial = 0x02,
+    Final = 0x03,
+    Error = 0x04,
+    Cancel = 0x05,
+}
+
+///
+/// Commands exchanged with workers
+///
+/// READY may be followed by `key=value` frames after the service name, describing the worker
+/// (e.g. `weight=4` for the weighted dispatch strategy)
+///
+/// `Cancel` is an extension sent by the broker when the client of a request being handled
+/// cancelled it. The worker may stop handling the request, any answer it still sends for it being
+/// dropped by the broker:
+///
+/// * Frame 0: "MDPW02"
+/// * Frame 1: 0x07 (CANCEL)
+/// * Frame 2: Request ID (the `request_id` option of the request)
+///
+pub enum WorkerInteractionType {
+    Ready = 0x01,
+    Request = 0x02,
+    Partial = 0x03,
+    Final = 0x04,
+    Heartbeat = 0x05,
+    Disconnect = 0x06,
+    Cancel = 0x07,
+}
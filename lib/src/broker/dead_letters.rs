@@ -0,0 +1,203 @@
+use crate::broker::data_structures::envelope_to_hex;
+use crate::errors::RustydomoError;
+use crate::options::RequestOptions;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of dead letters kept in memory when no capacity is configured
+pub const DEFAULT_DEAD_LETTERS_CAPACITY: usize = 1000;
+
+///
+/// Why a message ended up in the dead letters
+///
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DeadLetterKind {
+    /// Request whose deadline passed before it was dispatched
+    ExpiredRequest,
+    /// Request accepted by the broker, no worker of its service being left to handle it
+    UndeliverableRequest,
+    /// PARTIAL answer whose client was not connected anymore
+    LostPartial,
+    /// FINAL answer whose client was not connected anymore
+    LostFinal,
+}
+
+impl DeadLetterKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeadLetterKind::ExpiredRequest => "expired_request",
+            DeadLetterKind::UndeliverableRequest => "undeliverable_request",
+            DeadLetterKind::LostPartial => "lost_partial",
+            DeadLetterKind::LostFinal => "lost_final",
+        }
+    }
+}
+
+///
+/// Message the broker could not deliver
+///
+#[derive(Clone)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub kind: DeadLetterKind,
+    /// Service the request was sent to, unknown for answers to requests the broker did not track
+    pub service_name: Option<String>,
+    /// Envelope of the client the message was addressed to, its identity first
+    pub envelope: Vec<Vec<u8>>,
+    /// Body of the request, or frames following the command of the answer
+    pub payload: Vec<Vec<u8>>,
+    pub options: RequestOptions,
+    timestamp_ms: u64,
+}
+
+impl DeadLetter {
+    ///
+    /// Returns the letter without its frames, as listed by the admin endpoint
+    ///
+    pub fn summary(&self) -> Value {
+        json!({
+            "id": self.id,
+            "kind": self.kind.as_str(),
+            "service": self.service_name,
+            "client": envelope_to_hex(&self.envelope).join("."),
+            "request_id": self.options.request_id,
+            "timestamp_ms": self.timestamp_ms,
+            "frames": self.payload.len(),
+            "bytes": self.payload.iter().map(Vec::len).sum::<usize>(),
+        })
+    }
+
+    ///
+    /// Returns the whole letter, frames being hexadecimal strings
+    ///
+    pub fn details(&self) -> Value {
+        let mut details = self.summary();
+        details["envelope"] = json!(envelope_to_hex(&self.envelope));
+        details["payload"] = json!(envelope_to_hex(&self.payload));
+        details
+    }
+}
+
+///
+/// Last messages the broker could not deliver, kept in memory to be inspected or replayed and
+/// optionally appended to a file, one JSON document per line
+///
+pub struct DeadLetterStore {
+    letters: VecDeque<DeadLetter>,
+    capacity: usize,
+    next_id: u64,
+    output: Option<LineWriter<File>>,
+}
+
+impl Default for DeadLetterStore {
+    fn default() -> Self {
+        DeadLetterStore {
+            letters: VecDeque::new(),
+            capacity: DEFAULT_DEAD_LETTERS_CAPACITY,
+            next_id: 1,
+            output: None,
+        }
+    }
+}
+
+impl DeadLetterStore {
+    ///
+    /// Sets the number of letters kept in memory, the oldest ones being dropped first
+    ///
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.letters.len() > capacity {
+            self.letters.pop_front();
+        }
+    }
+
+    ///
+    /// Appends every letter recorded from now on to the given file
+    ///
+    pub fn open_file(&mut self, path: &str) -> Result<(), RustydomoError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| {
+                RustydomoError::ConfigurationError(format!("Can not open '{path}' : {err}"))
+            })?;
+        log::info!("Writing dead letters to '{}'", path);
+        self.output = Some(LineWriter::new(file));
+        Ok(())
+    }
+
+    ///
+    /// Records a message the broker could not deliver
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - why the message could not be delivered
+    /// * `service_name` - service the request was sent to, if known
+    /// * `envelope` - envelope of the client the message was addressed to
+    /// * `payload` - body of the request, or frames following the command of the answer
+    /// * `options` - options of the request
+    ///
+    pub fn record(
+        &mut self,
+        kind: DeadLetterKind,
+        service_name: Option<&str>,
+        envelope: Vec<Vec<u8>>,
+        payload: Vec<Vec<u8>>,
+        options: RequestOptions,
+    ) {
+        let letter = DeadLetter {
+            id: self.next_id,
+            kind,
+            service_name: service_name.map(str::to_string),
+            envelope,
+            payload,
+            options,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+        self.next_id += 1;
+        log::warn!(
+            "Dead letter {} recorded ({}) for client '{}'",
+            letter.id,
+            kind.as_str(),
+            envelope_to_hex(&letter.envelope).join(".")
+        );
+
+        if let Some(output) = &mut self.output {
+            writeln!(output, "{}", letter.details())
+                .unwrap_or_else(|err| log::error!("Failed to write dead letter : {}", err));
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        if self.letters.len() == self.capacity {
+            self.letters.pop_front();
+        }
+        self.letters.push_back(letter);
+    }
+
+    ///
+    /// Returns the summary of every letter kept in memory, from the oldest to the newest
+    ///
+    pub fn list(&self) -> Value {
+        Value::Array(self.letters.iter().map(DeadLetter::summary).collect())
+    }
+
+    pub fn get(&self, id: u64) -> Option<&DeadLetter> {
+        self.letters.iter().find(|letter| letter.id == id)
+    }
+
+    ///
+    /// Forgets a letter, once replayed
+    ///
+    pub fn remove(&mut self, id: u64) -> Option<DeadLetter> {
+        let pos = self.letters.iter().position(|letter| letter.id == id)?;
+        self.letters.remove(pos)
+    }
+}
@@ -0,0 +1,204 @@
+use crate::errors::RustydomoError;
+use rand::Rng;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+///
+/// Way the worker handling a request is selected among the workers of a service
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DispatchStrategy {
+    /// Each worker in turn
+    #[default]
+    RoundRobin,
+    /// Worker with the fewest requests waiting for their FINAL answer
+    LeastOutstanding,
+    /// Each worker in turn, proportionally to the weight it declared when registering
+    Weighted,
+    /// Less loaded of two workers picked at random
+    RandomOfTwo,
+    /// Worker that answered its last request the fastest
+    LeastLatency,
+}
+
+impl FromStr for DispatchStrategy {
+    type Err = RustydomoError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(DispatchStrategy::RoundRobin),
+            "least-outstanding" => Ok(DispatchStrategy::LeastOutstanding),
+            "weighted" => Ok(DispatchStrategy::Weighted),
+            "random-of-two" => Ok(DispatchStrategy::RandomOfTwo),
+            "least-latency" => Ok(DispatchStrategy::LeastLatency),
+            _ => Err(RustydomoError::ConfigurationError(format!(
+                "unknown dispatch strategy '{value}'"
+            ))),
+        }
+    }
+}
+
+impl Display for DispatchStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DispatchStrategy::RoundRobin => "round-robin",
+            DispatchStrategy::LeastOutstanding => "least-outstanding",
+            DispatchStrategy::Weighted => "weighted",
+            DispatchStrategy::RandomOfTwo => "random-of-two",
+            DispatchStrategy::LeastLatency => "least-latency",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+///
+/// What is done with requests none of the workers of their service has the labels of
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnmatchedLabelsPolicy {
+    /// Answered right away with an ERROR
+    #[default]
+    Reject,
+    /// Queued until a worker with the requested labels registers, or their deadline passes
+    Queue,
+}
+
+impl FromStr for UnmatchedLabelsPolicy {
+    type Err = RustydomoError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(UnmatchedLabelsPolicy::Reject),
+            "queue" => Ok(UnmatchedLabelsPolicy::Queue),
+            _ => Err(RustydomoError::ConfigurationError(format!(
+                "unknown unmatched labels policy '{value}'"
+            ))),
+        }
+    }
+}
+
+///
+/// Statistics recorded for each worker and used to select the one handling a request
+///
+#[derive(Clone, Copy)]
+pub struct WorkerStats {
+    /// Weight declared by the worker when registering
+    pub weight: u32,
+    /// Current weight of the smooth weighted round robin
+    current_weight: i64,
+    /// Last time a request was sent to the worker
+    last_dispatch: Option<Instant>,
+    /// Time the worker took to answer its last request
+    pub last_latency: Option<Duration>,
+}
+
+impl WorkerStats {
+    pub fn new(weight: u32) -> Self {
+        WorkerStats {
+            weight,
+            current_weight: 0,
+            last_dispatch: None,
+            last_latency: None,
+        }
+    }
+}
+
+///
+/// Worker that may handle a request, its statistics being stored back once the worker is selected
+///
+pub struct Candidate {
+    /// Number of requests sent to the worker and not answered yet
+    pub outstanding: usize,
+    pub stats: WorkerStats,
+}
+
+impl Candidate {
+    ///
+    /// Key used to pick workers in turn : those never used come first, then the one that
+    /// waited the longest
+    ///
+    fn round_robin_key(&self) -> Option<Instant> {
+        self.stats.last_dispatch
+    }
+}
+
+fn position_of_min<K: Ord>(
+    candidates: &[Candidate],
+    key: impl Fn(&Candidate) -> K,
+) -> Option<usize> {
+    candidates
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, candidate)| key(candidate))
+        .map(|(idx, _)| idx)
+}
+
+impl DispatchStrategy {
+    ///
+    /// Selects the worker a request has to be sent to, and records the dispatch
+    ///
+    /// Returns the index of the selected candidate, if any
+    ///
+    pub fn select(&self, candidates: &mut [Candidate]) -> Option<usize> {
+        let selected = match self {
+            DispatchStrategy::RoundRobin => {
+                position_of_min(candidates, |candidate| candidate.round_robin_key())
+            }
+            DispatchStrategy::LeastOutstanding => position_of_min(candidates, |candidate| {
+                (candidate.outstanding, candidate.round_robin_key())
+            }),
+            DispatchStrategy::Weighted => select_weighted(candidates),
+            DispatchStrategy::RandomOfTwo => select_random_of_two(candidates),
+            DispatchStrategy::LeastLatency => position_of_min(candidates, |candidate| {
+                // workers without any answer yet are tried first
+                (
+                    candidate.stats.last_latency.unwrap_or_default(),
+                    candidate.round_robin_key(),
+                )
+            }),
+        }?;
+
+        candidates[selected].stats.last_dispatch = Some(Instant::now());
+        Some(selected)
+    }
+}
+
+///
+/// Smooth weighted round robin : every worker gains its weight, the one with the highest current
+/// weight is selected and loses the total weight
+///
+fn select_weighted(candidates: &mut [Candidate]) -> Option<usize> {
+    let total_weight: i64 = candidates
+        .iter()
+        .map(|candidate| i64::from(candidate.stats.weight))
+        .sum();
+
+    for candidate in candidates.iter_mut() {
+        candidate.stats.current_weight += i64::from(candidate.stats.weight);
+    }
+    let selected = candidates
+        .iter()
+        .enumerate()
+        .max_by_key(|(idx, candidate)| (candidate.stats.current_weight, std::cmp::Reverse(*idx)))
+        .map(|(idx, _)| idx)?;
+    candidates[selected].stats.current_weight -= total_weight;
+
+    Some(selected)
+}
+
+fn select_random_of_two(candidates: &[Candidate]) -> Option<usize> {
+    if candidates.len() < 2 {
+        return position_of_min(candidates, |candidate| candidate.round_robin_key());
+    }
+
+    let mut rng = rand::thread_rng();
+    let first = rng.gen_range(0..candidates.len());
+    let second = (first + rng.gen_range(1..candidates.len())) % candidates.len();
+
+    if candidates[second].outstanding < candidates[first].outstanding {
+        Some(second)
+    } else {
+        Some(first)
+    }
+}
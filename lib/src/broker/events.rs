@@ -0,0 +1,116 @@
+use crate::errors::RustydomoError;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zmq::{Context, SocketType};
+
+///
+/// Lifecycle event of the broker
+///
+/// Each event is published as a two frames message : its topic, then a JSON object holding the
+/// event name (the topic), its time in milliseconds since the epoch and the fields below.
+///
+pub enum BrokerEvent {
+    /// `worker.registered` : `worker` (hexadecimal identity), `service`
+    WorkerRegistered { worker: String, service: String },
+    /// `worker.expired` : `worker`, `service`
+    WorkerExpired { worker: String, service: String },
+    /// `worker.disconnected` : `worker`, `service`
+    WorkerDisconnected { worker: String, service: String },
+    /// `service.appeared` : `service`, when its first worker registers
+    ServiceAppeared { service: String },
+    /// `service.disappeared` : `service`, when its last worker leaves
+    ServiceDisappeared { service: String },
+    /// `request.rejected` : `service`, `client` (hexadecimal identity), `status`, `reason`
+    RequestRejected {
+        service: String,
+        client: String,
+        status: String,
+        reason: String,
+    },
+    /// `queue.high_water` : `service`, `depth`, `threshold`, when the requests queued or being
+    /// handled for a service reach the threshold
+    QueueHighWater {
+        service: String,
+        depth: usize,
+        threshold: usize,
+    },
+}
+
+impl BrokerEvent {
+    pub fn topic(&self) -> &'static str {
+        match self {
+            BrokerEvent::WorkerRegistered { .. } => "worker.registered",
+            BrokerEvent::WorkerExpired { .. } => "worker.expired",
+            BrokerEvent::WorkerDisconnected { .. } => "worker.disconnected",
+            BrokerEvent::ServiceAppeared { .. } => "service.appeared",
+            BrokerEvent::ServiceDisappeared { .. } => "service.disappeared",
+            BrokerEvent::RequestRejected { .. } => "request.rejected",
+            BrokerEvent::QueueHighWater { .. } => "queue.high_water",
+        }
+    }
+
+    pub fn payload(&self) -> Value {
+        let mut payload = match self {
+            BrokerEvent::WorkerRegistered { worker, service }
+            | BrokerEvent::WorkerExpired { worker, service }
+            | BrokerEvent::WorkerDisconnected { worker, service } => {
+                json!({"worker": worker, "service": service})
+            }
+            BrokerEvent::ServiceAppeared { service }
+            | BrokerEvent::ServiceDisappeared { service } => {
+                json!({ "service": service })
+            }
+            BrokerEvent::RequestRejected {
+                service,
+                client,
+                status,
+                reason,
+            } => json!({"service": service, "client": client, "status": status, "reason": reason}),
+            BrokerEvent::QueueHighWater {
+                service,
+                depth,
+                threshold,
+            } => json!({"service": service, "depth": depth, "threshold": threshold}),
+        };
+        payload["event"] = json!(self.topic());
+        payload["timestamp_ms"] = json!(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64);
+        payload
+    }
+}
+
+///
+/// PUB socket the broker events are published on
+///
+pub struct EventPublisher {
+    pub connection: zmq::Socket,
+}
+
+impl EventPublisher {
+    pub fn bind(ctx: &Context, endpoint: &str) -> Result<Self, RustydomoError> {
+        let connection = ctx
+            .socket(SocketType::PUB)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+        connection
+            .bind(endpoint)
+            .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
+        log::info!("Publishing broker events on '{}'", endpoint);
+
+        Ok(EventPublisher { connection })
+    }
+
+    pub fn publish(&self, event: &BrokerEvent) -> Result<(), RustydomoError> {
+        log::debug!("Publishing event '{}'", event.topic());
+        self.connection
+            .send_multipart(
+                [
+                    event.topic().as_bytes(),
+                    event.payload().to_string().as_bytes(),
+                ],
+                zmq::DONTWAIT,
+            )
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+    }
+}
@@ -0,0 +1,261 @@
+use crate::broker::data_structures::{
+    matching_service_names, parse_service_requirement, parse_versioned_service,
+    ClientInteractionType,
+};
+use crate::errors::RustydomoError;
+use crate::options::RequestOptions;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use zmq::{Context, Message, SocketType};
+
+/// MMI service used to retrieve the list of services a peer broker handles locally
+pub const MMI_DISCOVERY_SERVICE: &str = "mmi.discovery";
+
+/// Number of refresh periods without any answer after which a peer services are forgotten
+const PEER_SILENCE_LIMIT: u32 = 3;
+
+///
+/// Peer broker requests can be forwarded to
+///
+/// The broker connects to the clients endpoint of its peer and behaves as a regular MDP client
+/// from its point of view.
+///
+pub struct PeerBroker {
+    pub endpoint: String,
+    pub connection: zmq::Socket,
+    /// Services the peer handled when it answered the last discovery request
+    services: HashSet<String>,
+    /// Last time the peer answered a discovery request
+    last_answer: Option<Instant>,
+}
+
+impl PeerBroker {
+    fn is_alive(&self, refresh_interval: Duration) -> bool {
+        self.last_answer
+            .map(|instant| instant.elapsed() < refresh_interval * PEER_SILENCE_LIMIT)
+            .unwrap_or(false)
+    }
+
+    ///
+    /// Updates the services the peer can handle from its answer to `mmi.discovery`
+    ///
+    pub fn update_services(&mut self, services: HashSet<String>) {
+        if services != self.services {
+            log::info!(
+                "Peer broker '{}' now handles services {:?}",
+                self.endpoint,
+                services
+            );
+        }
+        self.services = services;
+        self.last_answer = Some(Instant::now());
+    }
+}
+
+///
+/// Set of peer brokers used to handle requests that cannot be served locally
+///
+pub struct Federation {
+    pub peers: Vec<PeerBroker>,
+    refresh_interval: Duration,
+    last_refresh: Option<Instant>,
+}
+
+impl Federation {
+    pub fn new(
+        ctx: &Context,
+        endpoints: &[String],
+        refresh_interval: Duration,
+    ) -> Result<Self, RustydomoError> {
+        let mut peers = Vec::new();
+
+        for endpoint in endpoints {
+            let connection = ctx
+                .socket(SocketType::DEALER)
+                .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+            // do not queue anything for a peer that is not there, requests would be delivered
+            // much later otherwise
+            connection
+                .set_immediate(true)
+                .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+            connection
+                .set_linger(0)
+                .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+            connection
+                .connect(endpoint)
+                .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
+            log::info!("Federated with peer broker '{}'", endpoint);
+
+            peers.push(PeerBroker {
+                endpoint: endpoint.clone(),
+                connection,
+                services: HashSet::new(),
+                last_answer: None,
+            });
+        }
+
+        Ok(Federation {
+            peers,
+            refresh_interval,
+            last_refresh: None,
+        })
+    }
+
+    ///
+    /// Returns the index of the first live peer advertising the given service, a version of it
+    /// matching the requested one or a wildcard pattern matching it
+    ///
+    pub fn find_peer_for_service(&self, service_name: &str) -> Option<usize> {
+        let (base_name, requirement) = parse_service_requirement(service_name);
+        self.peers.iter().position(|peer| {
+            let has_version = || {
+                peer.services
+                    .iter()
+                    .filter_map(|name| parse_versioned_service(name))
+                    .any(|(name, version)| {
+                        name == base_name
+                            && requirement
+                                .as_ref()
+                                .is_none_or(|requirement| requirement.matches(&version))
+                    })
+            };
+            peer.is_alive(self.refresh_interval)
+                && match requirement {
+                    // versioned requests may also be handled by wildcard patterns
+                    Some(_) => {
+                        has_version()
+                            || matching_service_names(base_name)
+                                .skip(1)
+                                .any(|name| peer.services.contains(&name))
+                    }
+                    None => {
+                        matching_service_names(service_name)
+                            .any(|name| peer.services.contains(&name))
+                            || has_version()
+                    }
+                }
+        })
+    }
+
+    ///
+    /// Forwards a client request to the given peer
+    ///
+    /// The client envelope (its identity, followed by its request tag if any) is kept as envelope
+    /// frames so that the peer answers can be routed back to the client as is.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_idx` - index of the peer, as returned by `find_peer_for_service`
+    /// * `envelope` - envelope of the client the answers will be routed to
+    /// * `service_name` - requested service, with the request options
+    /// * `payload` - request body
+    ///
+    pub fn forward_request(
+        &self,
+        peer_idx: usize,
+        envelope: &[Vec<u8>],
+        service_name: &str,
+        payload: Vec<Message>,
+    ) -> Result<(), RustydomoError> {
+        log::info!(
+            "Forwarding request for service '{}' to peer broker '{}'",
+            service_name,
+            self.peers[peer_idx].endpoint
+        );
+        self.forward(
+            peer_idx,
+            envelope,
+            ClientInteractionType::Request,
+            service_name,
+            payload,
+        )
+    }
+
+    ///
+    /// Forwards the cancellation of a request previously forwarded to the given peer
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_idx` - index of the peer, as returned by `find_peer_for_service`
+    /// * `envelope` - envelope of the client that cancelled its request
+    /// * `service_name` - requested service
+    /// * `request_id` - identifier of the cancelled request
+    ///
+    pub fn forward_cancel(
+        &self,
+        peer_idx: usize,
+        envelope: &[Vec<u8>],
+        service_name: &str,
+        request_id: &str,
+    ) -> Result<(), RustydomoError> {
+        log::info!(
+            "Forwarding cancellation of request '{}' to peer broker '{}'",
+            request_id,
+            self.peers[peer_idx].endpoint
+        );
+        self.forward(
+            peer_idx,
+            envelope,
+            ClientInteractionType::Cancel,
+            // marked as forwarded, so that the peer does not forward it again
+            &RequestOptions {
+                forwarded: true,
+                ..RequestOptions::default()
+            }
+            .to_service_frame(service_name),
+            vec![Message::from(request_id)],
+        )
+    }
+
+    fn forward(
+        &self,
+        peer_idx: usize,
+        envelope: &[Vec<u8>],
+        command: ClientInteractionType,
+        service_name: &str,
+        payload: Vec<Message>,
+    ) -> Result<(), RustydomoError> {
+        let command_type: [u8; 1] = [command as u8];
+        // the body frames received from the client are sent as they are, without being copied
+        let frames: Vec<Message> = envelope
+            .iter()
+            .map(|frame| Message::from(frame.as_slice()))
+            .chain([
+                Message::from("MDPC02"),
+                Message::from(command_type.as_slice()),
+                Message::from(service_name),
+            ])
+            .chain(payload)
+            .collect();
+
+        self.peers[peer_idx]
+            .connection
+            .send_multipart(frames, zmq::DONTWAIT)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+    }
+
+    ///
+    /// Periodically asks every peer which services it can handle
+    ///
+    pub fn refresh_peers_services(&mut self) {
+        if let Some(last_refresh) = self.last_refresh {
+            if last_refresh.elapsed() < self.refresh_interval {
+                return;
+            }
+        }
+        self.last_refresh = Some(Instant::now());
+
+        let request_type: [u8; 1] = [ClientInteractionType::Request as u8];
+        for peer in self.peers.iter() {
+            let frames: [&[u8]; 3] = [
+                "MDPC02".as_bytes(),
+                request_type.as_slice(),
+                MMI_DISCOVERY_SERVICE.as_bytes(),
+            ];
+            // a peer that is not connected simply does not get the request
+            if let Err(err) = peer.connection.send_multipart(frames, zmq::DONTWAIT) {
+                log::debug!("Peer broker '{}' not reachable : {}", peer.endpoint, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,1064 @@
+use crate::broker::data_structures::{
+    ClientInteractionType, ConnectionData, Identity, WorkerInteractionType,
+};
+use crate::broker::dead_letters::DeadLetterKind;
+use crate::broker::federation::{Federation, PeerBroker, MMI_DISCOVERY_SERVICE};
+use crate::broker::majordomo_context::{AnswerRelay, MajordomoContext, Task, WorkerProperties};
+use crate::broker::mmi_handler::handle_mmi_services;
+use crate::errors::RustydomoError;
+use crate::options::RequestOptions;
+use crate::structures::MessageHelper;
+use log::{debug, info};
+use zmq::{Message, Socket};
+
+static EXPECTED_CLIENT_VERSION_HEADER: &str = "MDPC02";
+static EXPECTED_WORKER_VERSION_HEADER: &str = "MDPW02";
+
+/// Status code and reason sent to clients whose request exceeds a rate limit or concurrency cap
+const RATE_LIMITED_STATUS_CODE: &str = "429";
+const RATE_LIMITED_REASON: &str = "Rate limited";
+
+/// Status code and reason sent to clients whose request deadline passed before it was dispatched
+pub const DEADLINE_EXCEEDED_STATUS_CODE: &str = "504";
+pub const DEADLINE_EXCEEDED_REASON: &str = "Deadline exceeded";
+
+/// Status code and reason sent to clients whose request no worker nor peer broker can handle
+const SERVICE_NOT_AVAILABLE_STATUS_CODE: &str = "404";
+const SERVICE_NOT_AVAILABLE_REASON: &str = "Service not available";
+
+/// Status code and reason sent to clients whose request no worker with the requested labels can
+/// handle
+pub const NO_MATCHING_WORKER_STATUS_CODE: &str = "412";
+pub const NO_MATCHING_WORKER_REASON: &str = "No worker matching the requested labels";
+
+/// Status code and reason sent to clients whose request has too many frames or bytes
+const REQUEST_TOO_LARGE_STATUS_CODE: &str = "413";
+const REQUEST_TOO_LARGE_REASON: &str = "Request too large";
+
+/// Status code and reason sent to clients whose request can not be queued because too many bytes
+/// are queued already
+pub const OVERLOADED_STATUS_CODE: &str = "503";
+pub const OVERLOADED_REASON: &str = "Broker overloaded";
+
+/// Status code and reason sent to clients whose request was accepted, but whose service has no
+/// worker left to handle it
+pub const NO_WORKER_LEFT_STATUS_CODE: &str = "503";
+pub const NO_WORKER_LEFT_REASON: &str = "No worker left to handle the request";
+
+/// Status code and reasons sent to clients whose broadcast request cannot meet its gather policy
+pub const GATHER_FAILED_STATUS_CODE: &str = "503";
+pub const NOT_ENOUGH_WORKERS_REASON: &str = "Not enough workers to meet the gather policy";
+pub const WORKERS_LOST_REASON: &str = "Workers lost before the gather policy was met";
+
+fn receive_data(sock: &Socket) -> Result<Message, RustydomoError> {
+    sock.recv_msg(0)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+}
+
+///
+/// Reads the frames left from the message being read, if any
+///
+/// Used when a message is rejected halfway, so that its remaining frames are not read as the
+/// beginning of the next message.
+///
+fn discard_remaining_frames(sock: &Socket) -> Result<(), RustydomoError> {
+    if sock
+        .get_rcvmore()
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?
+    {
+        discard_message(sock)?;
+    }
+    Ok(())
+}
+
+pub fn handle_client_messages(
+    clients_connection: &ConnectionData,
+    workers_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+    federation: &Federation,
+) -> Result<(), RustydomoError> {
+    let result = process_client_message(clients_connection, workers_connection, ctx, federation);
+    if result.is_err() {
+        discard_remaining_frames(&clients_connection.connection)?;
+    }
+    result
+}
+
+fn process_client_message(
+    clients_connection: &ConnectionData,
+    workers_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+    federation: &Federation,
+) -> Result<(), RustydomoError> {
+    // A REQUEST command consists of a multipart message of 4 or more frames, formatted on the wire as follows:
+    // Frame 0: “MDPC02” (six bytes, representing MDP/Client v0.2)
+    // Frame 1: 0x01 (one byte, representing REQUEST)
+    // Frame 2: Service name (printable string), optionally followed by request options
+    //          (e.g. "echo?ttl_ms=1500")
+    // Frames 3+: Request body (opaque binary)
+
+    // finally retrieve the first element of the actual content : the client id
+    let client_id = receive_data(&clients_connection.connection)?;
+    let id = Identity::try_from(&(*client_id)).unwrap();
+    log::debug!("Client {:?} sent a command", id.value);
+
+    assert!(client_id.get_more());
+    // the client id may be followed by extra envelope frames (e.g. a request tag, or the client
+    // identity when the request was forwarded by a peer broker). They are all kept so that
+    // answers can be routed back through them
+    let mut envelope: Vec<Vec<u8>> = vec![id.value.clone()];
+    // ensure that we are reading a valid MDP client signa by checking its header
+    loop {
+        // frame 0 read and handled here
+        let content = receive_data(&clients_connection.connection)?;
+        if content.as_str() == Some(EXPECTED_CLIENT_VERSION_HEADER) {
+            break;
+        }
+        if !content.get_more() {
+            ctx.stats.record_malformed_frame();
+            return Err(RustydomoError::CommunicationError(std::format!(
+                "Unrecognized protocol frame received. Expected '{}', Obtained '{}'",
+                EXPECTED_CLIENT_VERSION_HEADER,
+                String::from_utf8_lossy(&content)
+            )));
+        }
+        envelope.push((*content).to_vec());
+    }
+
+    // frame 1 : command type
+    let content = receive_data(&clients_connection.connection)?;
+    let command_type = content.first().copied().unwrap_or_default();
+
+    match command_type {
+        x if x == ClientInteractionType::Request as u8 => {
+            debug!("Received client request");
+        }
+        x if x == ClientInteractionType::Cancel as u8 => {
+            return process_client_cancel(
+                clients_connection,
+                workers_connection,
+                ctx,
+                federation,
+                &envelope,
+            );
+        }
+        val => {
+            ctx.stats.record_malformed_frame();
+            return Err(RustydomoError::UnrecognizedCommandType(val));
+        }
+    }
+
+    // frame 2 : service name
+    let content = receive_data(&clients_connection.connection)?;
+    let (service_name, options) = match content.as_str().map(RequestOptions::from_service_frame) {
+        Some(Ok((name, options))) => (name.to_string(), options),
+        Some(Err(err)) => {
+            ctx.stats.record_malformed_frame();
+            return Err(err);
+        }
+        None => {
+            ctx.stats.record_malformed_frame();
+            return Err(RustydomoError::ConversionError(
+                "Service name is not a valid string".into(),
+            ));
+        }
+    };
+    debug!("Service name called : {}", service_name);
+
+    // check whether or not we have to handle an MMI request before
+    if !handle_mmi_services(
+        ctx,
+        &service_name,
+        &envelope,
+        &clients_connection.connection,
+    ) {
+        // next frames are service-specific, they are not kept anymore once the request is
+        // known to be too large. Frames are kept as received, to be forwarded without any copy
+        let mut body: Vec<Message> = Vec::new();
+        let mut body_bytes = 0;
+        let mut too_large = false;
+        let mut has_more = content.get_more();
+        while has_more {
+            let frame = receive_data(&clients_connection.connection)?;
+            has_more = frame.get_more();
+            if too_large {
+                continue;
+            }
+            body_bytes += frame.len();
+            if ctx.size_limits.request_fits(body.len() + 1, body_bytes) {
+                body.push(frame);
+            } else {
+                too_large = true;
+                body.clear();
+            }
+        }
+        ctx.stats.record_request(&service_name);
+
+        if too_large {
+            debug!("Request for service '{service_name}' too large");
+            ctx.stats.record_too_large(&service_name);
+            ctx.record_rejection(
+                &service_name,
+                &envelope,
+                REQUEST_TOO_LARGE_STATUS_CODE,
+                REQUEST_TOO_LARGE_REASON,
+            );
+            return send_client_error(
+                &clients_connection.connection,
+                &envelope,
+                &service_name,
+                REQUEST_TOO_LARGE_STATUS_CODE,
+                REQUEST_TOO_LARGE_REASON,
+            );
+        }
+
+        if !ctx.admit_request(&service_name, &id.value) {
+            debug!("Request for service '{service_name}' rate limited");
+            ctx.stats.record_rate_limited(&service_name);
+            ctx.record_rejection(
+                &service_name,
+                &envelope,
+                RATE_LIMITED_STATUS_CODE,
+                RATE_LIMITED_REASON,
+            );
+            return send_client_error(
+                &clients_connection.connection,
+                &envelope,
+                &service_name,
+                RATE_LIMITED_STATUS_CODE,
+                RATE_LIMITED_REASON,
+            );
+        }
+
+        let options = match options.request_id {
+            Some(_) => options,
+            None => options.with_request_id(&format!("{:016x}", rand::random::<u64>())),
+        };
+        debug!(
+            "Request '{}' for service '{}' received from client {}",
+            options.request_id.as_deref().unwrap_or_default(),
+            service_name,
+            id.to_hex()
+        );
+        let task = Task::new(service_name, envelope, body, options);
+        if task.is_expired() {
+            debug!(
+                "Request for service '{}' expired on arrival",
+                task.service_name
+            );
+            ctx.stats.record_expired(&task.service_name);
+            ctx.record_rejection(
+                &task.service_name,
+                &task.envelope,
+                DEADLINE_EXCEEDED_STATUS_CODE,
+                DEADLINE_EXCEEDED_REASON,
+            );
+            return send_client_error(
+                &clients_connection.connection,
+                &task.envelope,
+                &task.service_name,
+                DEADLINE_EXCEEDED_STATUS_CODE,
+                DEADLINE_EXCEEDED_REASON,
+            );
+        }
+
+        // requests no local worker handles go to a peer broker first, then to the fallback service
+        if !ctx.has_workers_for(&task.service_name) {
+            // only requests coming directly from a client are forwarded, so that a request can
+            // not bounce from one peer to another
+            let peer_idx = if task.options.forwarded {
+                None
+            } else {
+                federation.find_peer_for_service(&task.service_name)
+            };
+            if let Some(peer_idx) = peer_idx {
+                let Task {
+                    service_name,
+                    envelope,
+                    payload,
+                    options,
+                    ..
+                } = task;
+                return federation.forward_request(
+                    peer_idx,
+                    &envelope,
+                    // options are forwarded as well, with the budget left
+                    &RequestOptions {
+                        forwarded: true,
+                        ..options
+                    }
+                    .to_service_frame(&service_name),
+                    payload,
+                );
+            }
+            if !ctx.can_handle_service(&task.service_name) {
+                debug!("Cannot handle service '{}'", task.service_name);
+                ctx.stats.record_error(&task.service_name);
+                ctx.record_rejection(
+                    &task.service_name,
+                    &task.envelope,
+                    SERVICE_NOT_AVAILABLE_STATUS_CODE,
+                    SERVICE_NOT_AVAILABLE_REASON,
+                );
+                return send_client_error(
+                    &clients_connection.connection,
+                    &task.envelope,
+                    &task.service_name,
+                    SERVICE_NOT_AVAILABLE_STATUS_CODE,
+                    SERVICE_NOT_AVAILABLE_REASON,
+                );
+            }
+        }
+        // at this point we can just send the payload to be handled to context
+        ctx.send_task_to_worker(&workers_connection.connection, task)?;
+    }
+    Ok(())
+}
+
+///
+/// Handles the CANCEL command of a client, once its envelope and command type were read
+///
+fn process_client_cancel(
+    clients_connection: &ConnectionData,
+    workers_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+    federation: &Federation,
+    envelope: &[Vec<u8>],
+) -> Result<(), RustydomoError> {
+    // Frame 2: Service name
+    // Frame 3: Request ID
+    let frames = clients_connection
+        .connection
+        .recv_multipart(0)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    let (service_frame, request_id) = match frames.as_slice() {
+        [service_frame, request_id] => (
+            String::from_utf8_lossy(service_frame),
+            String::from_utf8_lossy(request_id),
+        ),
+        _ => {
+            ctx.stats.record_malformed_frame();
+            return Err(RustydomoError::ConversionError(
+                "CANCEL expects a service name and a request ID".into(),
+            ));
+        }
+    };
+    let (service_name, options) = RequestOptions::from_service_frame(&service_frame)?;
+    debug!("Client cancelled request '{}'", request_id);
+
+    if !ctx.cancel_request(&workers_connection.connection, envelope, &request_id)? {
+        // the request may have been forwarded to a peer
+        match federation.find_peer_for_service(service_name) {
+            Some(peer_idx) if !options.forwarded => {
+                federation.forward_cancel(peer_idx, envelope, service_name, &request_id)?
+            }
+            _ => debug!("Cancelled request '{}' not found", request_id),
+        }
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn display_content(entry: &[u8]) {
+    log::debug!(
+        "{}",
+        entry
+            .iter()
+            .map(|val| std::format!("{:02X}", val))
+            .collect::<String>()
+    );
+}
+
+fn send_residual_data(
+    sock_to_read: &zmq::Socket,
+    sock_to_send_to: &zmq::Socket,
+) -> Result<(), RustydomoError> {
+    loop {
+        let data = receive_data(sock_to_read)?;
+        let has_more = data.get_more();
+        sock_to_send_to
+            .send(data, if has_more { zmq::SNDMORE } else { 0 })
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        if !has_more {
+            break;
+        }
+    }
+    Ok(())
+}
+
+///
+/// Client envelope of a PARTIAL/FINAL answer from a worker
+///
+struct AnswerEnvelope {
+    /// Envelope without the request options the broker added for the worker
+    frames: Vec<Vec<u8>>,
+    /// Request options, if the worker sent them back
+    options: Option<RequestOptions>,
+    /// Whether or not the answer has a body
+    has_payload: bool,
+}
+
+///
+/// Reads the client envelope of a PARTIAL/FINAL answer from a worker, up to the empty frame
+///
+fn read_answer_envelope(workers_socket: &zmq::Socket) -> Result<AnswerEnvelope, RustydomoError> {
+    let mut envelope: Vec<Vec<u8>> = Vec::new();
+
+    loop {
+        let client_identity = receive_data(workers_socket)?;
+
+        // while we are not on an emlpty frame, we continue to read the frames as they are
+        // considered to be part of the identity packets the client sent
+        if !client_identity.is_empty() {
+            envelope.push((*client_identity).to_vec());
+        } else {
+            // request options were appended to the envelope for the worker only, after the
+            // client identity
+            let options = match envelope.last() {
+                Some(frame) if envelope.len() > 1 => RequestOptions::from_envelope_frame(frame),
+                _ => None,
+            };
+            if options.is_some() {
+                envelope.pop();
+            }
+            return Ok(AnswerEnvelope {
+                frames: envelope,
+                options,
+                has_payload: client_identity.get_more(),
+            });
+        }
+    }
+}
+
+///
+/// Sends the identity frame routing a message to a client
+///
+/// Returns false when the message can not be routed, the client being disconnected (the clients
+/// router is ROUTER_MANDATORY) or not reading its messages, in which case nothing was sent
+///
+/// # Arguments
+///
+/// * `clients_socket` - socket used to answer clients
+/// * `identity` - identity of the client connection, first frame of its envelope
+///
+pub fn route_to_client(clients_socket: &Socket, identity: &[u8]) -> Result<bool, RustydomoError> {
+    match clients_socket.send(identity, zmq::SNDMORE | zmq::DONTWAIT) {
+        Ok(()) => Ok(true),
+        Err(zmq::Error::EHOSTUNREACH) | Err(zmq::Error::EAGAIN) => Ok(false),
+        Err(err) => Err(RustydomoError::CommunicationError(err.to_string())),
+    }
+}
+
+///
+/// Relays a PARTIAL/FINAL answer from a worker to the client it is addressed to
+///
+/// Returns the frames that would have followed the command when the client can not be reached
+///
+/// # Arguments
+///
+/// * `workers_socket` - socket the rest of the answer is read from
+/// * `clients_socket` - socket used to answer clients
+/// * `envelope` - client envelope the answer is routed through
+/// * `has_payload` - whether or not the answer has a body to read
+/// * `response_type` - PARTIAL or FINAL
+/// * `worker` - hexadecimal identity of the worker, sent before the body of answers to broadcast
+///   requests
+///
+fn relay_answer(
+    workers_socket: &zmq::Socket,
+    clients_socket: &zmq::Socket,
+    envelope: &[Vec<u8>],
+    has_payload: bool,
+    response_type: ClientInteractionType,
+    worker: Option<&str>,
+) -> Result<Option<Vec<Vec<u8>>>, RustydomoError> {
+    let (identity, tags) = envelope
+        .split_first()
+        .ok_or_else(|| RustydomoError::CommunicationError("Answer without envelope".into()))?;
+    if !route_to_client(clients_socket, identity)? {
+        let mut undelivered: Vec<Vec<u8>> = worker
+            .map(|worker| worker.as_bytes().to_vec())
+            .into_iter()
+            .collect();
+        if has_payload {
+            undelivered.extend(
+                workers_socket
+                    .recv_multipart(0)
+                    .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?,
+            );
+        }
+        return Ok(Some(undelivered));
+    }
+    for frame in tags.iter() {
+        clients_socket
+            .send(frame, zmq::SNDMORE)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    }
+
+    clients_socket
+        .send::<&[u8]>("MDPC02".as_bytes(), zmq::SNDMORE)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+
+    let data_to_send: [u8; 1] = [response_type as u8];
+
+    if let Some(worker) = worker {
+        clients_socket
+            .send(data_to_send.as_slice(), zmq::SNDMORE)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        clients_socket
+            .send(worker, if has_payload { zmq::SNDMORE } else { 0 })
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        if has_payload {
+            send_residual_data(workers_socket, clients_socket)?;
+        }
+    } else if has_payload {
+        clients_socket
+            .send(data_to_send.as_slice(), zmq::SNDMORE)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+
+        send_residual_data(workers_socket, clients_socket)?;
+    } else {
+        clients_socket
+            .send(data_to_send.as_slice(), 0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    }
+    Ok(None)
+}
+
+///
+/// Sends a PARTIAL/FINAL answer kept by the broker (e.g. a dead letter) to a client
+///
+/// Returns false when the client can not be reached
+///
+/// # Arguments
+///
+/// * `clients_socket` - socket used to answer clients
+/// * `envelope` - client envelope the answer is routed through
+/// * `response_type` - PARTIAL or FINAL
+/// * `payload` - frames following the command
+///
+pub fn send_client_answer(
+    clients_socket: &Socket,
+    envelope: &[Vec<u8>],
+    response_type: ClientInteractionType,
+    payload: &[Vec<u8>],
+) -> Result<bool, RustydomoError> {
+    let (identity, tags) = match envelope.split_first() {
+        Some(split) => split,
+        None => return Ok(false),
+    };
+    if !route_to_client(clients_socket, identity)? {
+        return Ok(false);
+    }
+    for frame in tags.iter() {
+        clients_socket
+            .send(frame, zmq::SNDMORE)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    }
+    let command: [u8; 1] = [response_type as u8];
+    clients_socket
+        .send::<&[u8]>(EXPECTED_CLIENT_VERSION_HEADER.as_bytes(), zmq::SNDMORE)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    clients_socket
+        .send(
+            command.as_slice(),
+            if payload.is_empty() { 0 } else { zmq::SNDMORE },
+        )
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    clients_socket
+        .send_multipart(payload.iter(), 0)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    Ok(true)
+}
+
+///
+/// Relays a PARTIAL/FINAL answer from a worker, unless its request was cancelled, and records it
+///
+fn handle_worker_answer(
+    clients_connection: &ConnectionData,
+    workers_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+    worker_identity: &[u8],
+    response_type: ClientInteractionType,
+) -> Result<(), RustydomoError> {
+    let is_final = matches!(response_type, ClientInteractionType::Final);
+    let AnswerEnvelope {
+        frames: envelope,
+        options,
+        has_payload,
+    } = read_answer_envelope(&workers_connection.connection)?;
+    let options = options.unwrap_or_default();
+    let request_id = options.request_id.clone();
+
+    match ctx.record_answer(worker_identity, &envelope, request_id.as_deref(), is_final) {
+        Some(AnswerRelay {
+            span,
+            command,
+            worker,
+            service_name,
+        }) => {
+            let kind = match command {
+                ClientInteractionType::Final => DeadLetterKind::LostFinal,
+                _ => DeadLetterKind::LostPartial,
+            };
+            let undelivered = span.in_scope(|| {
+                relay_answer(
+                    &workers_connection.connection,
+                    &clients_connection.connection,
+                    &envelope,
+                    has_payload,
+                    command,
+                    worker.as_deref(),
+                )
+            })?;
+            if let Some(payload) = undelivered {
+                ctx.dead_letters.record(
+                    kind,
+                    service_name.as_deref(),
+                    envelope.clone(),
+                    payload,
+                    options,
+                );
+            }
+        }
+        None => {
+            debug!("Dropping answer to a cancelled request");
+            if has_payload {
+                discard_message(&workers_connection.connection)?;
+            }
+        }
+    }
+    if is_final {
+        ctx.complete_request(
+            &workers_connection.connection,
+            worker_identity,
+            &envelope,
+            request_id.as_deref(),
+        )?;
+    }
+    Ok(())
+}
+
+///
+/// Reads the optional `key=value` frames following the service name in a READY command
+///
+/// `weight` is used by the weighted dispatch strategy, other properties are labels matched
+/// against the labels requested by clients
+///
+fn read_worker_properties(workers_socket: &Socket) -> Result<WorkerProperties, RustydomoError> {
+    let mut properties = WorkerProperties::default();
+    loop {
+        let property = receive_data(workers_socket)?;
+        match property.as_str().and_then(|entry| entry.split_once('=')) {
+            Some(("weight", value)) => {
+                properties.weight = value.parse::<u32>().map_err(|err| {
+                    RustydomoError::ConversionError(format!("Invalid worker weight : {}", err))
+                })?
+            }
+            Some((key, value)) => {
+                properties.labels.insert(key.to_string(), value.to_string());
+            }
+            None => debug!("Ignoring worker property {:?}", property.as_str()),
+        }
+        if !property.get_more() {
+            return Ok(properties);
+        }
+    }
+}
+
+///
+/// Handles all messges sent by workers$
+///
+/// # Arguments
+///
+/// * `clients_connection` - socket used to receive data from workers
+/// * `worker_connection` - socket used to receive data from workers
+/// * `ctx` - context linked to Majordomo handling
+///
+pub fn handle_worker_messages(
+    clients_connection: &ConnectionData,
+    workers_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+) -> Result<(), RustydomoError> {
+    let result = process_worker_message(clients_connection, workers_connection, ctx);
+    if result.is_err() {
+        discard_remaining_frames(&workers_connection.connection)?;
+    }
+    result
+}
+
+fn process_worker_message(
+    clients_connection: &ConnectionData,
+    workers_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+) -> Result<(), RustydomoError> {
+    // Frame 0: “MDPW02” (six bytes, representing MDP/Worker v0.2)
+    // Frame 1: (one byte, representing READY / REQUEST / HEARTBEAT)
+    // next frames are context specific and application specific
+    let worker_identity = receive_data(&workers_connection.connection)?;
+    //log::debug!("Received worker identity : {:?}", worker_identity);
+
+    // parse command type (frame 0)
+    let content = receive_data(&workers_connection.connection)?;
+    if content.as_str() != Some(EXPECTED_WORKER_VERSION_HEADER) {
+        ctx.stats.record_malformed_frame();
+        return Err(RustydomoError::CommunicationError(std::format!(
+            "Unrecognized worker protocol frame received. Expected '{}', Obtained '{}'",
+            EXPECTED_WORKER_VERSION_HEADER,
+            String::from_utf8_lossy(&content)
+        )));
+    }
+
+    // read frame 1 (command type)
+    let content = receive_data(&workers_connection.connection)?;
+
+    match content.first().copied().unwrap_or_default() {
+        x if x == WorkerInteractionType::Ready as u8 => {
+            let service_name = receive_data(&workers_connection.connection)?;
+            let properties = if service_name.get_more() {
+                read_worker_properties(&workers_connection.connection)?
+            } else {
+                WorkerProperties::default()
+            };
+            match service_name.as_str() {
+                Some(name) => {
+                    ctx.register_worker(&worker_identity, name, properties)?;
+                    // requests may have been waiting for a worker with its labels
+                    ctx.dispatch_pending_requests(&workers_connection.connection, name)?;
+                }
+                None => {
+                    ctx.stats.record_malformed_frame();
+                    return Err(RustydomoError::ConversionError(
+                        "Service name is not a valid string".into(),
+                    ));
+                }
+            }
+        }
+        x if x == WorkerInteractionType::Heartbeat as u8 => {
+            // heartbeat are quite easy to handle here
+            ctx.refresh_expiration_time(&worker_identity)?;
+        }
+        x if x == WorkerInteractionType::Partial as u8 => {
+            // any time we receive a command from worker, refresh its expiration time ( not only
+            // on heartbeat)
+            ctx.refresh_expiration_time(&worker_identity)?;
+            handle_worker_answer(
+                clients_connection,
+                workers_connection,
+                ctx,
+                &worker_identity,
+                ClientInteractionType::Partial,
+            )?;
+        }
+        x if x == WorkerInteractionType::Final as u8 => {
+            // any time we receive a command from worker, refresh its expiration time ( not only
+            // on heartbeat)
+            ctx.refresh_expiration_time(&worker_identity)?;
+            handle_worker_answer(
+                clients_connection,
+                workers_connection,
+                ctx,
+                &worker_identity,
+                ClientInteractionType::Final,
+            )?;
+        }
+        x if x == WorkerInteractionType::Disconnect as u8 => {
+            ctx.remove_worker(&worker_identity).unwrap_or_else(|err| {
+                log::warn!(
+                    "Error while trying to remove worker from list of known workers {}",
+                    err.to_string()
+                )
+            });
+        }
+
+        val => {
+            ctx.stats.record_malformed_frame();
+            return Err(RustydomoError::UnrecognizedCommandType(val));
+        }
+    }
+    Ok(())
+}
+
+///
+/// Handles messages sent back by a peer broker
+///
+/// Those are either answers to requests forwarded to this peer, which are relayed to the client
+/// they are addressed to, or answers to the services discovery sent by this broker
+///
+/// # Arguments
+///
+/// * `peer` - peer broker that sent the message
+/// * `clients_connection` - connection used to answer clients
+///
+pub fn handle_peer_messages(
+    peer: &mut PeerBroker,
+    clients_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+) -> Result<(), RustydomoError> {
+    let first_frame = receive_data(&peer.connection)?;
+
+    if first_frame.as_str() == Some(EXPECTED_CLIENT_VERSION_HEADER) {
+        // no envelope : this is the answer to our own discovery request
+        // Frame 1: command type, Frame 2: service name, Frames 3+: services handled by the peer
+        let frames = peer
+            .connection
+            .recv_multipart(0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        if frames.get(1).map(|name| name.as_slice()) == Some(MMI_DISCOVERY_SERVICE.as_bytes()) {
+            peer.update_services(
+                frames
+                    .iter()
+                    .skip(2)
+                    .map(|entry| String::from_utf8_lossy(entry).into_owned())
+                    .collect(),
+            );
+        } else {
+            debug!("Unexpected answer received from peer '{}'", peer.endpoint);
+        }
+    } else {
+        // answer to a forwarded request, the first frame being the identity of the client to
+        // route it to
+        if !first_frame.get_more() {
+            debug!("Dropping truncated answer from peer '{}'", peer.endpoint);
+        } else if route_to_client(&clients_connection.connection, &first_frame)? {
+            send_residual_data(&peer.connection, &clients_connection.connection)?;
+        } else {
+            let frames = peer
+                .connection
+                .recv_multipart(0)
+                .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+            record_lost_peer_answer(ctx, first_frame.to_vec(), frames);
+        }
+    }
+    Ok(())
+}
+
+///
+/// Records the answer to a forwarded request whose client can not be reached anymore
+///
+/// # Arguments
+///
+/// * `ctx` - context linked to Majordomo handling
+/// * `identity` - identity of the client
+/// * `frames` - frames following the identity : client tags, "MDPC02", command and body
+///
+fn record_lost_peer_answer(ctx: &mut MajordomoContext, identity: Vec<u8>, frames: Vec<Vec<u8>>) {
+    let header = match frames
+        .iter()
+        .position(|frame| frame == EXPECTED_CLIENT_VERSION_HEADER.as_bytes())
+    {
+        Some(header) => header,
+        None => return,
+    };
+    let kind = match frames.get(header + 1).and_then(|command| command.first()) {
+        Some(&command) if command == ClientInteractionType::Final as u8 => {
+            DeadLetterKind::LostFinal
+        }
+        Some(&command) if command == ClientInteractionType::Partial as u8 => {
+            DeadLetterKind::LostPartial
+        }
+        // ERRORs are not worth keeping
+        _ => return,
+    };
+    let mut envelope = vec![identity];
+    envelope.extend(frames[..header].iter().cloned());
+    ctx.dead_letters.record(
+        kind,
+        None,
+        envelope,
+        frames[header + 2..].to_vec(),
+        RequestOptions::default(),
+    );
+}
+
+///
+/// Sends an ERROR to a client, instead of the answer to its request
+///
+/// # Arguments
+///
+/// * `clients_socket` - connection used to answer clients
+/// * `envelope` - envelope of the client the error is routed to
+/// * `service_name` - service the request was sent to
+/// * `status_code` - status of the error, as used for MMI answers (e.g. "503")
+/// * `reason` - human readable description of the error
+///
+pub fn send_client_error(
+    clients_socket: &Socket,
+    envelope: &[Vec<u8>],
+    service_name: &str,
+    status_code: &str,
+    reason: &str,
+) -> Result<(), RustydomoError> {
+    let error_command: [u8; 1] = [ClientInteractionType::Error as u8];
+    let (identity, tags) = match envelope.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    if !route_to_client(clients_socket, identity)? {
+        debug!(
+            "Client of a request for service '{}' is gone, dropping its ERROR",
+            service_name
+        );
+        return Ok(());
+    }
+    for frame in tags {
+        clients_socket
+            .send(frame, zmq::SNDMORE)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    }
+    clients_socket
+        .send_multipart(
+            [
+                EXPECTED_CLIENT_VERSION_HEADER.as_bytes(),
+                error_command.as_slice(),
+                service_name.as_bytes(),
+                status_code.as_bytes(),
+                reason.as_bytes(),
+            ],
+            0,
+        )
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+}
+
+///
+/// Reads a whole client request and answers it with an ERROR without handling it
+///
+/// # Arguments
+///
+/// * `clients_connection` - connection used to receive data from clients
+/// * `ctx` - context the rejection is recorded in
+/// * `status_code` - status of the error, as used for MMI answers (e.g. "503")
+/// * `reason` - human readable description of the error
+///
+pub fn reject_client_message(
+    clients_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+    status_code: &str,
+    reason: &str,
+) -> Result<(), RustydomoError> {
+    let frames = clients_connection
+        .connection
+        .recv_multipart(0)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+
+    // envelope frames come first, up to the protocol header
+    match frames
+        .iter()
+        .position(|frame| frame.as_slice() == EXPECTED_CLIENT_VERSION_HEADER.as_bytes())
+    {
+        // cancellations do not expect any answer
+        Some(header_idx)
+            if frames.get(header_idx + 1).map(Vec::as_slice)
+                == Some([ClientInteractionType::Cancel as u8].as_slice()) =>
+        {
+            Ok(())
+        }
+        Some(header_idx) if header_idx > 0 => {
+            let service_name = frames
+                .get(header_idx + 2)
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .unwrap_or_default();
+            ctx.record_rejection(&service_name, &frames[..header_idx], status_code, reason);
+            send_client_error(
+                &clients_connection.connection,
+                &frames[..header_idx],
+                &service_name,
+                status_code,
+                reason,
+            )
+        }
+        _ => Err(RustydomoError::CommunicationError(
+            "Rejected client message is not a valid request".into(),
+        )),
+    }
+}
+
+///
+/// Reads and drops a whole message, so that the next one can be read properly
+///
+pub fn discard_message(sock: &Socket) -> Result<(), RustydomoError> {
+    sock.recv_multipart(0)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    Ok(())
+}
+
+///
+/// Answers any message from a worker with a DISCONNECT command, so that the worker connects to
+/// another broker
+///
+/// # Arguments
+///
+/// * `workers_connection` - socket used to receive data from workers
+/// * `ctx` - context linked to Majordomo handling
+///
+pub fn reject_worker_message(
+    workers_connection: &ConnectionData,
+    ctx: &mut MajordomoContext,
+) -> Result<(), RustydomoError> {
+    let worker_identity = receive_data(&workers_connection.connection)?;
+    if worker_identity.get_more() {
+        discard_message(&workers_connection.connection)?;
+    }
+    // the worker may have registered before this broker stopped accepting workers
+    ctx.remove_worker(&worker_identity).ok();
+
+    let disconnect_command: [u8; 1] = [WorkerInteractionType::Disconnect as u8];
+    workers_connection
+        .connection
+        .send(worker_identity, zmq::SNDMORE)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    workers_connection
+        .connection
+        .send(EXPECTED_WORKER_VERSION_HEADER, zmq::SNDMORE)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    workers_connection
+        .connection
+        .send(disconnect_command.as_slice(), 0)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    Ok(())
+}
+
+// generic monitor handlers
+fn handle_monitor_message(source_name: &str, sock: &ConnectionData) -> Result<(), RustydomoError> {
+    let content = receive_data(&sock.monitor_connection)?;
+    //read eventual extra data beforehand
+    let origin_addr = receive_data(&sock.monitor_connection)?;
+
+    // connection address received on second frame (as defined by zmq specification)
+    let content_helper = MessageHelper { m: content };
+
+    if let Ok(event_id) = <MessageHelper as TryInto<u16>>::try_into(content_helper) {
+        match event_id {
+            x if x == zmq::SocketEvent::HANDSHAKE_SUCCEEDED as u16 => {
+                info!(
+                    "{} accepted on {}",
+                    source_name,
+                    origin_addr.as_str().unwrap_or("<unknown>")
+                )
+            }
+            // x if x == zmq::SocketEvent::CLOSED as u16 => {
+            //     info!("{} connection closed", source_name)
+            // }
+            x if x == zmq::SocketEvent::DISCONNECTED as u16 => {
+                info!(
+                    "{} disconnected from {}",
+                    source_name,
+                    origin_addr.as_str().unwrap_or("<unknown>")
+                )
+            }
+            _ => debug!("Unrecognized event : {}", event_id),
+        }
+    } else {
+        debug!("Failed to convert event_id to u16");
+    }
+
+    Ok(())
+}
+
+pub fn handle_client_monitor_messages(sock: &ConnectionData) -> Result<(), RustydomoError> {
+    handle_monitor_message("Client", sock)
+}
+
+pub fn handle_worker_monitor_messages(sock: &ConnectionData) -> Result<(), RustydomoError> {
+    handle_monitor_message("Worker", sock)
+}
@@ -0,0 +1,59 @@
+use crate::broker::data_structures::Identity;
+use std::collections::BTreeMap;
+
+/// Number of points each worker has on the ring, so that keys are evenly spread between workers
+const VIRTUAL_NODES: u32 = 64;
+
+///
+/// Consistent hash ring mapping affinity keys to the workers of a service
+///
+/// Each worker is placed at several points of the ring, and a key goes to the worker of the first
+/// point following its hash. When a worker joins or leaves, only the keys of the ring segments it
+/// takes or gives back move to another worker.
+///
+#[derive(Default)]
+pub struct HashRing {
+    points: BTreeMap<u64, Identity>,
+}
+
+impl HashRing {
+    pub fn new<'a>(workers: impl Iterator<Item = &'a Identity>) -> Self {
+        let mut points = BTreeMap::new();
+        for identity in workers {
+            for replica in 0..VIRTUAL_NODES {
+                let mut point = identity.value.clone();
+                point.extend_from_slice(&replica.to_be_bytes());
+                points.insert(hash(&point), identity.clone());
+            }
+        }
+        HashRing { points }
+    }
+
+    ///
+    /// Returns the worker the given key maps to, skipping the workers not accepted (e.g. the ones
+    /// without the requested labels)
+    ///
+    pub fn lookup(&self, key: &str, accept: impl Fn(&Identity) -> bool) -> Option<&Identity> {
+        let key_hash = hash(key.as_bytes());
+        self.points
+            .range(key_hash..)
+            .chain(self.points.range(..key_hash))
+            .map(|(_, identity)| identity)
+            .find(|identity| accept(identity))
+    }
+}
+
+///
+/// 64 bits FNV-1a hash, mixed so that close inputs are spread over the whole ring
+///
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    // splitmix64 finalizer
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
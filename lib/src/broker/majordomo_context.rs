@@ -0,0 +1,2308 @@
+use crate::broker::data_structures::{
+    envelope_to_hex, matching_service_names, parse_service_requirement, parse_versioned_service,
+    ClientInteractionType, Identity, WorkerInteractionType,
+};
+use crate::broker::dead_letters::{DeadLetterKind, DeadLetterStore};
+use crate::broker::dispatch::{Candidate, DispatchStrategy, UnmatchedLabelsPolicy, WorkerStats};
+use crate::broker::events::BrokerEvent;
+use crate::broker::handlers::{
+    DEADLINE_EXCEEDED_REASON, DEADLINE_EXCEEDED_STATUS_CODE, GATHER_FAILED_STATUS_CODE,
+    NOT_ENOUGH_WORKERS_REASON, NO_MATCHING_WORKER_REASON, NO_MATCHING_WORKER_STATUS_CODE,
+    NO_WORKER_LEFT_REASON, NO_WORKER_LEFT_STATUS_CODE, OVERLOADED_REASON, OVERLOADED_STATUS_CODE,
+    WORKERS_LOST_REASON,
+};
+use crate::broker::hash_ring::HashRing;
+use crate::broker::rate_limit::{Outstanding, RateLimiter};
+use crate::broker::size_limits::SizeLimits;
+use crate::broker::stats::{BrokerStats, ServiceGauges};
+use crate::broker::worker_registry::{Worker, WorkerId, WorkerRegistry};
+use crate::errors::RustydomoError;
+use crate::options::{GatherPolicy, RequestOptions, MAX_PRIORITY};
+use semver::{Version, VersionReq};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// Time after which a silent worker expires, workers that just registered being given four times
+/// as long
+pub const EXPIRATION_TIME: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Time between two heartbeats sent to workers, well below the time after which they consider
+/// the broker gone
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Weight of the workers that did not declare any when registering
+const DEFAULT_WORKER_WEIGHT: u32 = 1;
+
+/// Waiting time worth one priority level for queued requests, when none is configured
+pub const DEFAULT_PRIORITY_AGING: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Requests a worker is sent before answering them, when no credit is configured
+pub const DEFAULT_WORKER_CREDIT: usize = 1;
+
+///
+/// Properties declared by a worker in READY, as `key=value` frames following the service name
+///
+pub struct WorkerProperties {
+    /// Weight used by the weighted dispatch strategy (`weight=<N>`)
+    pub weight: u32,
+    /// Every other property, matched against the labels requested by clients
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Default for WorkerProperties {
+    fn default() -> Self {
+        WorkerProperties {
+            weight: DEFAULT_WORKER_WEIGHT,
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+///
+/// Request sent to a worker and waiting for its FINAL answer
+///
+struct InFlightRequest {
+    service_name: String,
+    /// Envelope of the client the request comes from, as sent back by the worker
+    envelope: Vec<Vec<u8>>,
+    dispatched_at: std::time::Instant,
+    request_id: String,
+    /// Service whose workers handle the request, which may be a wildcard pattern or the fallback
+    /// service
+    route: String,
+    /// Whether or not the client cancelled the request, its answers being dropped
+    cancelled: bool,
+    /// Span covering the whole handling of the request
+    span: tracing::Span,
+    /// Span covering the handling of the request by the worker
+    dispatched: tracing::Span,
+    /// Span closed when the first answer is received
+    first_partial: Option<tracing::Span>,
+    /// Broadcast request this request is the copy of, if any
+    group: Option<u64>,
+}
+
+impl InFlightRequest {
+    ///
+    /// Indicates whether or not an answer sent back with the given envelope and request ID is
+    /// addressed to this request (workers may not send the request ID back)
+    ///
+    fn matches(&self, envelope: &[Vec<u8>], request_id: Option<&str>) -> bool {
+        self.envelope == envelope
+            && request_id.is_none_or(|request_id| request_id == self.request_id)
+    }
+}
+
+///
+/// Request broadcast to every worker of a service, whose answers are gathered
+///
+struct GatherGroup {
+    /// Number of FINAL answers meeting the gather policy
+    required: usize,
+    /// Number of FINAL answers received so far
+    answered: usize,
+    /// Number of workers still handling the request
+    outstanding: usize,
+}
+
+///
+/// How an answer received from a worker is relayed to the client
+///
+pub struct AnswerRelay {
+    /// Span in which the answer is relayed (a disabled span for PARTIAL answers)
+    pub span: tracing::Span,
+    /// Command the answer is relayed with, FINAL answers of broadcast requests being relayed as
+    /// PARTIAL until the gather policy is met
+    pub command: ClientInteractionType,
+    /// Hexadecimal identity of the worker, sent before the body of answers to broadcast requests
+    pub worker: Option<String>,
+    /// Service the request was sent to, unknown for answers to requests the broker does not track
+    pub service_name: Option<String>,
+}
+
+///
+/// Request dropped by the broker, whose client has to be told with an ERROR
+///
+pub struct RejectedRequest {
+    pub service_name: String,
+    /// Envelope of the client the ERROR is routed to
+    pub envelope: Vec<Vec<u8>>,
+    pub status_code: &'static str,
+    pub reason: &'static str,
+}
+
+///
+/// Outcome of an attempt to send a request to the workers of its service
+///
+enum Dispatch {
+    /// The request was sent, or dropped and its client told
+    Done,
+    /// Every worker able to handle the request already has as many requests as its credit
+    Busy(Task),
+    /// No worker of the service has the requested labels
+    Unmatched(Task),
+    /// No worker of the service is left
+    NoWorker(Task),
+}
+
+///
+/// Request received from a client, to be handled by a worker of its service
+///
+pub struct Task {
+    pub service_name: String,
+    /// Envelope of the client the answers have to be routed to
+    pub envelope: Vec<Vec<u8>>,
+    /// Body frames as received from the client, forwarded to the worker without any copy
+    pub payload: Vec<zmq::Message>,
+    pub options: RequestOptions,
+    /// Span covering the whole handling of the request
+    span: tracing::Span,
+    /// Span covering the time the request waits before being dispatched
+    queued: tracing::Span,
+}
+
+impl Task {
+    pub fn new(
+        service_name: String,
+        envelope: Vec<Vec<u8>>,
+        payload: Vec<zmq::Message>,
+        options: RequestOptions,
+    ) -> Self {
+        let span = tracing::info_span!(
+            "request",
+            request_id = options.request_id.as_deref().unwrap_or_default(),
+            service = service_name.as_str(),
+            client = envelope_to_hex(&envelope).join(".").as_str(),
+            outcome = tracing::field::Empty,
+        );
+        let queued = tracing::info_span!(parent: &span, "queued");
+        Task {
+            service_name,
+            envelope,
+            payload,
+            options,
+            span,
+            queued,
+        }
+    }
+
+    ///
+    /// Closes the span covering the time the request waited before being dispatched
+    ///
+    fn leave_queue(&mut self) {
+        drop(std::mem::replace(&mut self.queued, tracing::Span::none()));
+    }
+
+    ///
+    /// Returns the number of bytes of the request body
+    ///
+    pub fn payload_bytes(&self) -> usize {
+        self.payload.iter().map(|frame| frame.len()).sum()
+    }
+
+    pub fn request_id(&self) -> &str {
+        self.options.request_id.as_deref().unwrap_or_default()
+    }
+
+    ///
+    /// Indicates whether or not the deadline of the request passed, nobody waiting for its answer
+    ///
+    pub fn is_expired(&self) -> bool {
+        self.options.remaining_budget() == Some(std::time::Duration::ZERO)
+    }
+}
+
+///
+/// Request accepted for a paused service, dispatched once the service is resumed, or waiting for
+/// a worker with the requested labels
+///
+struct PendingRequest {
+    task: Task,
+    queued_at: std::time::Instant,
+    /// Time the request is dispatched in the order of, earlier for higher priorities
+    rank: std::time::Instant,
+}
+
+///
+/// Sends a command without any additional frame to the given worker
+///
+fn send_worker_command(
+    worker_sock: &zmq::Socket,
+    identity: &Identity,
+    command: WorkerInteractionType,
+) -> Result<(), RustydomoError> {
+    send_worker_command_with_frames(worker_sock, identity, command, &[])
+}
+
+fn send_worker_command_with_frames(
+    worker_sock: &zmq::Socket,
+    identity: &Identity,
+    command: WorkerInteractionType,
+    frames: &[&[u8]],
+) -> Result<(), RustydomoError> {
+    let command: [u8; 1] = [command as u8];
+    worker_sock
+        .send(identity.value.as_slice(), zmq::SNDMORE)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    worker_sock
+        .send("MDPW02".as_bytes(), zmq::SNDMORE)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    worker_sock
+        .send(
+            command.as_slice(),
+            if frames.is_empty() { 0 } else { zmq::SNDMORE },
+        )
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+    worker_sock
+        .send_multipart(frames, 0)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+}
+
+///
+/// Base context used to carry all useful informations for proper broker interactiions management
+///
+pub struct MajordomoContext {
+    /// All registered workers known to the system
+    workers: WorkerRegistry,
+    /// List of workers registered by service name
+    services: HashMap<String, Vec<WorkerId>>,
+    /// Last time heartbeats were sent to workers
+    last_heartbeat: Option<std::time::Instant>,
+    /// Versions registered for each service base name, with the service name their workers
+    /// registered with (`resize` -> 1.4.2 -> `resize@1.4.2`)
+    versions: HashMap<String, BTreeMap<Version, String>>,
+    /// Consistent hash ring of each service, mapping affinity keys to its workers
+    rings: HashMap<String, HashRing>,
+    /// Requests sent to each worker and not answered yet
+    in_flight: HashMap<Identity, Vec<InFlightRequest>>,
+    /// Broadcast requests being handled, by group ID
+    gather_groups: HashMap<u64, GatherGroup>,
+    next_gather_group: u64,
+    /// Service handling the requests no worker nor peer broker can handle, if any
+    fallback_service: Option<String>,
+    /// Services whose requests are queued instead of being dispatched
+    paused: HashSet<String>,
+    /// Services not accepting new requests, their workers being disconnected once idle
+    draining: HashSet<String>,
+    /// Requests queued while their service is paused, or waiting for a worker with their labels
+    pending: HashMap<String, VecDeque<PendingRequest>>,
+    /// What is done with requests no worker with the requested labels can handle
+    unmatched_labels_policy: UnmatchedLabelsPolicy,
+    /// Requests dropped by the broker, whose clients have not been told yet
+    rejected_requests: Vec<RejectedRequest>,
+    /// Lifecycle events not published yet
+    events: Vec<BrokerEvent>,
+    /// Number of requests queued or being handled for a service above which an event is raised
+    queue_high_water: Option<usize>,
+    /// Waiting time worth one priority level for queued requests
+    priority_aging: std::time::Duration,
+    /// Requests a worker is sent before answering them, the others waiting in the broker queues
+    worker_credit: usize,
+    /// Services whose queue reached the high-water mark, until it goes below again
+    high_water_reached: HashSet<String>,
+    /// Dispatch strategy of the services not using the default one
+    dispatch_strategies: HashMap<String, DispatchStrategy>,
+    default_dispatch_strategy: DispatchStrategy,
+    /// Limits applied to client requests
+    pub rate_limiter: RateLimiter,
+    /// Limits on the size of the requests, and of the requests queued
+    pub size_limits: SizeLimits,
+    /// Statistics exposed on the metrics endpoint
+    pub stats: BrokerStats,
+    /// Requests and answers the broker could not deliver
+    pub dead_letters: DeadLetterStore,
+}
+
+impl MajordomoContext {
+    pub fn new() -> Self {
+        MajordomoContext {
+            workers: WorkerRegistry::default(),
+            services: HashMap::new(),
+            last_heartbeat: None,
+            versions: HashMap::new(),
+            rings: HashMap::new(),
+            in_flight: HashMap::new(),
+            gather_groups: HashMap::new(),
+            next_gather_group: 0,
+            fallback_service: None,
+            paused: HashSet::new(),
+            draining: HashSet::new(),
+            pending: HashMap::new(),
+            unmatched_labels_policy: UnmatchedLabelsPolicy::default(),
+            rejected_requests: Vec::new(),
+            events: Vec::new(),
+            queue_high_water: None,
+            priority_aging: DEFAULT_PRIORITY_AGING,
+            worker_credit: DEFAULT_WORKER_CREDIT,
+            high_water_reached: HashSet::new(),
+            dispatch_strategies: HashMap::new(),
+            default_dispatch_strategy: DispatchStrategy::default(),
+            rate_limiter: RateLimiter::default(),
+            size_limits: SizeLimits::default(),
+            stats: BrokerStats::default(),
+            dead_letters: DeadLetterStore::default(),
+        }
+    }
+
+    /// Indicates whether or not the given service name can be handled by the broker currently
+    ///
+    /// It checks whether workers registered for this service, for a wildcard pattern matching it
+    /// or for the fallback service, their service not being drained
+    ///
+    /// # Arguments
+    ///
+    /// * `service_name` - service name to check
+    ///
+    pub fn can_handle_service(&self, service_name: &str) -> bool {
+        self.route(service_name).is_some()
+    }
+
+    ///
+    /// Indicates whether or not workers registered for the given service or for a wildcard
+    /// pattern matching it, the fallback service being ignored
+    ///
+    pub fn has_workers_for(&self, service_name: &str) -> bool {
+        self.local_route(service_name).is_some()
+    }
+
+    ///
+    /// Sets the service handling requests for services no worker nor peer broker can handle
+    ///
+    pub fn set_fallback_service(&mut self, service_name: &str) {
+        log::info!("Unknown services requests are sent to '{}'", service_name);
+        self.fallback_service = Some(service_name.to_string());
+    }
+
+    ///
+    /// Returns the service whose workers handle requests for the given service : the most specific
+    /// of the service itself, its newest version matching the requested one and the wildcard
+    /// patterns matching it (`images.resize.*`, then `images.*`) having workers, or else the
+    /// fallback service
+    ///
+    fn route(&self, service_name: &str) -> Option<String> {
+        self.local_route(service_name).or_else(|| {
+            self.fallback_service
+                .as_deref()
+                .and_then(|fallback| self.local_route(fallback))
+        })
+    }
+
+    fn local_route(&self, service_name: &str) -> Option<String> {
+        let (base_name, requirement) = parse_service_requirement(service_name);
+        // versioned requests are only handled by the matching versions and the wildcard patterns
+        let exact = match requirement {
+            Some(_) => None,
+            None => Some(service_name.to_string()).filter(|name| self.is_routable(name)),
+        };
+        exact
+            .or_else(|| self.newest_version(base_name, requirement.as_ref()))
+            .or_else(|| {
+                matching_service_names(base_name)
+                    .skip(1)
+                    .find(|name| self.is_routable(name))
+            })
+    }
+
+    fn is_routable(&self, service_name: &str) -> bool {
+        !self.draining.contains(service_name) && self.registered_workers_count(service_name) > 0
+    }
+
+    ///
+    /// Returns the service name the workers of the newest version of the given service matching
+    /// the requirement registered with, any version matching when there is no requirement
+    ///
+    fn newest_version(&self, base_name: &str, requirement: Option<&VersionReq>) -> Option<String> {
+        self.versions
+            .get(base_name)?
+            .iter()
+            .rev()
+            .filter(|(version, _)| {
+                requirement.is_none_or(|requirement| requirement.matches(version))
+            })
+            .map(|(_, service_name)| service_name)
+            .find(|service_name| self.is_routable(service_name))
+            .cloned()
+    }
+
+    ///
+    /// Returns the versions of the given service handled by at least one worker, from the newest
+    /// to the oldest
+    ///
+    pub fn service_versions(&self, base_name: &str) -> Vec<String> {
+        self.versions
+            .get(base_name)
+            .map(|versions| versions.keys().rev().map(Version::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    ///
+    /// Sets how workers are selected for the services without a specific dispatch strategy
+    ///
+    pub fn set_default_dispatch_strategy(&mut self, strategy: DispatchStrategy) {
+        self.default_dispatch_strategy = strategy;
+    }
+
+    ///
+    /// Sets how workers are selected for the given service
+    ///
+    pub fn set_dispatch_strategy(&mut self, service_name: &str, strategy: DispatchStrategy) {
+        log::info!(
+            "Dispatching requests for service '{}' with strategy '{}'",
+            service_name,
+            strategy
+        );
+        self.dispatch_strategies
+            .insert(service_name.to_string(), strategy);
+    }
+
+    fn dispatch_strategy(&self, service_name: &str) -> DispatchStrategy {
+        self.dispatch_strategies
+            .get(service_name)
+            .copied()
+            .unwrap_or(self.default_dispatch_strategy)
+    }
+
+    ///
+    /// Returns the names of all services currently handled by at least one worker
+    ///
+    pub fn available_services(&self) -> Vec<&str> {
+        self.services
+            .iter()
+            .filter(|(_, workers)| !workers.is_empty())
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    ///
+    /// Registeres given task and sent its associated payload to next available worker
+    ///
+    /// Note: if multiple workers are registerd to handle, the workers are selected in a round
+    /// robin fashion to ensure proper equity between workers
+    ///
+    /// # Arguments
+    ///
+    /// * `workers_connection` - connection used to send requests to all registered workers
+    ///
+    /// * `task` - Request to handle, with the client envelope and its payload
+    pub fn send_task_to_worker(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        task: Task,
+    ) -> Result<(), RustydomoError> {
+        let route = match self.route(&task.service_name) {
+            Some(route) => route,
+            None => return Err(RustydomoError::ServiceNotAvailable(task.service_name)),
+        };
+        if route != task.service_name {
+            log::debug!(
+                "Request for service '{}' handled by service '{}'",
+                task.service_name,
+                route
+            );
+        }
+        if self.paused.contains(&route) {
+            log::info!("Service '{}' is paused, queuing task", route);
+            self.queue_request(&route, task);
+        } else {
+            log::info!(
+                "Queuing task '{}' with payload length being",
+                task.service_name
+            );
+            self.process_tasks(workers_connection, &route, task)?;
+        }
+
+        self.check_queue_high_water(&route);
+        Ok(())
+    }
+
+    ///
+    /// Sets the number of requests queued or being handled for a service above which a
+    /// `queue.high_water` event is raised
+    ///
+    pub fn set_queue_high_water(&mut self, threshold: usize) {
+        self.queue_high_water = Some(threshold);
+    }
+
+    fn check_queue_high_water(&mut self, service_name: &str) {
+        let threshold = match self.queue_high_water {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let depth = self.in_flight_count(service_name) + self.pending_count(service_name);
+        if depth < threshold {
+            self.high_water_reached.remove(service_name);
+        } else if self.high_water_reached.insert(service_name.to_string()) {
+            log::warn!(
+                "{} requests queued or being handled for service '{}'",
+                depth,
+                service_name
+            );
+            self.events.push(BrokerEvent::QueueHighWater {
+                service: service_name.to_string(),
+                depth,
+                threshold,
+            });
+        }
+    }
+
+    ///
+    /// Returns the lifecycle events raised since the last call, to be published
+    ///
+    pub fn take_events(&mut self) -> Vec<BrokerEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    ///
+    /// Raises a `request.rejected` event for a request answered with an ERROR
+    ///
+    /// # Arguments
+    ///
+    /// * `service_name` - service the request was sent to
+    /// * `envelope` - envelope of the client that sent the request
+    /// * `status_code` - status of the error sent to the client
+    /// * `reason` - reason of the error sent to the client
+    ///
+    pub fn record_rejection(
+        &mut self,
+        service_name: &str,
+        envelope: &[Vec<u8>],
+        status_code: &str,
+        reason: &str,
+    ) {
+        self.events.push(BrokerEvent::RequestRejected {
+            service: service_name.to_string(),
+            client: envelope_to_hex(envelope).join("."),
+            status: status_code.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    ///
+    /// Registers a new worker so that it can be used to dispatch tasks
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - actual identity associated to this new worker (zmq identity)
+    /// * `service_name` - Service handled by the given worker
+    /// * `weight` - Weight declared by the worker, used by the weighted dispatch strategy
+    ///
+    pub fn register_worker(
+        &mut self,
+        identity: &[u8],
+        service_name: &str,
+        properties: WorkerProperties,
+    ) -> Result<(), RustydomoError> {
+        let identity = Identity::try_from(identity)?;
+        // a worker sending READY again starts over, the requests it was handling being lost
+        if let Some(id) = self.workers.id(&identity) {
+            log::warn!("Worker '{}' registered again", identity.to_hex());
+            self.forget_worker(id);
+        }
+        log::info!(
+            "Registered new worker for service '{}'. Identity : '{:?}'",
+            service_name,
+            identity.value
+        );
+        self.events.push(BrokerEvent::WorkerRegistered {
+            worker: identity.to_hex(),
+            service: service_name.to_string(),
+        });
+        self.stats.register_service(service_name);
+        // workers are given some time to send their first heartbeat
+        let id = self.workers.insert(Worker::new(
+            service_name,
+            identity,
+            std::time::Instant::now() + (EXPIRATION_TIME * 4),
+            properties.labels,
+            WorkerStats::new(properties.weight),
+        ));
+
+        // create entry in the map if it does not exist
+        let service_workers = self.services.entry(service_name.to_string()).or_default();
+        if service_workers.is_empty() {
+            self.events.push(BrokerEvent::ServiceAppeared {
+                service: service_name.to_string(),
+            });
+            if let Some((base_name, version)) = parse_versioned_service(service_name) {
+                self.versions
+                    .entry(base_name.to_string())
+                    .or_default()
+                    .insert(version, service_name.to_string());
+            }
+        }
+        // finally register the worker
+        service_workers.push(id);
+        self.rebuild_ring(service_name);
+
+        Ok(())
+    }
+
+    ///
+    /// Updates given worker (specified by its identity) to update its expiration date
+    /// appropriately
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - actual identity associated to the worker to be updated
+    ///
+    pub fn refresh_expiration_time(&mut self, identity: &[u8]) -> Result<(), RustydomoError> {
+        let searched_identity: Identity = Identity::try_from(identity)?;
+        self.workers.refresh(
+            &searched_identity,
+            std::time::Instant::now() + EXPIRATION_TIME,
+        );
+        Ok(())
+    }
+
+    pub fn remove_worker(&mut self, identity: &[u8]) -> Result<(), RustydomoError> {
+        let searched_identity: Identity = Identity::try_from(identity)?;
+        log::debug!("Removing worker (cause : DISCONNECT received)");
+
+        match self.workers.id(&searched_identity) {
+            Some(id) => {
+                self.forget_worker(id);
+                log::debug!("Worker removed");
+                Ok(())
+            }
+            None => Err(RustydomoError::ServiceNotAvailable(format!(
+                "Identity : {:?}",
+                searched_identity.value
+            ))),
+        }
+    }
+
+    ///
+    /// Forgets about a worker that is gone, and about the requests it was handling
+    ///
+    fn forget_worker(&mut self, id: WorkerId) -> Option<Worker> {
+        let worker = self.workers.remove(id)?;
+        self.release_worker(id, &worker, false);
+        Some(worker)
+    }
+
+    ///
+    /// Detaches a worker removed from the registry from its service, the requests it was handling
+    /// being lost
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - worker removed from the registry
+    /// * `worker` - the removed worker
+    /// * `expired` - whether the worker stopped sending heartbeats or disconnected
+    ///
+    fn release_worker(&mut self, id: WorkerId, worker: &Worker, expired: bool) {
+        let (worker_hex, service) = (worker.identity.to_hex(), worker.service_name.clone());
+        self.events.push(if expired {
+            BrokerEvent::WorkerExpired {
+                worker: worker_hex,
+                service,
+            }
+        } else {
+            BrokerEvent::WorkerDisconnected {
+                worker: worker_hex,
+                service,
+            }
+        });
+        self.detach_from_service(id, &worker.service_name);
+        self.drop_in_flight_requests(&worker.identity);
+    }
+
+    ///
+    /// Removes a worker from the workers of its service
+    ///
+    fn detach_from_service(&mut self, id: WorkerId, service_name: &str) {
+        if let Some(service_workers) = self.services.get_mut(service_name) {
+            let old_len = service_workers.len();
+            service_workers.retain(|entry| *entry != id);
+            log::debug!(
+                "Service workers removed : {}",
+                old_len - service_workers.len()
+            );
+            let disappeared = old_len > 0 && service_workers.is_empty();
+            self.rebuild_ring(service_name);
+            if disappeared {
+                self.forget_version(service_name);
+                self.events.push(BrokerEvent::ServiceDisappeared {
+                    service: service_name.to_string(),
+                });
+            }
+        }
+    }
+
+    ///
+    /// Maps the affinity keys of the given service to its current workers
+    ///
+    fn rebuild_ring(&mut self, service_name: &str) {
+        match self.services.get(service_name) {
+            Some(workers) if !workers.is_empty() => {
+                let identities: Vec<&Identity> = workers
+                    .iter()
+                    .filter_map(|id| self.workers.get(*id))
+                    .map(|worker| &worker.identity)
+                    .collect();
+                self.rings.insert(
+                    service_name.to_string(),
+                    HashRing::new(identities.into_iter()),
+                );
+            }
+            _ => {
+                self.rings.remove(service_name);
+            }
+        }
+    }
+
+    fn forget_version(&mut self, service_name: &str) {
+        if let Some((base_name, version)) = parse_versioned_service(service_name) {
+            if let Some(versions) = self.versions.get_mut(base_name) {
+                versions.remove(&version);
+                if versions.is_empty() {
+                    self.versions.remove(base_name);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Forgets about the requests sent to a worker that is gone, they will never be answered
+    ///
+    fn drop_in_flight_requests(&mut self, identity: &Identity) {
+        if let Some(requests) = self.in_flight.remove(identity) {
+            for request in requests.into_iter().filter(|request| !request.cancelled) {
+                log::warn!(
+                    "Request for service '{}' lost with its worker",
+                    request.service_name
+                );
+                if let Some(group) = request.group {
+                    self.drop_gathered_answer(group, &request);
+                    continue;
+                }
+                request.span.record("outcome", "lost");
+                self.stats.record_error(&request.service_name);
+            }
+        }
+    }
+
+    ///
+    /// Accounts for a worker lost while handling a broadcast request, rejecting the request once
+    /// the remaining workers cannot meet its gather policy anymore
+    ///
+    fn drop_gathered_answer(&mut self, group: u64, request: &InFlightRequest) {
+        let failed = match self.gather_groups.get_mut(&group) {
+            Some(gather) => {
+                gather.outstanding -= 1;
+                gather.answered + gather.outstanding < gather.required
+            }
+            None => return,
+        };
+        if !failed {
+            return;
+        }
+        self.gather_groups.remove(&group);
+        // answers of the remaining workers are dropped, the client being sent an ERROR
+        for other in self
+            .in_flight
+            .values_mut()
+            .flatten()
+            .filter(|other| other.group == Some(group))
+        {
+            other.cancelled = true;
+        }
+        request.span.record("outcome", "lost");
+        self.stats.record_error(&request.service_name);
+        self.record_rejection(
+            &request.service_name,
+            &request.envelope,
+            GATHER_FAILED_STATUS_CODE,
+            WORKERS_LOST_REASON,
+        );
+        self.rejected_requests.push(RejectedRequest {
+            service_name: request.service_name.clone(),
+            envelope: request.envelope.clone(),
+            status_code: GATHER_FAILED_STATUS_CODE,
+            reason: WORKERS_LOST_REASON,
+        });
+    }
+
+    ///
+    /// Marks the request answered by a FINAL as completed
+    ///
+    /// Once the gather policy of a broadcast request is met, the workers still handling it are
+    /// sent a CANCEL command
+    ///
+    /// # Arguments
+    ///
+    /// * `workers_connection` - connection used to send commands to workers
+    /// * `identity` - identity of the worker that answered
+    /// * `envelope` - client envelope sent back by the worker
+    /// * `request_id` - request ID sent back by the worker, if any
+    ///
+    pub fn complete_request(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        identity: &[u8],
+        envelope: &[Vec<u8>],
+        request_id: Option<&str>,
+    ) -> Result<(), RustydomoError> {
+        let worker_identity = Identity::try_from(identity).unwrap();
+        let requests = match self.in_flight.get_mut(&worker_identity) {
+            Some(requests) => requests,
+            None => return Ok(()),
+        };
+
+        if let Some(pos) = requests
+            .iter()
+            .position(|entry| entry.matches(envelope, request_id))
+        {
+            let request = requests.remove(pos);
+            if request.cancelled {
+                return Ok(());
+            }
+            request.span.record("outcome", "completed");
+            let latency = request.dispatched_at.elapsed();
+            self.stats.record_response(&request.service_name, latency);
+
+            if let Some(worker) = self
+                .workers
+                .id(&worker_identity)
+                .and_then(|id| self.workers.get_mut(id))
+            {
+                worker.stats.last_latency = Some(latency);
+            }
+            if let Some(group) = request.group {
+                self.complete_gathered_answer(workers_connection, group, &request.request_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Accounts for a FINAL answer to a broadcast request, cancelling the request on the other
+    /// workers once its gather policy is met
+    ///
+    /// The cancelled copies stop counting against the credit of their workers right away, workers
+    /// stopping on a cancellation not sending any FINAL
+    ///
+    fn complete_gathered_answer(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        group: u64,
+        request_id: &str,
+    ) -> Result<(), RustydomoError> {
+        let met = match self.gather_groups.get_mut(&group) {
+            Some(gather) => {
+                gather.outstanding -= 1;
+                gather.answered += 1;
+                gather.answered >= gather.required
+            }
+            None => return Ok(()),
+        };
+        if !met {
+            return Ok(());
+        }
+        self.gather_groups.remove(&group);
+        for (identity, requests) in self.in_flight.iter_mut() {
+            for request in requests
+                .iter_mut()
+                .filter(|request| request.group == Some(group) && !request.cancelled)
+            {
+                log::debug!(
+                    "Gather policy of request '{}' met, cancelling it on worker '{}'",
+                    request_id,
+                    identity.to_hex()
+                );
+                request.cancelled = true;
+                request.span.record("outcome", "completed");
+                send_worker_command_with_frames(
+                    workers_connection,
+                    identity,
+                    WorkerInteractionType::Cancel,
+                    &[request_id.as_bytes()],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Records an answer received for a request
+    ///
+    /// Returns how the answer has to be relayed to the client, or nothing when the answer has to
+    /// be dropped because the client cancelled the request (or the gather policy of a broadcast
+    /// request was already met)
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - identity of the worker that answered
+    /// * `envelope` - client envelope sent back by the worker
+    /// * `request_id` - request ID sent back by the worker, if any
+    /// * `is_final` - whether or not the answer is a FINAL one
+    ///
+    pub fn record_answer(
+        &mut self,
+        identity: &[u8],
+        envelope: &[Vec<u8>],
+        request_id: Option<&str>,
+        is_final: bool,
+    ) -> Option<AnswerRelay> {
+        let worker_identity = Identity::try_from(identity).unwrap();
+        let request = self
+            .in_flight
+            .get_mut(&worker_identity)
+            .and_then(|requests| {
+                requests
+                    .iter_mut()
+                    .find(|entry| entry.matches(envelope, request_id))
+            });
+        let command = if is_final {
+            ClientInteractionType::Final
+        } else {
+            ClientInteractionType::Partial
+        };
+        match request {
+            Some(request) if request.cancelled => None,
+            Some(request) => {
+                // closes the first answer span, if not closed yet
+                request.first_partial.take();
+                // the FINAL answers of a broadcast request are relayed as PARTIAL until the
+                // one meeting its gather policy
+                let (command, worker) = match request
+                    .group
+                    .and_then(|group| self.gather_groups.get(&group))
+                {
+                    Some(gather) if is_final && gather.answered + 1 < gather.required => (
+                        ClientInteractionType::Partial,
+                        Some(worker_identity.to_hex()),
+                    ),
+                    Some(_) => (command, Some(worker_identity.to_hex())),
+                    None => (command, None),
+                };
+                let span = if is_final {
+                    tracing::info_span!(parent: &request.dispatched, "final")
+                } else {
+                    tracing::Span::none()
+                };
+                Some(AnswerRelay {
+                    span,
+                    command,
+                    worker,
+                    service_name: Some(request.service_name.clone()),
+                })
+            }
+            None => Some(AnswerRelay {
+                span: tracing::Span::none(),
+                command,
+                worker: None,
+                service_name: None,
+            }),
+        }
+    }
+
+    ///
+    /// Cancels a request on behalf of its client
+    ///
+    /// A queued request is simply forgotten, while the worker handling a dispatched one is sent
+    /// a CANCEL command. Returns whether or not the request was found.
+    ///
+    /// # Arguments
+    ///
+    /// * `workers_connection` - connection used to send commands to workers
+    /// * `envelope` - envelope of the client that sent the request
+    /// * `request_id` - identifier of the request
+    ///
+    pub fn cancel_request(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        envelope: &[Vec<u8>],
+        request_id: &str,
+    ) -> Result<bool, RustydomoError> {
+        for pending in self.pending.values_mut() {
+            if let Some(pos) = pending.iter().position(|request| {
+                request.task.envelope == envelope && request.task.request_id() == request_id
+            }) {
+                if let Some(request) = pending.remove(pos) {
+                    log::debug!("Queued request '{}' cancelled", request_id);
+                    request.task.span.record("outcome", "cancelled");
+                }
+                return Ok(true);
+            }
+        }
+
+        // broadcast requests are being handled by several workers
+        let mut found = false;
+        for (identity, requests) in self.in_flight.iter_mut() {
+            if let Some(request) = requests
+                .iter_mut()
+                .find(|entry| !entry.cancelled && entry.matches(envelope, Some(request_id)))
+            {
+                log::debug!(
+                    "Request '{}' cancelled, notifying worker '{}'",
+                    request_id,
+                    identity.to_hex()
+                );
+                request.cancelled = true;
+                request.span.record("outcome", "cancelled");
+                if let Some(group) = request.group {
+                    self.gather_groups.remove(&group);
+                }
+                send_worker_command_with_frames(
+                    workers_connection,
+                    identity,
+                    WorkerInteractionType::Cancel,
+                    &[request_id.as_bytes()],
+                )?;
+                found = true;
+            }
+        }
+        Ok(found)
+    }
+
+    fn in_flight_count(&self, service_name: &str) -> usize {
+        self.in_flight
+            .values()
+            .flatten()
+            .filter(|request| request.route == service_name && !request.cancelled)
+            .count()
+    }
+
+    ///
+    /// Returns the number of requests the given worker is handling
+    ///
+    /// Cancelled requests are not counted : workers may stop handling them without sending any
+    /// answer, their entries only being kept to drop the answers still sent
+    ///
+    fn outstanding_count(&self, identity: &Identity) -> usize {
+        self.in_flight.get(identity).map_or(0, |requests| {
+            requests.iter().filter(|request| !request.cancelled).count()
+        })
+    }
+
+    fn pending_count(&self, service_name: &str) -> usize {
+        self.pending.get(service_name).map_or(0, VecDeque::len)
+    }
+
+    ///
+    /// Returns the current state of every known service, for the metrics endpoint
+    ///
+    pub fn service_gauges(&self) -> Vec<ServiceGauges<'_>> {
+        let mut gauges: Vec<ServiceGauges> = self
+            .services
+            .iter()
+            .map(|(service_name, workers)| ServiceGauges {
+                service_name,
+                queue_depth: self.in_flight_count(service_name) + self.pending_count(service_name),
+                workers: workers.len(),
+            })
+            .collect();
+        gauges.sort_by(|left, right| left.service_name.cmp(right.service_name));
+        gauges
+    }
+
+    ///
+    /// Selects the worker handling the given request among the given workers of its service, with
+    /// the dispatch strategy of this service
+    ///
+    /// Only workers with some credit left are selected, nothing being returned when they are all
+    /// busy
+    ///
+    fn select_worker(
+        &mut self,
+        service_name: &str,
+        workers: Vec<WorkerId>,
+        options: &RequestOptions,
+    ) -> Option<WorkerId> {
+        // requests with an affinity key wait for the worker the key maps to
+        if let Some(affinity_key) = &options.affinity_key {
+            let identity = self
+                .rings
+                .get(service_name)?
+                .lookup(affinity_key, |identity| {
+                    self.workers
+                        .id(identity)
+                        .is_some_and(|id| workers.contains(&id))
+                })?;
+            return self.workers.id(identity).filter(|id| self.has_credit(*id));
+        }
+
+        let workers: Vec<WorkerId> = workers
+            .into_iter()
+            .filter(|id| self.has_credit(*id))
+            .collect();
+        let mut candidates: Vec<Candidate> = workers
+            .iter()
+            .filter_map(|id| self.workers.get(*id))
+            .map(|worker| Candidate {
+                outstanding: self.outstanding_count(&worker.identity),
+                stats: worker.stats,
+            })
+            .collect();
+
+        let selected = self
+            .dispatch_strategy(service_name)
+            .select(&mut candidates)?;
+        // strategies update the statistics of every candidate (e.g. the current weights)
+        for (id, candidate) in workers.iter().zip(candidates) {
+            if let Some(worker) = self.workers.get_mut(*id) {
+                worker.stats = candidate.stats;
+            }
+        }
+        Some(workers[selected])
+    }
+
+    ///
+    /// Indicates whether or not the given worker has fewer requests to answer than its credit
+    ///
+    fn has_credit(&self, id: WorkerId) -> bool {
+        self.workers
+            .get(id)
+            .is_some_and(|worker| self.outstanding_count(&worker.identity) < self.worker_credit)
+    }
+
+    ///
+    /// Indicates whether or not a worker of the given service can be sent a request right away
+    ///
+    fn has_idle_worker(&self, service_name: &str) -> bool {
+        self.services
+            .get(service_name)
+            .is_some_and(|workers| workers.iter().any(|id| self.has_credit(*id)))
+    }
+
+    ///
+    /// Returns the workers of the given service which declared all the given labels
+    ///
+    fn workers_with_labels(
+        &self,
+        service_name: &str,
+        labels: &BTreeMap<String, String>,
+    ) -> Vec<WorkerId> {
+        self.services
+            .get(service_name)
+            .map(|workers| {
+                workers
+                    .iter()
+                    .copied()
+                    .filter(|id| {
+                        self.workers
+                            .get(*id)
+                            .is_some_and(|worker| worker.has_labels(labels))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    ///
+    /// Sends the given request to one of the workers of the service handling it, selected with
+    /// the dispatch strategy of this service, or to all of them for broadcast requests
+    ///
+    /// Requests are queued by priority when every worker able to handle them is busy, until one
+    /// has some credit left. Requests whose deadline passed are dropped instead, their clients
+    /// being told by the broker loop (see `take_rejected_requests`). Requests no worker with the
+    /// requested labels can handle are queued or rejected, depending on the unmatched labels
+    /// policy, and requests no worker is left to handle are rejected and kept as dead letters
+    ///
+    /// # Arguments
+    ///
+    /// * `workers_connection` - connection used to send requests to all registered workers
+    /// * `route` - service whose workers handle the request (see `route`)
+    /// * `task` - Request to handle, with the client envelope and its payload
+    ///
+    pub fn process_tasks(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        route: &str,
+        task: Task,
+    ) -> Result<(), RustydomoError> {
+        match self.try_dispatch(workers_connection, route, task)? {
+            Dispatch::Done => (),
+            Dispatch::Busy(task) => {
+                log::debug!(
+                    "Workers of service '{}' are busy, queuing request '{}'",
+                    route,
+                    task.request_id()
+                );
+                self.queue_request(route, task);
+            }
+            Dispatch::Unmatched(task) => self.handle_unmatched(route, task),
+            Dispatch::NoWorker(task) => self.reject_undeliverable(task),
+        }
+        Ok(())
+    }
+
+    ///
+    /// Sends the given request to the workers of the service handling it, the request being
+    /// given back when none of them can take it
+    ///
+    fn try_dispatch(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        route: &str,
+        mut task: Task,
+    ) -> Result<Dispatch, RustydomoError> {
+        if task.is_expired() {
+            self.expire(task);
+            return Ok(Dispatch::Done);
+        }
+        let workers = self.workers_with_labels(route, &task.options.labels);
+        if workers.is_empty() {
+            return Ok(if task.options.labels.is_empty() {
+                Dispatch::NoWorker(task)
+            } else {
+                Dispatch::Unmatched(task)
+            });
+        }
+        if let Some(policy) = task.options.gather {
+            self.broadcast_task(workers_connection, route, workers, task, policy)?;
+            return Ok(Dispatch::Done);
+        }
+        let worker = match self.select_worker(route, workers, &task.options) {
+            Some(worker) => worker,
+            None => return Ok(Dispatch::Busy(task)),
+        };
+        task.leave_queue();
+        let payload = std::mem::take(&mut task.payload);
+        self.send_to_worker(workers_connection, route, worker, &task, payload, None)?;
+        Ok(Dispatch::Done)
+    }
+
+    ///
+    /// Sends the given request to the given workers of the service handling it, which have the
+    /// requested labels, their answers being gathered according to the given policy
+    ///
+    /// Broadcast requests do not wait for the workers to have some credit left. Requests needing
+    /// more answers than there are workers are rejected
+    ///
+    fn broadcast_task(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        route: &str,
+        workers: Vec<WorkerId>,
+        mut task: Task,
+        policy: GatherPolicy,
+    ) -> Result<(), RustydomoError> {
+        let required = policy.required_answers(workers.len());
+        if required > workers.len() {
+            log::debug!(
+                "Request '{}' needs {} answers but service '{}' has {} workers",
+                task.request_id(),
+                required,
+                route,
+                workers.len()
+            );
+            self.stats.record_error(&task.service_name);
+            task.span.record("outcome", "rejected");
+            self.reject(task, GATHER_FAILED_STATUS_CODE, NOT_ENOUGH_WORKERS_REASON);
+            return Ok(());
+        }
+
+        task.leave_queue();
+        let group = self.next_gather_group;
+        self.next_gather_group += 1;
+        self.gather_groups.insert(
+            group,
+            GatherGroup {
+                required,
+                answered: 0,
+                outstanding: workers.len(),
+            },
+        );
+        // the last worker gets the frames received from the client, the others get copies
+        let (last, others) = workers.split_last().unwrap();
+        for worker in others {
+            let payload = task
+                .payload
+                .iter()
+                .map(|frame| zmq::Message::from(&frame[..]))
+                .collect();
+            self.send_to_worker(
+                workers_connection,
+                route,
+                *worker,
+                &task,
+                payload,
+                Some(group),
+            )?;
+        }
+        let payload = std::mem::take(&mut task.payload);
+        self.send_to_worker(
+            workers_connection,
+            route,
+            *last,
+            &task,
+            payload,
+            Some(group),
+        )?;
+        Ok(())
+    }
+
+    ///
+    /// Sends a request to the given worker and keeps track of it until its FINAL answer
+    ///
+    /// # Arguments
+    ///
+    /// * `workers_connection` - connection used to send requests to all registered workers
+    /// * `route` - service whose workers handle the request (see `route`)
+    /// * `worker` - worker the request is sent to
+    /// * `task` - Request to send, with the client envelope
+    /// * `payload` - body of the request, taken out of the task so that it is sent without copy
+    /// * `group` - broadcast request the request is a copy of, if any
+    ///
+    fn send_to_worker(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        route: &str,
+        worker: WorkerId,
+        task: &Task,
+        payload: Vec<zmq::Message>,
+        group: Option<u64>,
+    ) -> Result<(), RustydomoError> {
+        let entry = self
+            .workers
+            .get(worker)
+            .ok_or_else(|| RustydomoError::ServiceNotAvailable(task.service_name.clone()))?;
+        log::info!(
+            "Sending request '{}' for service '{}' on worker '{}'",
+            task.request_id(),
+            task.service_name,
+            entry
+        );
+        let dispatched = tracing::info_span!(
+            parent: &task.span,
+            "dispatched",
+            worker = entry.identity.to_hex().as_str()
+        );
+        let first_partial = tracing::info_span!(parent: &dispatched, "first_partial");
+        //send identity first, the the rest of the payload
+        workers_connection
+            .send(entry.identity.value.as_slice(), zmq::SNDMORE)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+
+        workers_connection
+            .send::<&[u8]>("MDPW02".as_bytes(), zmq::SNDMORE)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+
+        // convert client request to worker request : command, client envelope, an empty
+        // frame and then the request body
+        let worker_command_type: [u8; 1] = [WorkerInteractionType::Request as u8];
+        workers_connection
+            .send(worker_command_type.as_slice(), zmq::SNDMORE)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        for frame in task.envelope.iter() {
+            workers_connection
+                .send(frame, zmq::SNDMORE)
+                .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        }
+        // options (such as the budget left before the deadline) are sent to the worker
+        // as the last envelope frame
+        if !task.options.is_empty() {
+            workers_connection
+                .send(task.options.to_envelope_frame(), zmq::SNDMORE)
+                .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        }
+        workers_connection
+            .send(
+                Vec::<u8>::new(),
+                if payload.is_empty() { 0 } else { zmq::SNDMORE },
+            )
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        workers_connection
+            .send_multipart(payload, 0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+
+        self.in_flight
+            .entry(entry.identity.clone())
+            .or_default()
+            .push(InFlightRequest {
+                service_name: task.service_name.clone(),
+                envelope: task.envelope.clone(),
+                dispatched_at: std::time::Instant::now(),
+                request_id: task.request_id().to_string(),
+                route: route.to_string(),
+                cancelled: false,
+                span: task.span.clone(),
+                dispatched,
+                first_partial: Some(first_partial),
+                group,
+            });
+
+        Ok(())
+    }
+
+    ///
+    /// Queues a request for the given service, queued requests being dispatched by priority,
+    /// then in their order of arrival
+    ///
+    /// Each priority level is worth the priority aging of waiting time, so that low priority
+    /// requests end up dispatched before newer high priority ones instead of starving. Requests
+    /// exceeding the queued bytes limits are rejected instead.
+    ///
+    fn queue_request(&mut self, service_name: &str, task: Task) {
+        let request_bytes = task.payload_bytes();
+        if !self.size_limits.queue_fits(
+            self.queued_bytes(Some(service_name)) + request_bytes,
+            self.queued_bytes(None) + request_bytes,
+        ) {
+            log::warn!(
+                "Too many bytes queued to queue request '{}' for service '{}'",
+                task.request_id(),
+                service_name
+            );
+            self.stats.record_overloaded(&task.service_name);
+            task.span.record("outcome", "rejected");
+            self.reject(task, OVERLOADED_STATUS_CODE, OVERLOADED_REASON);
+            return;
+        }
+        let queued_at = std::time::Instant::now();
+        let rank =
+            queued_at + self.priority_aging * u32::from(MAX_PRIORITY - task.options.priority());
+        let pending = self.pending.entry(service_name.to_string()).or_default();
+        let pos = pending.partition_point(|request| request.rank <= rank);
+        pending.insert(
+            pos,
+            PendingRequest {
+                task,
+                queued_at,
+                rank,
+            },
+        );
+    }
+
+    ///
+    /// Returns the bytes of the bodies of the requests queued for the given service, or for all
+    /// services
+    ///
+    fn queued_bytes(&self, service_name: Option<&str>) -> usize {
+        self.pending
+            .iter()
+            .filter(|(name, _)| service_name.is_none_or(|service_name| service_name == *name))
+            .flat_map(|(_, requests)| requests.iter())
+            .map(|request| request.task.payload_bytes())
+            .sum()
+    }
+
+    ///
+    /// Sets the waiting time worth one priority level for queued requests
+    ///
+    pub fn set_priority_aging(&mut self, aging: std::time::Duration) {
+        self.priority_aging = aging;
+    }
+
+    ///
+    /// Sets the number of requests a worker is sent before answering them
+    ///
+    pub fn set_worker_credit(&mut self, credit: usize) {
+        self.worker_credit = credit;
+    }
+
+    ///
+    /// Returns the number of requests queued for the given service, by priority
+    ///
+    fn queued_by_priority(&self, service_name: &str) -> BTreeMap<u8, usize> {
+        let mut queued = BTreeMap::new();
+        for request in self.pending.get(service_name).into_iter().flatten() {
+            *queued.entry(request.task.options.priority()).or_default() += 1;
+        }
+        queued
+    }
+
+    ///
+    /// Returns the statistics of the given service, as answered to `mmi.stats` requests, or
+    /// nothing when no worker registered for it and no request is queued for it
+    ///
+    pub fn service_stats(&self, service_name: &str) -> Option<Value> {
+        if !self.services.contains_key(service_name) && self.pending_count(service_name) == 0 {
+            return None;
+        }
+        Some(json!({
+            "service": service_name,
+            "workers": self.registered_workers_count(service_name),
+            "in_flight": self.in_flight_count(service_name),
+            "queued": self.pending_count(service_name),
+            "queued_by_priority": self.queued_by_priority(service_name),
+            "queued_bytes": self.queued_bytes(Some(service_name)),
+            "paused": self.paused.contains(service_name),
+        }))
+    }
+
+    fn handle_unmatched(&mut self, route: &str, task: Task) {
+        match self.unmatched_labels_policy {
+            UnmatchedLabelsPolicy::Queue => {
+                log::debug!(
+                    "Queuing request '{}' until a worker of service '{}' has its labels",
+                    task.request_id(),
+                    route
+                );
+                self.queue_request(route, task);
+            }
+            UnmatchedLabelsPolicy::Reject => {
+                log::debug!(
+                    "No worker of service '{}' has the labels of request '{}'",
+                    route,
+                    task.request_id()
+                );
+                self.stats.record_error(&task.service_name);
+                task.span.record("outcome", "rejected");
+                self.reject(
+                    task,
+                    NO_MATCHING_WORKER_STATUS_CODE,
+                    NO_MATCHING_WORKER_REASON,
+                );
+            }
+        }
+    }
+
+    ///
+    /// Sets what is done with requests no worker with the requested labels can handle
+    ///
+    pub fn set_unmatched_labels_policy(&mut self, policy: UnmatchedLabelsPolicy) {
+        self.unmatched_labels_policy = policy;
+    }
+
+    ///
+    /// Forgets about the workers whose expiration date passed without hearing from them
+    ///
+    pub fn check_expired_workers(&mut self) {
+        let ref_time = std::time::Instant::now();
+
+        while let Some((id, worker)) = self.workers.pop_expired(ref_time) {
+            log::info!("Worker '{}' expired", worker);
+            self.stats.record_heartbeat_expiration();
+            self.release_worker(id, &worker, true);
+        }
+    }
+
+    ///
+    /// Sends HEARTBEAT to every registered worker, unless heartbeats were sent less than the
+    /// heartbeat interval ago
+    ///
+    pub fn send_heartbeat(&mut self, worker_sock: &zmq::Socket) -> Result<(), RustydomoError> {
+        if self
+            .last_heartbeat
+            .is_some_and(|last| last.elapsed() < HEARTBEAT_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.last_heartbeat = Some(std::time::Instant::now());
+        let hearbeat_command: Vec<u8> = vec![WorkerInteractionType::Heartbeat as u8];
+
+        for (_, worker) in self.workers.iter() {
+            worker_sock
+                .send(worker.identity.value.as_slice(), zmq::SNDMORE)
+                .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+            // send appropriate header
+            worker_sock
+                .send("MDPW02".as_bytes(), zmq::SNDMORE)
+                .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+            // send the final part of the command (the actual HEARTBEAT payload)
+            worker_sock
+                .send::<&[u8]>(&hearbeat_command, 0)
+                .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Sends DISCONNECT to every registered worker and forgets about all of them
+    ///
+    /// # Arguments
+    ///
+    /// * `worker_sock` - connection used to send commands to workers
+    ///
+    pub fn disconnect_all_workers(
+        &mut self,
+        worker_sock: &zmq::Socket,
+    ) -> Result<(), RustydomoError> {
+        for id in self.workers.ids() {
+            if let Some(worker) = self.forget_worker(id) {
+                log::info!("Disconnecting worker '{}'", worker);
+                send_worker_command(
+                    worker_sock,
+                    &worker.identity,
+                    WorkerInteractionType::Disconnect,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Sends DISCONNECT to the given worker and forgets about it
+    ///
+    /// # Arguments
+    ///
+    /// * `worker_sock` - connection used to send commands to workers
+    /// * `identity` - identity of the worker to evict
+    ///
+    pub fn evict_worker(
+        &mut self,
+        worker_sock: &zmq::Socket,
+        identity: &[u8],
+    ) -> Result<(), RustydomoError> {
+        self.remove_worker(identity)?;
+        log::info!("Evicting worker {:?}", identity);
+        send_worker_command(
+            worker_sock,
+            &Identity::try_from(identity)?,
+            WorkerInteractionType::Disconnect,
+        )
+    }
+
+    ///
+    /// Stops dispatching requests for the given service, new requests being queued until the
+    /// service is resumed
+    ///
+    pub fn pause_service(&mut self, service_name: &str) {
+        log::info!("Pausing service '{}'", service_name);
+        self.paused.insert(service_name.to_string());
+    }
+
+    ///
+    /// Dispatches again requests for the given service, starting with the queued ones
+    ///
+    /// # Arguments
+    ///
+    /// * `workers_connection` - connection used to send requests to all registered workers
+    /// * `service_name` - service to resume
+    ///
+    pub fn resume_service(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        service_name: &str,
+    ) -> Result<(), RustydomoError> {
+        log::info!("Resuming service '{}'", service_name);
+        self.paused.remove(service_name);
+        self.dispatch_pending_requests(workers_connection, service_name)
+    }
+
+    ///
+    /// Dispatches the requests queued for the given service by priority, unless it is paused,
+    /// until its workers have no credit left
+    ///
+    /// Requests waiting for a busy worker (e.g. the one of their affinity key) or for a worker
+    /// with their labels keep their rank in the queue
+    ///
+    /// # Arguments
+    ///
+    /// * `workers_connection` - connection used to send requests to all registered workers
+    /// * `service_name` - service whose requests are dispatched
+    ///
+    pub fn dispatch_pending_requests(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        service_name: &str,
+    ) -> Result<(), RustydomoError> {
+        if self.paused.contains(service_name) {
+            return Ok(());
+        }
+        let mut pending = self.pending.remove(service_name).unwrap_or_default();
+        let mut kept = VecDeque::new();
+        while let Some(request) = pending.pop_front() {
+            // the workers may have gone while the service was paused, its requests being rejected
+            if self.registered_workers_count(service_name) > 0
+                && !self.has_idle_worker(service_name)
+            {
+                pending.push_front(request);
+                break;
+            }
+            let PendingRequest {
+                task,
+                queued_at,
+                rank,
+            } = request;
+            let task = match self.try_dispatch(workers_connection, service_name, task)? {
+                Dispatch::Done => continue,
+                Dispatch::Busy(task) => task,
+                Dispatch::Unmatched(task)
+                    if self.unmatched_labels_policy == UnmatchedLabelsPolicy::Queue =>
+                {
+                    task
+                }
+                Dispatch::Unmatched(task) => {
+                    self.handle_unmatched(service_name, task);
+                    continue;
+                }
+                Dispatch::NoWorker(task) => {
+                    self.reject_undeliverable(task);
+                    continue;
+                }
+            };
+            kept.push_back(PendingRequest {
+                task,
+                queued_at,
+                rank,
+            });
+        }
+        kept.append(&mut pending);
+        if !kept.is_empty() {
+            self.pending.insert(service_name.to_string(), kept);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Dispatches the queued requests of every service having a worker with some credit left,
+    /// and rejects those of the services whose last worker left
+    ///
+    pub fn dispatch_queued_requests(
+        &mut self,
+        workers_connection: &zmq::Socket,
+    ) -> Result<(), RustydomoError> {
+        let services: Vec<String> = self
+            .pending
+            .keys()
+            .filter(|service_name| !self.paused.contains(*service_name))
+            .filter(|service_name| {
+                self.registered_workers_count(service_name) == 0
+                    || self.has_idle_worker(service_name)
+            })
+            .cloned()
+            .collect();
+        for service_name in services {
+            self.dispatch_pending_requests(workers_connection, &service_name)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Stops accepting new requests for the given service, its workers being disconnected once
+    /// the requests already accepted are answered
+    ///
+    /// # Arguments
+    ///
+    /// * `workers_connection` - connection used to send requests to all registered workers
+    /// * `service_name` - service to drain
+    ///
+    pub fn drain_service(
+        &mut self,
+        workers_connection: &zmq::Socket,
+        service_name: &str,
+    ) -> Result<(), RustydomoError> {
+        if self.registered_workers_count(service_name) == 0 {
+            return Err(RustydomoError::ServiceNotAvailable(service_name.into()));
+        }
+        log::info!("Draining service '{}'", service_name);
+        self.draining.insert(service_name.to_string());
+        // queued requests were accepted, they have to be handled before the workers leave
+        self.resume_service(workers_connection, service_name)
+    }
+
+    ///
+    /// Disconnects the workers of the drained services that have no request left to answer
+    ///
+    pub fn complete_drains(&mut self, worker_sock: &zmq::Socket) -> Result<(), RustydomoError> {
+        let drained: Vec<String> = self
+            .draining
+            .iter()
+            .filter(|service_name| {
+                self.in_flight_count(service_name) == 0 && self.pending_count(service_name) == 0
+            })
+            .cloned()
+            .collect();
+
+        for service_name in drained {
+            let workers: Vec<Identity> = self
+                .services
+                .get(&service_name)
+                .map(|workers| {
+                    workers
+                        .iter()
+                        .filter_map(|id| self.workers.get(*id))
+                        .map(|worker| worker.identity.clone())
+                        .collect()
+                })
+                .unwrap_or_default();
+            for identity in workers {
+                self.evict_worker(worker_sock, &identity.value)?;
+            }
+            log::info!("Service '{}' drained", service_name);
+            self.draining.remove(&service_name);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Indicates whether or not a new request is within the rate limits and concurrency caps of
+    /// its service and client
+    ///
+    /// # Arguments
+    ///
+    /// * `service_name` - service the request is sent to
+    /// * `client_identity` - identity of the client sending the request
+    ///
+    pub fn admit_request(&mut self, service_name: &str, client_identity: &[u8]) -> bool {
+        let from_client =
+            |envelope: &Vec<Vec<u8>>| envelope.first().map(Vec::as_slice) == Some(client_identity);
+        // limits apply to the requested service, whichever service handles it
+        let outstanding = Outstanding {
+            service: self
+                .in_flight
+                .values()
+                .flatten()
+                .filter(|request| request.service_name == service_name)
+                .count()
+                + self
+                    .pending
+                    .values()
+                    .flatten()
+                    .filter(|request| request.task.service_name == service_name)
+                    .count(),
+            client: self
+                .in_flight
+                .values()
+                .flatten()
+                .filter(|request| from_client(&request.envelope))
+                .count()
+                + self
+                    .pending
+                    .values()
+                    .flatten()
+                    .filter(|request| from_client(&request.task.envelope))
+                    .count(),
+        };
+        self.rate_limiter
+            .allow(service_name, client_identity, outstanding)
+    }
+
+    fn expire(&mut self, task: Task) {
+        log::debug!(
+            "Request '{}' for service '{}' expired",
+            task.request_id(),
+            task.service_name
+        );
+        task.span.record("outcome", "expired");
+        self.stats.record_expired(&task.service_name);
+        self.dead_letters.record(
+            DeadLetterKind::ExpiredRequest,
+            Some(&task.service_name),
+            task.envelope.clone(),
+            task.payload.iter().map(|frame| frame.to_vec()).collect(),
+            task.options.clone(),
+        );
+        self.reject(
+            task,
+            DEADLINE_EXCEEDED_STATUS_CODE,
+            DEADLINE_EXCEEDED_REASON,
+        );
+    }
+
+    ///
+    /// Rejects a request no worker of its service is left to handle, keeping it as a dead letter
+    /// so that it can be replayed
+    ///
+    fn reject_undeliverable(&mut self, task: Task) {
+        log::warn!(
+            "No worker left to handle request '{}' for service '{}'",
+            task.request_id(),
+            task.service_name
+        );
+        task.span.record("outcome", "rejected");
+        self.stats.record_error(&task.service_name);
+        self.dead_letters.record(
+            DeadLetterKind::UndeliverableRequest,
+            Some(&task.service_name),
+            task.envelope.clone(),
+            task.payload.iter().map(|frame| frame.to_vec()).collect(),
+            task.options.clone(),
+        );
+        self.reject(task, NO_WORKER_LEFT_STATUS_CODE, NO_WORKER_LEFT_REASON);
+    }
+
+    fn reject(&mut self, task: Task, status_code: &'static str, reason: &'static str) {
+        self.record_rejection(&task.service_name, &task.envelope, status_code, reason);
+        self.rejected_requests.push(RejectedRequest {
+            service_name: task.service_name,
+            envelope: task.envelope,
+            status_code,
+            reason,
+        });
+    }
+
+    ///
+    /// Drops the queued requests whose deadline passed
+    ///
+    /// Returns each request dropped by the broker since the last call, so that clients can be told
+    ///
+    pub fn take_rejected_requests(&mut self) -> Vec<RejectedRequest> {
+        let mut expired: Vec<Task> = Vec::new();
+        for pending in self.pending.values_mut() {
+            let (kept, dropped): (VecDeque<PendingRequest>, VecDeque<PendingRequest>) =
+                std::mem::take(pending)
+                    .into_iter()
+                    .partition(|request| !request.task.is_expired());
+            *pending = kept;
+            expired.extend(dropped.into_iter().map(|request| request.task));
+        }
+        for task in expired {
+            self.expire(task);
+        }
+        std::mem::take(&mut self.rejected_requests)
+    }
+
+    ///
+    /// Indicates whether or not some requests sent to workers are still waiting for their answer
+    ///
+    /// Cancelled requests are not waited for, nobody expecting their answer anymore
+    ///
+    pub fn has_in_flight_requests(&self) -> bool {
+        self.in_flight
+            .values()
+            .flatten()
+            .any(|request| !request.cancelled)
+    }
+
+    ///
+    /// Removes the queued requests, all of them or only those no worker is going to take (paused
+    /// services, no worker with their labels), the others waiting for a worker with some credit
+    ///
+    /// Returns the service name and client envelope of each of them, so that clients can be told
+    ///
+    pub fn take_queued_requests(&mut self, all: bool) -> Vec<(String, Vec<Vec<u8>>)> {
+        let mut requests = Vec::new();
+        for (service_name, pending) in std::mem::take(&mut self.pending) {
+            let (taken, kept): (VecDeque<PendingRequest>, VecDeque<PendingRequest>) =
+                pending.into_iter().partition(|request| {
+                    all || self.paused.contains(&service_name)
+                        || self
+                            .workers_with_labels(&service_name, &request.task.options.labels)
+                            .is_empty()
+                });
+            for request in taken {
+                self.stats.record_error(&service_name);
+                requests.push((service_name.clone(), request.task.envelope));
+            }
+            if !kept.is_empty() {
+                self.pending.insert(service_name, kept);
+            }
+        }
+        requests
+    }
+
+    ///
+    /// Indicates whether or not some requests are queued by the broker
+    ///
+    pub fn has_queued_requests(&self) -> bool {
+        self.pending.values().any(|requests| !requests.is_empty())
+    }
+
+    ///
+    /// Removes all requests sent to workers and not answered yet, which will never be answered
+    ///
+    /// Returns the service name and client envelope of each of them, so that clients can be told
+    ///
+    pub fn take_in_flight_requests(&mut self) -> Vec<(String, Vec<Vec<u8>>)> {
+        let mut requests = Vec::new();
+        self.gather_groups.clear();
+        let mut gathered = HashSet::new();
+        for request in std::mem::take(&mut self.in_flight)
+            .into_values()
+            .flatten()
+            .filter(|request| !request.cancelled)
+        {
+            // broadcast requests are answered with a single ERROR
+            if let Some(group) = request.group {
+                if !gathered.insert(group) {
+                    continue;
+                }
+            }
+            self.stats.record_error(&request.service_name);
+            requests.push((request.service_name, request.envelope));
+        }
+        requests
+    }
+
+    fn registered_workers_count(&self, service_name: &str) -> usize {
+        self.services.get(service_name).map_or(0, Vec::len)
+    }
+
+    ///
+    /// Returns the state of every known service, for the admin endpoint
+    ///
+    pub fn services_state(&self) -> Value {
+        let mut names: Vec<&String> = self
+            .services
+            .keys()
+            .chain(self.paused.iter())
+            .collect::<HashSet<&String>>()
+            .into_iter()
+            .collect();
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|service_name| {
+                json!({
+                    "name": service_name,
+                    "workers": self.registered_workers_count(service_name),
+                    "in_flight": self.in_flight_count(service_name),
+                    "queued": self.pending_count(service_name),
+                    "queued_by_priority": self.queued_by_priority(service_name),
+                    "paused": self.paused.contains(service_name),
+                    "draining": self.draining.contains(service_name),
+                    "dispatch": self.dispatch_strategy(service_name).to_string(),
+                })
+            })
+            .collect()
+    }
+
+    ///
+    /// Returns the state of every registered worker, for the admin endpoint
+    ///
+    pub fn workers_state(&self) -> Value {
+        let now = std::time::Instant::now();
+        self.workers
+            .iter()
+            .map(|(_, worker)| {
+                json!({
+                    "identity": worker.identity.to_hex(),
+                    "service": worker.service_name,
+                    "expires_in_ms": worker
+                        .expiration_date
+                        .saturating_duration_since(now)
+                        .as_millis() as u64,
+                    "in_flight": self.outstanding_count(&worker.identity),
+                    "weight": worker.stats.weight,
+                    "labels": worker.labels,
+                    "last_latency_ms": worker
+                        .stats
+                        .last_latency
+                        .map(|latency| latency.as_millis() as u64),
+                })
+            })
+            .collect()
+    }
+
+    ///
+    /// Returns the whole broker state, including the requests being handled
+    ///
+    pub fn dump_state(&self) -> Value {
+        let in_flight: Vec<Value> = self
+            .in_flight
+            .iter()
+            .flat_map(|(identity, requests)| {
+                requests.iter().map(move |request| {
+                    json!({
+                        "worker": identity.to_hex(),
+                        "service": request.service_name,
+                        "request_id": request.request_id,
+                        "envelope": envelope_to_hex(&request.envelope),
+                        "elapsed_ms": request.dispatched_at.elapsed().as_millis() as u64,
+                        "cancelled": request.cancelled,
+                    })
+                })
+            })
+            .collect();
+        let queued: Vec<Value> = self
+            .pending
+            .iter()
+            .flat_map(|(service_name, requests)| {
+                requests.iter().map(move |request| {
+                    json!({
+                        "service": service_name,
+                        "request_id": request.task.request_id(),
+                        "envelope": envelope_to_hex(&request.task.envelope),
+                        "priority": request.task.options.priority(),
+                        "frames": request.task.payload.len(),
+                        "budget_ms": request
+                            .task
+                            .options
+                            .remaining_budget()
+                            .map(|budget| budget.as_millis() as u64),
+                        "waiting_ms": request.queued_at.elapsed().as_millis() as u64,
+                    })
+                })
+            })
+            .collect();
+
+        json!({
+            "services": self.services_state(),
+            "workers": self.workers_state(),
+            "in_flight": in_flight,
+            "queued": queued,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(request_id: &str, options: RequestOptions) -> Task {
+        Task::new(
+            "echo".into(),
+            vec![b"client".to_vec()],
+            vec![zmq::Message::from("hello")],
+            options.with_request_id(request_id),
+        )
+    }
+
+    ///
+    /// Checks that a single request was rejected as undeliverable and kept as a dead letter
+    ///
+    fn assert_undeliverable(ctx: &mut MajordomoContext) {
+        let rejected = ctx.take_rejected_requests();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].envelope, vec![b"client".to_vec()]);
+        assert_eq!(rejected[0].status_code, NO_WORKER_LEFT_STATUS_CODE);
+
+        let letter = ctx.dead_letters.get(1).expect("No dead letter");
+        assert!(letter.kind == DeadLetterKind::UndeliverableRequest);
+        assert_eq!(letter.service_name.as_deref(), Some("echo"));
+        assert_eq!(letter.payload, vec![b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn requests_no_worker_can_take_are_rejected() {
+        let mut ctx = MajordomoContext::new();
+        let workers_connection = zmq::Context::new().socket(zmq::ROUTER).unwrap();
+        let options = RequestOptions::default().with_affinity_key("user");
+
+        ctx.process_tasks(&workers_connection, "echo", task("request", options))
+            .unwrap();
+
+        assert_undeliverable(&mut ctx);
+    }
+
+    #[test]
+    fn queued_requests_are_rejected_once_the_last_worker_left() {
+        let mut ctx = MajordomoContext::new();
+        let workers_connection = zmq::Context::new().socket(zmq::ROUTER).unwrap();
+        ctx.register_worker(b"worker", "echo", WorkerProperties::default())
+            .unwrap();
+        ctx.pause_service("echo");
+        ctx.send_task_to_worker(
+            &workers_connection,
+            task("request", RequestOptions::default()),
+        )
+        .unwrap();
+        assert_eq!(ctx.pending_count("echo"), 1);
+
+        ctx.remove_worker(b"worker").unwrap();
+        ctx.resume_service(&workers_connection, "echo").unwrap();
+
+        assert_eq!(ctx.pending_count("echo"), 0);
+        assert_undeliverable(&mut ctx);
+    }
+
+    fn in_flight_ids(ctx: &MajordomoContext) -> Vec<String> {
+        ctx.in_flight
+            .values()
+            .flatten()
+            .map(|request| request.request_id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn requests_wait_for_an_idle_worker_by_priority() {
+        let mut ctx = MajordomoContext::new();
+        let workers_connection = zmq::Context::new().socket(zmq::ROUTER).unwrap();
+        ctx.register_worker(b"worker", "echo", WorkerProperties::default())
+            .unwrap();
+
+        for (request_id, priority) in [("first", 0), ("batch", 0), ("interactive", 9)] {
+            let options = RequestOptions::default().with_priority(priority);
+            ctx.send_task_to_worker(&workers_connection, task(request_id, options))
+                .unwrap();
+        }
+        assert_eq!(in_flight_ids(&ctx), vec!["first"]);
+        assert_eq!(ctx.pending_count("echo"), 2);
+
+        // nothing is dispatched while the worker is busy
+        ctx.dispatch_queued_requests(&workers_connection).unwrap();
+        assert_eq!(in_flight_ids(&ctx), vec!["first"]);
+
+        ctx.complete_request(
+            &workers_connection,
+            b"worker",
+            &[b"client".to_vec()],
+            Some("first"),
+        )
+        .unwrap();
+        ctx.dispatch_queued_requests(&workers_connection).unwrap();
+        assert_eq!(in_flight_ids(&ctx), vec!["interactive"]);
+        assert_eq!(ctx.pending_count("echo"), 1);
+    }
+
+    #[test]
+    fn workers_are_sent_as_many_requests_as_their_credit() {
+        let mut ctx = MajordomoContext::new();
+        let workers_connection = zmq::Context::new().socket(zmq::ROUTER).unwrap();
+        ctx.set_worker_credit(2);
+        ctx.register_worker(b"worker", "echo", WorkerProperties::default())
+            .unwrap();
+
+        for request_id in ["first", "second", "third"] {
+            ctx.send_task_to_worker(
+                &workers_connection,
+                task(request_id, RequestOptions::default()),
+            )
+            .unwrap();
+        }
+        assert_eq!(in_flight_ids(&ctx), vec!["first", "second"]);
+        assert_eq!(ctx.pending_count("echo"), 1);
+    }
+
+    #[test]
+    fn cancelled_requests_release_the_credit_of_their_worker() {
+        let mut ctx = MajordomoContext::new();
+        let workers_connection = zmq::Context::new().socket(zmq::ROUTER).unwrap();
+        ctx.register_worker(b"worker", "echo", WorkerProperties::default())
+            .unwrap();
+        for request_id in ["cancelled", "next"] {
+            ctx.send_task_to_worker(
+                &workers_connection,
+                task(request_id, RequestOptions::default()),
+            )
+            .unwrap();
+        }
+        assert_eq!(ctx.pending_count("echo"), 1);
+
+        // the worker may never answer the cancelled request
+        assert!(ctx
+            .cancel_request(&workers_connection, &[b"client".to_vec()], "cancelled")
+            .unwrap());
+        ctx.dispatch_queued_requests(&workers_connection).unwrap();
+
+        assert_eq!(in_flight_ids(&ctx), vec!["cancelled", "next"]);
+        assert_eq!(ctx.pending_count("echo"), 0);
+        assert_eq!(ctx.in_flight_count("echo"), 1);
+    }
+
+    #[test]
+    fn cancelled_requests_are_not_waited_for() {
+        let mut ctx = MajordomoContext::new();
+        let workers_connection = zmq::Context::new().socket(zmq::ROUTER).unwrap();
+        ctx.register_worker(b"worker", "echo", WorkerProperties::default())
+            .unwrap();
+        ctx.send_task_to_worker(
+            &workers_connection,
+            task("request", RequestOptions::default()),
+        )
+        .unwrap();
+        assert!(ctx.has_in_flight_requests());
+
+        ctx.cancel_request(&workers_connection, &[b"client".to_vec()], "request")
+            .unwrap();
+
+        assert!(!ctx.has_in_flight_requests());
+    }
+
+    #[test]
+    fn held_requests_are_rejected_once_the_last_worker_left() {
+        let mut ctx = MajordomoContext::new();
+        let workers_connection = zmq::Context::new().socket(zmq::ROUTER).unwrap();
+        ctx.register_worker(b"worker", "echo", WorkerProperties::default())
+            .unwrap();
+        for request_id in ["first", "request"] {
+            ctx.send_task_to_worker(
+                &workers_connection,
+                task(request_id, RequestOptions::default()),
+            )
+            .unwrap();
+        }
+
+        ctx.remove_worker(b"worker").unwrap();
+        ctx.dispatch_queued_requests(&workers_connection).unwrap();
+
+        assert_eq!(ctx.pending_count("echo"), 0);
+        assert_undeliverable(&mut ctx);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::errors::RustydomoError;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Maximum time spent reading a scrape request or writing its answer
+const SCRAPE_IO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time the serving thread waits for a scrape before checking whether it has to stop
+const ACCEPT_POLL_TIMEOUT_MS: i64 = 100;
+
+/// Minimum time between two renderings of the metrics by the broker loop
+const SNAPSHOT_PERIOD: Duration = Duration::from_secs(1);
+
+///
+/// Minimal HTTP server exposing the broker metrics on `/metrics`
+///
+/// Scrapes are answered by a thread of their own from the last snapshot of the metrics, which the
+/// broker loop renders periodically, so that a slow or idle scraper never delays the routing of
+/// requests.
+///
+pub struct MetricsServer {
+    snapshot: Arc<Mutex<String>>,
+    last_update: Option<Instant>,
+    stop_requested: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    ///
+    /// Starts listening for scrapes on the given local port
+    ///
+    pub fn bind(port: u16) -> Result<Self, RustydomoError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+        log::info!("Serving metrics on 'http://127.0.0.1:{}/metrics'", port);
+
+        let snapshot = Arc::new(Mutex::new(String::new()));
+        let stop_requested = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let snapshot = Arc::clone(&snapshot);
+            let stop_requested = Arc::clone(&stop_requested);
+            thread::Builder::new()
+                .name("metrics".into())
+                .spawn(move || serve_scrapes(&listener, &snapshot, &stop_requested))
+                .map_err(|err| RustydomoError::Unknown(err.to_string()))?
+        };
+
+        Ok(MetricsServer {
+            snapshot,
+            last_update: None,
+            stop_requested,
+            thread: Some(thread),
+        })
+    }
+
+    ///
+    /// Renders the metrics served to the next scrapes, unless they were rendered recently
+    ///
+    /// # Arguments
+    ///
+    /// * `render_metrics` - called to build the metrics, only when the snapshot is outdated
+    ///
+    pub fn update(&mut self, render_metrics: impl FnOnce() -> String) {
+        if self
+            .last_update
+            .is_some_and(|last_update| last_update.elapsed() < SNAPSHOT_PERIOD)
+        {
+            return;
+        }
+        self.last_update = Some(Instant::now());
+        let metrics = render_metrics();
+        *self.snapshot.lock().unwrap_or_else(PoisonError::into_inner) = metrics;
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop_requested.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+///
+/// Answers scrapes one at a time until a stop is requested
+///
+fn serve_scrapes(listener: &TcpListener, snapshot: &Mutex<String>, stop_requested: &AtomicBool) {
+    while !stop_requested.load(Ordering::Relaxed) {
+        let mut poll_list = [zmq::PollItem::from_fd(listener.as_raw_fd(), zmq::POLLIN)];
+        match zmq::poll(&mut poll_list, ACCEPT_POLL_TIMEOUT_MS) {
+            Ok(0) | Err(zmq::Error::EINTR) => continue,
+            Ok(_) => (),
+            Err(err) => {
+                log::error!("Failed to wait for metrics scrapes : {}", err);
+                return;
+            }
+        }
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            // the client may have given up in the meantime
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(err) => {
+                log::error!("Failed to accept metrics scrape : {}", err);
+                continue;
+            }
+        };
+        answer_scrape(stream, || {
+            snapshot
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone()
+        })
+        .unwrap_or_else(|err| log::warn!("Failed to serve metrics : {}", err));
+    }
+}
+
+fn answer_scrape(
+    mut stream: TcpStream,
+    render_metrics: impl FnOnce() -> String,
+) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(SCRAPE_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_IO_TIMEOUT))?;
+
+    // only the request line matters, headers are read until the end of the request head
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", render_metrics())
+        }
+        _ => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,206 @@
+use crate::broker::federation::MMI_DISCOVERY_SERVICE;
+use crate::broker::handlers::route_to_client;
+use crate::broker::{data_structures::ClientInteractionType, majordomo_context::MajordomoContext};
+use crate::errors::RustydomoError;
+
+pub fn is_mmi_service(service_name: &str) -> bool {
+    service_name.starts_with("mmi.")
+}
+
+fn send_mmi_answer(
+    connection: &zmq::Socket,
+    envelope: &[Vec<u8>],
+    service_name: &str,
+    answer: &[&str],
+) {
+    write_mmi_answer(connection, envelope, service_name, answer).unwrap_or_else(|err| {
+        log::error!("Failed to answer MMI request '{}' : {}", service_name, err)
+    });
+}
+
+fn write_mmi_answer(
+    connection: &zmq::Socket,
+    envelope: &[Vec<u8>],
+    service_name: &str,
+    answer: &[&str],
+) -> Result<(), RustydomoError> {
+    let final_request_response: [u8; 1] = [ClientInteractionType::Final as u8];
+    let (identity, tags) = match envelope.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    if !route_to_client(connection, identity)? {
+        log::debug!("Client of MMI request '{}' is gone", service_name);
+        return Ok(());
+    }
+    let frames = tags
+        .iter()
+        .map(Vec::as_slice)
+        .chain([
+            "MDPC02".as_bytes(),
+            final_request_response.as_slice(),
+            service_name.as_bytes(),
+        ])
+        .chain(answer.iter().map(|frame| frame.as_bytes()));
+    connection
+        .send_multipart(frames, 0)
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+}
+
+pub fn handle_mmi_services(
+    ctx: &MajordomoContext,
+    service_name: &str,
+    envelope: &[Vec<u8>],
+    clients_connection: &zmq::Socket,
+) -> bool {
+    if !is_mmi_service(service_name) {
+        // nothing to do it it is not an mmi service
+        false
+    } else {
+        let mut remaining_payload: Vec<Vec<u8>> = Vec::new();
+
+        // first of all read all remaining frames left from client
+        if let Ok(has_more_data) = clients_connection.get_rcvmore() {
+            if has_more_data {
+                remaining_payload = clients_connection.recv_multipart(0).unwrap_or_else(|err| {
+                    log::error!("Failed to receive MMI request : {}", err);
+                    Vec::new()
+                });
+            }
+        }
+        log::debug!("Handling MMI request: {}", &service_name);
+        match service_name {
+            "mmi.service" => handle_mmi_service_request(
+                ctx,
+                envelope,
+                service_name,
+                clients_connection,
+                remaining_payload,
+            ),
+            "mmi.versions" => handle_mmi_versions_request(
+                ctx,
+                envelope,
+                service_name,
+                clients_connection,
+                remaining_payload,
+            ),
+            "mmi.stats" => handle_mmi_stats_request(
+                ctx,
+                envelope,
+                service_name,
+                clients_connection,
+                remaining_payload,
+            ),
+            MMI_DISCOVERY_SERVICE => {
+                handle_mmi_discovery_request(ctx, envelope, service_name, clients_connection)
+            }
+            _ => {
+                log::warn!("Unrecognized service : {}", service_name);
+                send_mmi_answer(clients_connection, envelope, service_name, &["501"]);
+                false
+            }
+        }
+    }
+}
+
+fn handle_mmi_service_request(
+    ctx: &MajordomoContext,
+    envelope: &[Vec<u8>],
+    service_name: &str,
+    clients_connection: &zmq::Socket,
+    payload: Vec<Vec<u8>>,
+) -> bool {
+    if !payload.is_empty() {
+        // we expect at least one parameter : the service name
+        // if it is not the case we just search for an invalid service and it will simply fail
+        let service_to_search =
+            String::from_utf8(payload[0].clone()).unwrap_or("__unknown_service__".into());
+        if !ctx.can_handle_service(&service_to_search) {
+            send_mmi_answer(clients_connection, envelope, service_name, &["404"]);
+        } else {
+            send_mmi_answer(clients_connection, envelope, service_name, &["200"]);
+        }
+        true
+    } else {
+        // if there is no payload we just indicate the error accordingly
+        log::warn!("No parameter passed to mmi.service, ignoring request");
+        false
+    }
+}
+
+///
+/// Answers with the versions of the requested service handled by local workers, from the newest
+/// to the oldest, after a "200" status ("404" when no worker registered with a version)
+///
+fn handle_mmi_versions_request(
+    ctx: &MajordomoContext,
+    envelope: &[Vec<u8>],
+    service_name: &str,
+    clients_connection: &zmq::Socket,
+    payload: Vec<Vec<u8>>,
+) -> bool {
+    match payload.first() {
+        Some(service_to_search) => {
+            let versions = ctx.service_versions(&String::from_utf8_lossy(service_to_search));
+            if versions.is_empty() {
+                send_mmi_answer(clients_connection, envelope, service_name, &["404"]);
+            } else {
+                let answer: Vec<&str> = std::iter::once("200")
+                    .chain(versions.iter().map(String::as_str))
+                    .collect();
+                send_mmi_answer(clients_connection, envelope, service_name, &answer);
+            }
+            true
+        }
+        None => {
+            log::warn!("No parameter passed to mmi.versions, ignoring request");
+            false
+        }
+    }
+}
+
+///
+/// Answers with the statistics of the requested service as a JSON document (workers, requests
+/// in flight and queued, queued requests by priority), after a "200" status ("404" when the
+/// service is unknown)
+///
+fn handle_mmi_stats_request(
+    ctx: &MajordomoContext,
+    envelope: &[Vec<u8>],
+    service_name: &str,
+    clients_connection: &zmq::Socket,
+    payload: Vec<Vec<u8>>,
+) -> bool {
+    match payload.first() {
+        Some(service_to_search) => {
+            match ctx.service_stats(&String::from_utf8_lossy(service_to_search)) {
+                Some(stats) => send_mmi_answer(
+                    clients_connection,
+                    envelope,
+                    service_name,
+                    &["200", &stats.to_string()],
+                ),
+                None => send_mmi_answer(clients_connection, envelope, service_name, &["404"]),
+            }
+            true
+        }
+        None => {
+            log::warn!("No parameter passed to mmi.stats, ignoring request");
+            false
+        }
+    }
+}
+
+///
+/// Answers with the names of all services handled by local workers, one per frame
+///
+fn handle_mmi_discovery_request(
+    ctx: &MajordomoContext,
+    envelope: &[Vec<u8>],
+    service_name: &str,
+    clients_connection: &zmq::Socket,
+) -> bool {
+    let services = ctx.available_services();
+    send_mmi_answer(clients_connection, envelope, service_name, &services);
+    true
+}
@@ -0,0 +1,718 @@
+mod admin;
+mod binary_star;
+mod broker_connection;
+mod config;
+mod data_structures;
+mod dead_letters;
+mod dispatch;
+mod events;
+mod federation;
+mod handlers;
+mod hash_ring;
+mod majordomo_context;
+mod metrics_server;
+mod mmi_handler;
+mod rate_limit;
+mod shards;
+mod size_limits;
+mod stats;
+mod trace_export;
+mod worker_registry;
+
+use crate::errors::RustydomoError;
+use admin::AdminServer;
+use binary_star::BinaryStar;
+use data_structures::{ConnectionData, SocketType};
+use events::EventPublisher;
+use federation::Federation;
+use log::info;
+use majordomo_context::MajordomoContext;
+use metrics_server::MetricsServer;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use zmq::Context;
+
+pub use binary_star::{BinaryStarConfig, BinaryStarRole};
+pub use config::BrokerConfig;
+pub use dispatch::{DispatchStrategy, UnmatchedLabelsPolicy};
+pub use rate_limit::Limit;
+pub use size_limits::SizeLimits;
+pub use trace_export::OtlpFileExporter;
+
+/// Number of sockets always polled by the broker, optional connections come after them
+const FIXED_SOCKETS_COUNT: usize = 4;
+
+/// Longest time a broker run by `run_until` waits for messages before checking its timers
+const POLL_TIMEOUT: Duration = Duration::from_millis(400);
+
+/// Time given to the answers sent on shutdown to be delivered before the sockets are closed
+const SHUTDOWN_LINGER: Duration = Duration::from_secs(1);
+
+/// Status code and reason sent to clients whose request can not be handled because of a shutdown
+const SHUTDOWN_STATUS_CODE: &str = "503";
+const SHUTDOWN_REASON: &str = "Broker shutting down";
+
+/// Number given to the next broker, so that the `inproc://` endpoints of brokers sharing a
+/// context do not collide
+static NEXT_BROKER_ID: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Origin of the events of each entry of the poll list, after the fixed sockets
+///
+enum OptionalSocket {
+    Admin,
+    BinaryStar,
+    Peer(usize),
+}
+
+///
+/// Optional parts of the broker, none of them being used by the shards of a sharded broker
+///
+struct Components {
+    federation: Federation,
+    metrics_server: Option<MetricsServer>,
+    admin_server: Option<AdminServer>,
+    binary_star: Option<BinaryStar>,
+    event_publisher: Option<EventPublisher>,
+}
+
+///
+/// Publishes the events raised by the context, or just forgets them when no endpoint is configured
+///
+fn publish_events(ctx: &mut MajordomoContext, publisher: Option<&EventPublisher>) {
+    for event in ctx.take_events() {
+        if let Some(publisher) = publisher {
+            publisher
+                .publish(&event)
+                .unwrap_or_else(|err| log::error!("Failed to publish event : {}", err));
+        }
+    }
+}
+
+///
+/// Creates the state of the broker, as configured
+///
+fn configure_context(config: &BrokerConfig) -> Result<MajordomoContext, RustydomoError> {
+    let mut ctx = MajordomoContext::new();
+    ctx.set_default_dispatch_strategy(config.default_dispatch);
+    for (service_name, strategy) in &config.service_dispatch {
+        ctx.set_dispatch_strategy(service_name, *strategy);
+    }
+    for (service_name, limit) in &config.service_limits {
+        ctx.rate_limiter.set_service_limit(service_name, *limit);
+    }
+    for (identity, limit) in &config.client_limits {
+        ctx.rate_limiter.set_client_limit(identity, *limit);
+    }
+    if let Some(limit) = config.default_client_limit {
+        ctx.rate_limiter.set_default_client_limit(limit);
+    }
+    if let Some(service_name) = &config.fallback_service {
+        ctx.set_fallback_service(service_name);
+    }
+    ctx.set_unmatched_labels_policy(config.unmatched_labels_policy);
+    if let Some(threshold) = config.queue_high_water {
+        ctx.set_queue_high_water(threshold);
+    }
+    ctx.set_priority_aging(config.priority_aging);
+    ctx.set_worker_credit(config.worker_credit);
+    ctx.size_limits = config.size_limits;
+    ctx.dead_letters.set_capacity(config.dead_letters_capacity);
+    if let Some(path) = &config.dead_letter_file {
+        ctx.dead_letters.open_file(path)?;
+    }
+
+    Ok(ctx)
+}
+
+///
+/// Builds a broker, its options defaulting to the ones of the `broker` binary
+///
+/// ```no_run
+/// use domolib::broker::Broker;
+///
+/// let broker = Broker::builder()
+///     .with_clients_endpoint("inproc://clients")
+///     .with_workers_endpoint("inproc://workers")
+///     .with_context(zmq::Context::new())
+///     .build()
+///     .unwrap();
+/// broker.run().unwrap();
+/// ```
+///
+#[derive(Default)]
+pub struct BrokerBuilder {
+    config: BrokerConfig,
+    zmq_ctx: Option<Context>,
+}
+
+impl BrokerBuilder {
+    ///
+    /// Replaces every option by the given ones
+    ///
+    pub fn with_config(mut self, config: BrokerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    ///
+    /// Creates the sockets of the broker in the given context rather than in a new one, which is
+    /// needed for clients and workers to reach `inproc://` endpoints
+    ///
+    pub fn with_context(mut self, zmq_ctx: Context) -> Self {
+        self.zmq_ctx = Some(zmq_ctx);
+        self
+    }
+
+    pub fn with_clients_endpoint(mut self, endpoint: &str) -> Self {
+        self.config.clients_endpoint = endpoint.to_string();
+        self
+    }
+
+    pub fn with_workers_endpoint(mut self, endpoint: &str) -> Self {
+        self.config.workers_endpoint = endpoint.to_string();
+        self
+    }
+
+    ///
+    /// Sets the time given to in-flight requests to complete once a shutdown is requested
+    ///
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.grace_period = grace_period;
+        self
+    }
+
+    ///
+    /// Sets the dispatch strategy of the services without a specific one
+    ///
+    pub fn with_dispatch(mut self, strategy: DispatchStrategy) -> Self {
+        self.config.default_dispatch = strategy;
+        self
+    }
+
+    pub fn with_service_dispatch(mut self, service_name: &str, strategy: DispatchStrategy) -> Self {
+        self.config
+            .service_dispatch
+            .push((service_name.to_string(), strategy));
+        self
+    }
+
+    ///
+    /// Accepts admin commands on the given endpoint, which has no authentication and must be
+    /// local unless `with_admin_insecure` is called
+    ///
+    pub fn with_admin_endpoint(mut self, endpoint: &str) -> Self {
+        self.config.admin_endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    ///
+    /// Accepts admin commands on an endpoint other hosts can connect to
+    ///
+    pub fn with_admin_insecure(mut self) -> Self {
+        self.config.admin_insecure = true;
+        self
+    }
+
+    pub fn with_events_endpoint(mut self, endpoint: &str) -> Self {
+        self.config.events_endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    ///
+    /// Adds a peer broker requests are forwarded to when no local worker can handle them
+    ///
+    pub fn with_peer(mut self, endpoint: &str) -> Self {
+        self.config.peers.push(endpoint.to_string());
+        self
+    }
+
+    ///
+    /// Sets the number of requests a worker is sent before answering them, the others being
+    /// queued by the broker by priority
+    ///
+    pub fn with_worker_credit(mut self, credit: usize) -> Self {
+        self.config.worker_credit = credit;
+        self
+    }
+
+    ///
+    /// Sets how often peer brokers are asked which services they handle
+    ///
+    pub fn with_peer_refresh_interval(mut self, interval: Duration) -> Self {
+        self.config.peer_refresh_interval = interval;
+        self
+    }
+
+    ///
+    /// Splits the services between the given number of broker threads
+    ///
+    pub fn with_shards(mut self, count: usize) -> Self {
+        self.config.shards = count;
+        self
+    }
+
+    ///
+    /// Binds the sockets of the broker
+    ///
+    pub fn build(self) -> Result<Broker, RustydomoError> {
+        self.config.validate()?;
+        let zmq_ctx = self.zmq_ctx.unwrap_or_default();
+        let id = NEXT_BROKER_ID.fetch_add(1, Ordering::Relaxed);
+        let config = self.config;
+
+        info!("Creating clients related connection...");
+        let clients_connection = broker_connection::bind_router_connection(
+            &zmq_ctx,
+            &config.clients_endpoint,
+            &format!("inproc://monitor_clients_router-{id}"),
+            config.max_message_size,
+        )?;
+        // answers to disconnected clients fail instead of being silently dropped, so that they
+        // are kept as dead letters
+        clients_connection
+            .connection
+            .set_router_mandatory(true)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+
+        info!("Creating services related connection...");
+        let workers_connection = broker_connection::bind_router_connection(
+            &zmq_ctx,
+            &config.workers_endpoint,
+            &format!("inproc://monitor_services_router-{id}"),
+            config.max_message_size,
+        )?;
+
+        let components = Components {
+            federation: Federation::new(&zmq_ctx, &config.peers, config.peer_refresh_interval)?,
+            metrics_server: config.metrics_port.map(MetricsServer::bind).transpose()?,
+            binary_star: config
+                .binary_star
+                .as_ref()
+                .map(|bstar_config| BinaryStar::new(&zmq_ctx, bstar_config))
+                .transpose()?,
+            admin_server: config
+                .admin_endpoint
+                .as_ref()
+                .map(|endpoint| AdminServer::bind(&zmq_ctx, endpoint))
+                .transpose()?,
+            event_publisher: config
+                .events_endpoint
+                .as_ref()
+                .map(|endpoint| EventPublisher::bind(&zmq_ctx, endpoint))
+                .transpose()?,
+        };
+
+        Broker::assemble(
+            id,
+            config,
+            zmq_ctx,
+            clients_connection,
+            workers_connection,
+            components,
+        )
+    }
+}
+
+///
+/// Majordomo broker, routing the requests of clients to workers
+///
+/// It is either run until a shutdown is requested, or driven one step at a time with
+/// `poll_once`, e.g. from the loop of a larger program.
+///
+pub struct Broker {
+    ctx: MajordomoContext,
+    components: Components,
+    clients_connection: ConnectionData,
+    workers_connection: ConnectionData,
+    zmq_ctx: Context,
+    config: BrokerConfig,
+    /// Number telling the `inproc://` endpoints of this broker apart from the ones of others
+    id: usize,
+    /// Set once a shutdown is requested, in-flight requests being given until then to complete
+    shutdown_deadline: Option<Instant>,
+}
+
+impl Broker {
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder::default()
+    }
+
+    fn assemble(
+        id: usize,
+        config: BrokerConfig,
+        zmq_ctx: Context,
+        clients_connection: ConnectionData,
+        workers_connection: ConnectionData,
+        components: Components,
+    ) -> Result<Self, RustydomoError> {
+        Ok(Broker {
+            ctx: configure_context(&config)?,
+            components,
+            clients_connection,
+            workers_connection,
+            zmq_ctx,
+            config,
+            id,
+            shutdown_deadline: None,
+        })
+    }
+
+    ///
+    /// Runs the broker until SIGINT or SIGTERM is received and in-flight requests are completed
+    /// or the grace period is over
+    ///
+    pub fn run(self) -> Result<(), RustydomoError> {
+        let shutdown_requested = Arc::new(AtomicBool::new(false));
+        for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+            signal_hook::flag::register(signal, Arc::clone(&shutdown_requested))
+                .map_err(|err| RustydomoError::Unknown(err.to_string()))?;
+        }
+        self.run_until(&shutdown_requested)
+    }
+
+    ///
+    /// Runs the broker until the given flag is set and in-flight requests are completed or the
+    /// grace period is over, then disconnects the workers and closes the sockets
+    ///
+    pub fn run_until(mut self, shutdown_requested: &AtomicBool) -> Result<(), RustydomoError> {
+        if self.config.shards > 1 {
+            shards::serve_sharded(
+                &self.zmq_ctx,
+                &self.config,
+                self.id,
+                &self.clients_connection,
+                &self.workers_connection,
+                shutdown_requested,
+            )?;
+        } else {
+            self.serve_until(shutdown_requested)?;
+        }
+        self.close();
+        Ok(())
+    }
+
+    ///
+    /// Runs the broker loop until the given flag is set and in-flight requests are completed or
+    /// the grace period is over, then disconnects the workers
+    ///
+    fn serve_until(&mut self, shutdown_requested: &AtomicBool) -> Result<(), RustydomoError> {
+        loop {
+            if self.shutdown_deadline.is_none() && shutdown_requested.load(Ordering::Relaxed) {
+                self.begin_shutdown();
+            }
+            if let Some(deadline) = self.shutdown_deadline {
+                if !(self.ctx.has_in_flight_requests() || self.ctx.has_queued_requests())
+                    || Instant::now() >= deadline
+                {
+                    break;
+                }
+            }
+            self.poll_once(POLL_TIMEOUT)?;
+        }
+        self.complete_shutdown();
+        Ok(())
+    }
+
+    ///
+    /// Stops accepting client requests, the queued ones no worker is going to take being
+    /// rejected, the others waiting for a worker with some credit left
+    ///
+    fn begin_shutdown(&mut self) {
+        info!(
+            "Shutdown requested, waiting up to {:?} for in-flight requests",
+            self.config.grace_period
+        );
+        self.shutdown_deadline = Some(Instant::now() + self.config.grace_period);
+        for (service_name, envelope) in self.ctx.take_queued_requests(false) {
+            self.ctx.record_rejection(
+                &service_name,
+                &envelope,
+                SHUTDOWN_STATUS_CODE,
+                SHUTDOWN_REASON,
+            );
+            handlers::send_client_error(
+                &self.clients_connection.connection,
+                &envelope,
+                &service_name,
+                SHUTDOWN_STATUS_CODE,
+                SHUTDOWN_REASON,
+            )
+            .unwrap_or_else(|err| log::error!("Failed to answer client : {}", err));
+        }
+    }
+
+    ///
+    /// Tells the clients of the requests still queued or in flight that they will not be
+    /// answered, then disconnects the workers
+    ///
+    fn complete_shutdown(&mut self) {
+        let mut requests = self.ctx.take_queued_requests(true);
+        requests.extend(self.ctx.take_in_flight_requests());
+        for (service_name, envelope) in requests {
+            self.ctx.record_rejection(
+                &service_name,
+                &envelope,
+                SHUTDOWN_STATUS_CODE,
+                SHUTDOWN_REASON,
+            );
+            handlers::send_client_error(
+                &self.clients_connection.connection,
+                &envelope,
+                &service_name,
+                SHUTDOWN_STATUS_CODE,
+                SHUTDOWN_REASON,
+            )
+            .unwrap_or_else(|err| log::error!("Failed to answer client : {}", err));
+        }
+        self.ctx
+            .disconnect_all_workers(&self.workers_connection.connection)
+            .unwrap_or_else(|err| log::error!("Failed to disconnect workers : {}", err));
+        publish_events(&mut self.ctx, self.components.event_publisher.as_ref());
+    }
+
+    ///
+    /// Closes the sockets, pending answers, DISCONNECT commands and events being given some time
+    /// to be delivered when the context is terminated
+    ///
+    fn close(self) {
+        for socket in [
+            &self.clients_connection.connection,
+            &self.workers_connection.connection,
+        ]
+        .into_iter()
+        .chain(
+            self.components
+                .event_publisher
+                .iter()
+                .map(|publisher| &publisher.connection),
+        ) {
+            socket
+                .set_linger(SHUTDOWN_LINGER.as_millis() as i32)
+                .unwrap_or_else(|err| log::error!("Failed to set socket linger : {}", err));
+        }
+        for socket in [
+            &self.clients_connection.monitor_connection,
+            &self.workers_connection.monitor_connection,
+        ]
+        .into_iter()
+        .chain(
+            self.components
+                .admin_server
+                .iter()
+                .map(|admin| &admin.connection),
+        ) {
+            socket.set_linger(0).ok();
+        }
+        drop(self);
+        info!("Broker stopped");
+    }
+
+    ///
+    /// Handles the messages received within the given time, at most one per socket, then runs
+    /// the periodic tasks of the broker (expiration of workers and requests, heartbeats...)
+    ///
+    /// A sharded broker can not be driven this way, its shards running in their own threads.
+    ///
+    pub fn poll_once(&mut self, timeout: Duration) -> Result<(), RustydomoError> {
+        if self.config.shards > 1 {
+            return Err(RustydomoError::ConfigurationError(
+                "a sharded broker can only be run by run() or run_until()".into(),
+            ));
+        }
+        let Broker {
+            ctx,
+            components,
+            clients_connection,
+            workers_connection,
+            shutdown_deadline,
+            ..
+        } = self;
+        // optional sockets are polled in this order : admin, Binary Star, peers
+        let optional_sockets: Vec<OptionalSocket> = components
+            .admin_server
+            .iter()
+            .map(|_| OptionalSocket::Admin)
+            .chain(
+                components
+                    .binary_star
+                    .iter()
+                    .map(|_| OptionalSocket::BinaryStar),
+            )
+            .chain((0..components.federation.peers.len()).map(OptionalSocket::Peer))
+            .collect();
+
+        let sockets_stimulated = {
+            let mut poll_list = vec![
+                clients_connection.connection.as_poll_item(zmq::POLLIN),
+                clients_connection
+                    .monitor_connection
+                    .as_poll_item(zmq::POLLIN),
+                workers_connection.connection.as_poll_item(zmq::POLLIN),
+                workers_connection
+                    .monitor_connection
+                    .as_poll_item(zmq::POLLIN),
+            ];
+            poll_list.extend(
+                components
+                    .admin_server
+                    .iter()
+                    .map(|admin| admin.connection.as_poll_item(zmq::POLLIN)),
+            );
+            poll_list.extend(
+                components
+                    .binary_star
+                    .iter()
+                    .map(|bstar| bstar.state_subscriber.as_poll_item(zmq::POLLIN)),
+            );
+            poll_list.extend(
+                components
+                    .federation
+                    .peers
+                    .iter()
+                    .map(|peer| peer.connection.as_poll_item(zmq::POLLIN)),
+            );
+
+            match zmq::poll(&mut poll_list, timeout.as_millis() as i64) {
+                // interrupted by the signal requesting the shutdown
+                Err(zmq::Error::EINTR) => Vec::new(),
+                result => {
+                    result.map_err(|err| RustydomoError::Unknown(err.to_string()))?;
+                    // if there are events on a connection, just save the socket index so that it
+                    // can be fetched afterwards
+                    poll_list
+                        .into_iter()
+                        .enumerate()
+                        .filter(|(_, entry)| entry.get_revents() & zmq::POLLIN == zmq::POLLIN)
+                        .map(|(idx, _)| idx)
+                        .collect::<Vec<usize>>()
+                }
+            }
+        };
+
+        for idx in sockets_stimulated {
+            if let Some(source) = idx
+                .checked_sub(FIXED_SOCKETS_COUNT)
+                .and_then(|optional_idx| optional_sockets.get(optional_idx))
+            {
+                match source {
+                    OptionalSocket::Admin => {
+                        if let Some(admin) = &components.admin_server {
+                            admin
+                                .handle_command(
+                                    ctx,
+                                    &workers_connection.connection,
+                                    &clients_connection.connection,
+                                )
+                                .unwrap_or_else(|err| {
+                                    log::error!("Failed to handle admin command : {}", err)
+                                });
+                        }
+                    }
+                    OptionalSocket::BinaryStar => {
+                        if let Some(bstar) = &mut components.binary_star {
+                            bstar.handle_peer_state()?;
+                        }
+                    }
+                    OptionalSocket::Peer(peer_idx) => handlers::handle_peer_messages(
+                        &mut components.federation.peers[*peer_idx],
+                        clients_connection,
+                        ctx,
+                    )
+                    .unwrap_or_else(|err| log::error!("Failed to handle peer message : {}", err)),
+                }
+                continue;
+            }
+
+            match SocketType::try_from(idx)? {
+                SocketType::ClientSocket if shutdown_deadline.is_some() => {
+                    handlers::reject_client_message(
+                        clients_connection,
+                        ctx,
+                        SHUTDOWN_STATUS_CODE,
+                        SHUTDOWN_REASON,
+                    )
+                    .unwrap_or_else(|err| log::error!("Failed to reject client message : {}", err))
+                }
+                SocketType::ClientSocket
+                    if components
+                        .binary_star
+                        .as_mut()
+                        .is_some_and(|bstar| !bstar.accept_client_request()) =>
+                {
+                    // clients have to send their requests to the active broker
+                    handlers::discard_message(&clients_connection.connection).unwrap_or_else(
+                        |err| log::error!("Failed to drop client message : {}", err),
+                    )
+                }
+                SocketType::ClientSocket => handlers::handle_client_messages(
+                    clients_connection,
+                    workers_connection,
+                    ctx,
+                    &components.federation,
+                )
+                .unwrap_or_else(|err| log::error!("Failed to handle client message : {}", err)),
+                SocketType::ClientMonitorSocket => {
+                    handlers::handle_client_monitor_messages(clients_connection)?
+                }
+                SocketType::ServiceSocket
+                    if components
+                        .binary_star
+                        .as_ref()
+                        .is_some_and(|bstar| !bstar.accepts_workers()) =>
+                {
+                    handlers::reject_worker_message(workers_connection, ctx).unwrap_or_else(|err| {
+                        log::error!("Failed to reject worker message : {}", err)
+                    })
+                }
+                SocketType::ServiceSocket => {
+                    handlers::handle_worker_messages(clients_connection, workers_connection, ctx)
+                        .unwrap_or_else(|err| {
+                            log::error!("Failed to handle worker message : {}", err)
+                        })
+                }
+                SocketType::WorkerMonitorSocket => {
+                    handlers::handle_worker_monitor_messages(workers_connection)?
+                }
+            }
+        }
+
+        ctx.check_expired_workers();
+        // workers that answered can take the queued requests, by priority
+        ctx.dispatch_queued_requests(&workers_connection.connection)
+            .unwrap_or_else(|err| log::error!("Failed to dispatch queued requests : {}", err));
+        // clients of the requests dropped by the broker (e.g. because of their deadline) are told
+        for request in ctx.take_rejected_requests() {
+            handlers::send_client_error(
+                &clients_connection.connection,
+                &request.envelope,
+                &request.service_name,
+                request.status_code,
+                request.reason,
+            )
+            .unwrap_or_else(|err| log::error!("Failed to answer client : {}", err));
+        }
+        ctx.complete_drains(&workers_connection.connection)
+            .unwrap_or_else(|err| log::error!("Failed to disconnect drained workers : {}", err));
+        ctx.send_heartbeat(&workers_connection.connection)
+            .unwrap_or_else(|err| log::error!("Failed to send heartbeats : {}", err));
+        components.federation.refresh_peers_services();
+        if let Some(server) = &mut components.metrics_server {
+            server.update(|| ctx.stats.render(&ctx.service_gauges()));
+        }
+
+        if let Some(bstar) = &mut components.binary_star {
+            bstar
+                .publish_state()
+                .unwrap_or_else(|err| log::error!("Failed to publish Binary Star state : {}", err));
+            // workers registered while this broker was allowed to have some must go to the peer
+            if !bstar.accepts_workers() {
+                ctx.disconnect_all_workers(&workers_connection.connection)
+                    .unwrap_or_else(|err| log::error!("Failed to disconnect workers : {}", err));
+            }
+        }
+        publish_events(ctx, components.event_publisher.as_ref());
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+use crate::errors::RustydomoError;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Number of client buckets above which the full ones are forgotten
+const MAX_TRACKED_CLIENT_BUCKETS: usize = 10_000;
+
+///
+/// Limits applied to the requests of a service or of a client
+///
+/// Parsed from a comma separated list of `rate=<REQUESTS PER SECOND>`, `burst=<REQUESTS>` and
+/// `concurrent=<REQUESTS>` entries (e.g. `rate=100,burst=20,concurrent=10`)
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limit {
+    /// Sustained number of requests accepted per second
+    pub rate: Option<f64>,
+    /// Number of requests accepted at once when the rate allowed them to accumulate (the rate by
+    /// default)
+    pub burst: Option<f64>,
+    /// Number of requests accepted and not answered yet
+    pub max_concurrent: Option<usize>,
+}
+
+impl FromStr for Limit {
+    type Err = RustydomoError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut limit = Limit::default();
+        for entry in value.split(',') {
+            let invalid = |reason: String| {
+                RustydomoError::ConfigurationError(format!("'{entry}' : {reason}"))
+            };
+            match entry.split_once('=') {
+                Some(("rate", rate)) => {
+                    limit.rate = Some(rate.parse().map_err(|err| invalid(format!("{err}")))?)
+                }
+                Some(("burst", burst)) => {
+                    limit.burst = Some(burst.parse().map_err(|err| invalid(format!("{err}")))?)
+                }
+                Some(("concurrent", max)) => {
+                    limit.max_concurrent =
+                        Some(max.parse().map_err(|err| invalid(format!("{err}")))?)
+                }
+                _ => return Err(invalid("expected rate, burst or concurrent".into())),
+            }
+        }
+        Ok(limit)
+    }
+}
+
+///
+/// Token bucket refilled at the limit rate, a request being accepted when a token is available
+///
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, now: Instant) -> Self {
+        TokenBucket {
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, capacity: f64, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.last_refill = now;
+    }
+}
+
+///
+/// Source of a limit, buckets being kept per source
+///
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum LimitKey {
+    Service(String),
+    Client(Vec<u8>),
+}
+
+///
+/// Rate limits and concurrency caps applied to client requests
+///
+#[derive(Default)]
+pub struct RateLimiter {
+    service_limits: HashMap<String, Limit>,
+    client_limits: HashMap<Vec<u8>, Limit>,
+    /// Limit applied to each client without a specific one
+    default_client_limit: Option<Limit>,
+    buckets: HashMap<LimitKey, TokenBucket>,
+}
+
+///
+/// Number of requests accepted and not answered yet, for the service and the client of a request
+///
+pub struct Outstanding {
+    pub service: usize,
+    pub client: usize,
+}
+
+impl RateLimiter {
+    pub fn set_service_limit(&mut self, service_name: &str, limit: Limit) {
+        self.service_limits.insert(service_name.to_string(), limit);
+    }
+
+    ///
+    /// Sets the limit of the client with the given identity (routing id)
+    ///
+    /// Identities are chosen by the clients themselves, so this limit only applies to clients
+    /// that cooperate : a client reconnecting with another identity gets a new bucket.
+    ///
+    pub fn set_client_limit(&mut self, identity: &[u8], limit: Limit) {
+        self.client_limits.insert(identity.to_vec(), limit);
+    }
+
+    pub fn set_default_client_limit(&mut self, limit: Limit) {
+        self.default_client_limit = Some(limit);
+    }
+
+    ///
+    /// Indicates whether or not a request is within the limits of its service and client,
+    /// consuming a token of their buckets when it is
+    ///
+    /// # Arguments
+    ///
+    /// * `service_name` - service the request is sent to
+    /// * `client_identity` - identity of the client sending the request
+    /// * `outstanding` - requests already accepted for the service and the client
+    ///
+    pub fn allow(
+        &mut self,
+        service_name: &str,
+        client_identity: &[u8],
+        outstanding: Outstanding,
+    ) -> bool {
+        self.allow_at(service_name, client_identity, outstanding, Instant::now())
+    }
+
+    fn allow_at(
+        &mut self,
+        service_name: &str,
+        client_identity: &[u8],
+        outstanding: Outstanding,
+        now: Instant,
+    ) -> bool {
+        let service_limit = self.service_limits.get(service_name).copied();
+        let client_limit = self
+            .client_limits
+            .get(client_identity)
+            .copied()
+            .or(self.default_client_limit);
+
+        let limits = [
+            service_limit.map(|limit| {
+                (
+                    LimitKey::Service(service_name.to_string()),
+                    limit,
+                    outstanding.service,
+                )
+            }),
+            client_limit.map(|limit| {
+                (
+                    LimitKey::Client(client_identity.to_vec()),
+                    limit,
+                    outstanding.client,
+                )
+            }),
+        ];
+
+        // check everything first so that no token is consumed for a rejected request
+        for (key, limit, outstanding) in limits.iter().flatten() {
+            if limit
+                .max_concurrent
+                .is_some_and(|max_concurrent| *outstanding >= max_concurrent)
+            {
+                return false;
+            }
+            if let Some(rate) = limit.rate {
+                let capacity = limit.burst.unwrap_or(rate).max(1.0);
+                let bucket = self
+                    .buckets
+                    .entry(key.clone())
+                    .or_insert_with(|| TokenBucket::new(capacity, now));
+                bucket.refill(rate, capacity, now);
+                if bucket.tokens < 1.0 {
+                    return false;
+                }
+            }
+        }
+        for (key, limit, _) in limits.iter().flatten() {
+            if limit.rate.is_some() {
+                if let Some(bucket) = self.buckets.get_mut(key) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+        }
+
+        self.forget_idle_clients(now);
+        true
+    }
+
+    ///
+    /// Forgets about the buckets of clients that did not send any request for a while, clients
+    /// identities being random for most of them
+    ///
+    fn forget_idle_clients(&mut self, now: Instant) {
+        if self.buckets.len() <= MAX_TRACKED_CLIENT_BUCKETS {
+            return;
+        }
+        let client_limits = &self.client_limits;
+        let default_client_limit = self.default_client_limit;
+        self.buckets.retain(|key, bucket| {
+            let limit = match key {
+                LimitKey::Client(identity) => client_limits
+                    .get(identity)
+                    .copied()
+                    .or(default_client_limit),
+                LimitKey::Service(_) => return true,
+            };
+            match limit {
+                Some(Limit {
+                    rate: Some(rate),
+                    burst,
+                    ..
+                }) => {
+                    let capacity = burst.unwrap_or(rate).max(1.0);
+                    bucket.refill(rate, capacity, now);
+                    // a full bucket is the same as a new one
+                    bucket.tokens < capacity
+                }
+                _ => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn idle() -> Outstanding {
+        Outstanding {
+            service: 0,
+            client: 0,
+        }
+    }
+
+    #[test]
+    fn limits_are_parsed() {
+        assert_eq!(
+            "rate=100,burst=20,concurrent=10".parse::<Limit>().unwrap(),
+            Limit {
+                rate: Some(100.0),
+                burst: Some(20.0),
+                max_concurrent: Some(10),
+            }
+        );
+        assert!("rate=fast".parse::<Limit>().is_err());
+        assert!("speed=1".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn buckets_are_refilled_at_the_limit_rate() {
+        let mut limiter = RateLimiter::default();
+        limiter.set_service_limit("echo", "rate=2,burst=2".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.allow_at("echo", b"client", idle(), start));
+        assert!(limiter.allow_at("echo", b"client", idle(), start));
+        assert!(!limiter.allow_at("echo", b"client", idle(), start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow_at("echo", b"client", idle(), later));
+        assert!(!limiter.allow_at("echo", b"client", idle(), later));
+
+        // tokens do not accumulate above the burst
+        let much_later = start + Duration::from_secs(60);
+        assert!(limiter.allow_at("echo", b"client", idle(), much_later));
+        assert!(limiter.allow_at("echo", b"client", idle(), much_later));
+        assert!(!limiter.allow_at("echo", b"client", idle(), much_later));
+    }
+
+    #[test]
+    fn rejected_requests_consume_no_token() {
+        let mut limiter = RateLimiter::default();
+        limiter.set_service_limit("echo", "rate=2".parse().unwrap());
+        limiter.set_client_limit(b"client", "rate=1".parse().unwrap());
+        let now = Instant::now();
+
+        assert!(limiter.allow_at("echo", b"client", idle(), now));
+        // rejected by the client limit, the service token is kept for another client
+        assert!(!limiter.allow_at("echo", b"client", idle(), now));
+        assert!(limiter.allow_at("echo", b"another", idle(), now));
+        assert!(!limiter.allow_at("echo", b"another", idle(), now));
+    }
+
+    #[test]
+    fn concurrent_requests_are_capped() {
+        let mut limiter = RateLimiter::default();
+        limiter.set_service_limit("echo", "concurrent=2".parse().unwrap());
+        limiter.set_default_client_limit("concurrent=1".parse().unwrap());
+        let now = Instant::now();
+
+        let outstanding = |service, client| Outstanding { service, client };
+        assert!(limiter.allow_at("echo", b"client", outstanding(1, 0), now));
+        assert!(!limiter.allow_at("echo", b"client", outstanding(2, 0), now));
+        assert!(!limiter.allow_at("echo", b"client", outstanding(0, 1), now));
+        assert!(limiter.allow_at("other", b"client", outstanding(5, 0), now));
+    }
+
+    #[test]
+    fn full_client_buckets_are_forgotten() {
+        let mut limiter = RateLimiter::default();
+        limiter.set_default_client_limit("rate=1".parse().unwrap());
+        let start = Instant::now();
+
+        // every bucket is empty, none of them can be forgotten
+        for client in 0..=MAX_TRACKED_CLIENT_BUCKETS {
+            assert!(limiter.allow_at("echo", &client.to_be_bytes(), idle(), start));
+        }
+        assert_eq!(limiter.buckets.len(), MAX_TRACKED_CLIENT_BUCKETS + 1);
+
+        // once refilled, they are the same as new ones
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.allow_at("echo", b"latest", idle(), later));
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(!limiter.allow_at("echo", b"latest", idle(), later));
+    }
+}
@@ -0,0 +1,830 @@
+use crate::broker::config::BrokerConfig;
+use crate::broker::data_structures::{ClientInteractionType, ConnectionData};
+use crate::broker::federation::{Federation, MMI_DISCOVERY_SERVICE};
+use crate::broker::handlers::{OVERLOADED_REASON, OVERLOADED_STATUS_CODE};
+use crate::broker::majordomo_context::EXPIRATION_TIME;
+use crate::broker::mmi_handler::is_mmi_service;
+use crate::broker::{handlers, hash_ring, Broker, Components};
+use crate::errors::RustydomoError;
+use crate::options::OPTIONS_SEPARATOR;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use zmq::{Context, Message, Socket, SocketType};
+
+static CLIENT_VERSION_HEADER: &[u8] = b"MDPC02";
+static WORKER_VERSION_HEADER: &[u8] = b"MDPW02";
+const WORKER_READY: u8 = 1;
+const WORKER_DISCONNECT: u8 = 6;
+
+/// Period the front checks whether the shards stopped, once a shutdown is requested
+const FRONT_POLL_TIMEOUT_MS: i64 = 100;
+
+/// Messages held by each end of the link handing requests over to a shard, requests for a shard
+/// whose link is full being rejected
+const LINK_HIGH_WATER_MARK: i32 = 1000;
+
+/// Time after which the front forgets the shard of a silent worker, longer than the time a shard
+/// waits before expiring a worker that just registered
+const WORKER_ROUTE_EXPIRATION: Duration = EXPIRATION_TIME.saturating_mul(5);
+
+///
+/// Returns the shard handling the given service
+///
+/// Services sharing the first segment of their base name (`images` for `images.resize@^1.2`) are
+/// handled by the same shard, so that wildcard patterns and versions match the requests as they
+/// do in a single broker
+///
+pub fn shard_of(service_name: &str, shards_count: usize) -> usize {
+    let base_name = service_name
+        .split([OPTIONS_SEPARATOR, '@'])
+        .next()
+        .unwrap_or_default();
+    let group = base_name.split('.').next().unwrap_or_default();
+    (hash_ring::hash(group.as_bytes()) % shards_count as u64) as usize
+}
+
+///
+/// Returns the position of the protocol header in a client message or in its answer, after the
+/// identity and the tags of the client
+///
+fn client_header(frames: &[Message]) -> Option<usize> {
+    frames
+        .iter()
+        .skip(1)
+        .position(|frame| &frame[..] == CLIENT_VERSION_HEADER)
+        .map(|pos| pos + 1)
+}
+
+///
+/// Tells whether the given client message is a `mmi.discovery` request, which every shard answers
+///
+fn is_discovery_request(frames: &[Message]) -> bool {
+    let Some(header) = client_header(frames) else {
+        return false;
+    };
+    let command = frames.get(header + 1).and_then(|frame| frame.first());
+    let service_name = frames
+        .get(header + 2)
+        .and_then(|frame| frame.as_str())
+        .and_then(|service_name| service_name.split(OPTIONS_SEPARATOR).next());
+    command == Some(&(ClientInteractionType::Request as u8))
+        && service_name == Some(MMI_DISCOVERY_SERVICE)
+}
+
+///
+/// Returns the ERROR answering the given client message, none for cancellations, which expect no
+/// answer, and for malformed messages
+///
+fn client_error(frames: &[Message], status_code: &str, reason: &str) -> Option<Vec<Message>> {
+    let header = client_header(frames)?;
+    let command = frames.get(header + 1)?.first().copied();
+    if command == Some(ClientInteractionType::Cancel as u8) {
+        return None;
+    }
+    let service_name = frames.get(header + 2)?;
+    let error_command = [ClientInteractionType::Error as u8];
+    Some(
+        frames[..header]
+            .iter()
+            .map(|frame| Message::from(&frame[..]))
+            .chain([
+                Message::from(CLIENT_VERSION_HEADER),
+                Message::from(&error_command[..]),
+                Message::from(&service_name[..]),
+                Message::from(status_code),
+                Message::from(reason),
+            ])
+            .collect(),
+    )
+}
+
+///
+/// Returns the shard a client message goes to : the shard of the requested service, or of the
+/// service given as first body frame for MMI requests
+///
+/// Malformed messages go to the first shard, which answers them as a single broker would
+///
+fn client_shard(frames: &[Message], shards_count: usize) -> usize {
+    let Some(header) = client_header(frames) else {
+        return 0;
+    };
+    let frame_str = |idx: usize| frames.get(idx).and_then(|frame| frame.as_str());
+    let Some(service_name) = frame_str(header + 2) else {
+        return 0;
+    };
+    let base_name = service_name
+        .split(OPTIONS_SEPARATOR)
+        .next()
+        .unwrap_or_default();
+    if is_mmi_service(base_name) {
+        return frame_str(header + 3)
+            .map(|queried_service| shard_of(queried_service, shards_count))
+            .unwrap_or(0);
+    }
+    shard_of(service_name, shards_count)
+}
+
+///
+/// Returns the worker command carried by the given frames (identity, "MDPW02", command...)
+///
+fn worker_command(frames: &[Message]) -> Option<u8> {
+    match frames {
+        [_, header, command, ..] if &header[..] == WORKER_VERSION_HEADER => {
+            command.first().copied()
+        }
+        _ => None,
+    }
+}
+
+fn receive_frames(socket: &Socket) -> Result<Vec<Message>, RustydomoError> {
+    let mut frames = Vec::new();
+    loop {
+        let frame = socket
+            .recv_msg(0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+        let more = frame.get_more();
+        frames.push(frame);
+        if !more {
+            return Ok(frames);
+        }
+    }
+}
+
+fn send_frames(socket: &Socket, frames: Vec<Message>, flags: i32) -> Result<(), zmq::Error> {
+    let count = frames.len();
+    for (idx, frame) in frames.into_iter().enumerate() {
+        let more = if idx + 1 < count { zmq::SNDMORE } else { 0 };
+        socket.send(frame, flags | more)?;
+    }
+    Ok(())
+}
+
+///
+/// Creates a socket linking the front to a shard, with the given high water marks
+///
+/// A link only has a limit when both the sending and the receiving ends have a non zero high
+/// water mark. Only requests are limited : answers of the shards are never delayed, and messages
+/// of the workers are bounded by the requests they were sent.
+///
+fn link_socket(
+    zmq_ctx: &Context,
+    send_high_water_mark: i32,
+    receive_high_water_mark: i32,
+) -> Result<Socket, RustydomoError> {
+    let socket = zmq_ctx
+        .socket(SocketType::PAIR)
+        .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+    socket
+        .set_sndhwm(send_high_water_mark)
+        .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+    socket
+        .set_rcvhwm(receive_high_water_mark)
+        .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+    Ok(socket)
+}
+
+///
+/// Tells whether a message can be handed over to a shard right away, which is not the case once
+/// its link is full or the shard stopped
+///
+fn can_send(socket: &Socket) -> Result<bool, RustydomoError> {
+    socket
+        .get_events()
+        .map(|events| events.contains(zmq::POLLOUT))
+        .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+}
+
+///
+/// Hands messages over to a shard, those sent once it stopped being dropped
+///
+fn send_to_shard(socket: &Socket, frames: Vec<Message>) -> Result<(), RustydomoError> {
+    match send_frames(socket, frames, zmq::DONTWAIT) {
+        Err(zmq::Error::EAGAIN) => {
+            log::debug!("Message dropped, its shard stopped");
+            Ok(())
+        }
+        result => result.map_err(|err| RustydomoError::CommunicationError(err.to_string())),
+    }
+}
+
+///
+/// Sockets linking the front to a shard, messages being forwarded as they are received
+///
+struct ShardLink {
+    clients: Socket,
+    workers: Socket,
+}
+
+///
+/// Shard a registered worker was sent to
+///
+struct WorkerRoute {
+    shard: usize,
+    last_seen: Instant,
+}
+
+///
+/// Shards the registered workers were sent to, by identity
+///
+/// Shards do not tell the front when they expire a worker : its route is forgotten once the
+/// worker stayed silent for longer than its shard waits before expiring it
+///
+struct WorkerRoutes {
+    routes: HashMap<Vec<u8>, WorkerRoute>,
+    last_pruning: Instant,
+}
+
+impl WorkerRoutes {
+    fn new(now: Instant) -> Self {
+        WorkerRoutes {
+            routes: HashMap::new(),
+            last_pruning: now,
+        }
+    }
+
+    ///
+    /// Routes the messages of the worker to the given shard, returns the shard it was routed to
+    /// before
+    ///
+    fn register(&mut self, identity: Vec<u8>, shard: usize, now: Instant) -> Option<usize> {
+        self.routes
+            .insert(
+                identity,
+                WorkerRoute {
+                    shard,
+                    last_seen: now,
+                },
+            )
+            .map(|route| route.shard)
+    }
+
+    ///
+    /// Returns the shard of the worker with the given identity, if still known
+    ///
+    fn shard(&mut self, identity: &[u8], now: Instant) -> Option<usize> {
+        let route = self.routes.get_mut(identity)?;
+        route.last_seen = now;
+        Some(route.shard)
+    }
+
+    ///
+    /// Forgets the route of the worker, returns the shard it was routed to
+    ///
+    fn forget(&mut self, identity: &[u8]) -> Option<usize> {
+        self.routes.remove(identity).map(|route| route.shard)
+    }
+
+    ///
+    /// Forgets the route of the worker, unless it was routed to another shard since
+    ///
+    fn forget_in(&mut self, identity: &[u8], shard: usize) {
+        if self
+            .routes
+            .get(identity)
+            .is_some_and(|route| route.shard == shard)
+        {
+            self.routes.remove(identity);
+        }
+    }
+
+    ///
+    /// Forgets the routes of the workers expired by their shard, checked at most once per
+    /// expiration period
+    ///
+    fn forget_expired(&mut self, now: Instant) {
+        if now.duration_since(self.last_pruning) < WORKER_ROUTE_EXPIRATION {
+            return;
+        }
+        self.last_pruning = now;
+        self.routes
+            .retain(|_, route| now.duration_since(route.last_seen) < WORKER_ROUTE_EXPIRATION);
+    }
+}
+
+///
+/// `mmi.discovery` request sent to every shard, answered once each of them answered
+///
+struct Discovery {
+    /// Identity and tags of the client
+    envelope: Vec<Vec<u8>>,
+    pending_answers: usize,
+    services: BTreeSet<Vec<u8>>,
+}
+
+///
+/// Routes the messages of the clients and workers to the shards and their answers back
+///
+struct Front<'a> {
+    clients_connection: &'a ConnectionData,
+    workers_connection: &'a ConnectionData,
+    links: Vec<ShardLink>,
+    worker_routes: WorkerRoutes,
+    /// Discovery requests being answered by the shards, by the identity they were sent with
+    discoveries: HashMap<Vec<u8>, Discovery>,
+    next_discovery: u64,
+}
+
+impl Front<'_> {
+    fn forward_client_message(&mut self) -> Result<(), RustydomoError> {
+        let frames = receive_frames(&self.clients_connection.connection)?;
+        if is_discovery_request(&frames) {
+            return self.forward_discovery_request(frames);
+        }
+        let shard = client_shard(&frames, self.links.len());
+        if !can_send(&self.links[shard].clients)? {
+            log::warn!("Shard {} is saturated or stopped, rejecting request", shard);
+            return self.reject_client_message(&frames);
+        }
+        send_frames(&self.links[shard].clients, frames, 0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+    }
+
+    ///
+    /// Sends a discovery request to every shard that can take it, on behalf of a new identity
+    /// telling their answers apart from the others
+    ///
+    fn forward_discovery_request(&mut self, frames: Vec<Message>) -> Result<(), RustydomoError> {
+        let identity = format!("front-discovery-{}", self.next_discovery).into_bytes();
+        self.next_discovery += 1;
+        let mut pending_answers = 0;
+        for link in &self.links {
+            if !can_send(&link.clients)? {
+                continue;
+            }
+            let request = std::iter::once(Message::from(identity.as_slice()))
+                .chain(frames[1..].iter().map(|frame| Message::from(&frame[..])))
+                .collect();
+            send_frames(&link.clients, request, 0)
+                .map_err(|err| RustydomoError::CommunicationError(err.to_string()))?;
+            pending_answers += 1;
+        }
+        if pending_answers == 0 {
+            log::warn!("Every shard is saturated or stopped, rejecting discovery request");
+            return self.reject_client_message(&frames);
+        }
+        let header = client_header(&frames).unwrap_or(1);
+        let discovery = Discovery {
+            envelope: frames[..header]
+                .iter()
+                .map(|frame| frame.to_vec())
+                .collect(),
+            pending_answers,
+            services: BTreeSet::new(),
+        };
+        self.discoveries.insert(identity, discovery);
+        Ok(())
+    }
+
+    ///
+    /// Adds the services of a shard to the answer of a discovery request, sent to the client
+    /// once every shard answered
+    ///
+    fn collect_discovery_answer(&mut self, frames: Vec<Message>) -> Result<(), RustydomoError> {
+        let identity = frames[0].to_vec();
+        let Some(discovery) = self.discoveries.get_mut(&identity) else {
+            return Ok(());
+        };
+        discovery.pending_answers -= 1;
+        if let Some(header) = client_header(&frames) {
+            // services follow the service name in FINAL answers, shards failing to answer
+            // (e.g. rate limited) adding none
+            let command = frames.get(header + 1).and_then(|frame| frame.first());
+            if command == Some(&(ClientInteractionType::Final as u8)) {
+                let services = frames.iter().skip(header + 3).map(|frame| frame.to_vec());
+                discovery.services.extend(services);
+            }
+        }
+        if discovery.pending_answers > 0 {
+            return Ok(());
+        }
+        let Some(discovery) = self.discoveries.remove(&identity) else {
+            return Ok(());
+        };
+        let final_command = [ClientInteractionType::Final as u8];
+        let answer = discovery
+            .envelope
+            .iter()
+            .map(|frame| Message::from(frame.as_slice()))
+            .chain([
+                Message::from(CLIENT_VERSION_HEADER),
+                Message::from(&final_command[..]),
+                Message::from(MMI_DISCOVERY_SERVICE),
+            ])
+            .chain(
+                discovery
+                    .services
+                    .iter()
+                    .map(|service| Message::from(service.as_slice())),
+            )
+            .collect();
+        self.send_to_client(answer)
+    }
+
+    ///
+    /// Answers a client message no shard can take with an ERROR
+    ///
+    fn reject_client_message(&self, frames: &[Message]) -> Result<(), RustydomoError> {
+        match client_error(frames, OVERLOADED_STATUS_CODE, OVERLOADED_REASON) {
+            Some(error) => self.send_to_client(error),
+            None => Ok(()),
+        }
+    }
+
+    fn send_to_client(&self, frames: Vec<Message>) -> Result<(), RustydomoError> {
+        match send_frames(&self.clients_connection.connection, frames, zmq::DONTWAIT) {
+            Err(zmq::Error::EHOSTUNREACH) | Err(zmq::Error::EAGAIN) => {
+                log::warn!("Answer dropped, its client is not connected anymore");
+                Ok(())
+            }
+            result => result.map_err(|err| RustydomoError::CommunicationError(err.to_string())),
+        }
+    }
+
+    fn forward_worker_message(&mut self) -> Result<(), RustydomoError> {
+        let frames = receive_frames(&self.workers_connection.connection)?;
+        let identity = frames[0].to_vec();
+        let now = Instant::now();
+        let shard = match worker_command(&frames) {
+            Some(WORKER_READY) => {
+                let shard = frames
+                    .get(3)
+                    .and_then(|frame| frame.as_str())
+                    .map(|service_name| shard_of(service_name, self.links.len()))
+                    .unwrap_or(0);
+                let previous_shard = self.worker_routes.register(identity.clone(), shard, now);
+                if let Some(previous_shard) = previous_shard.filter(|&previous| previous != shard) {
+                    // the worker registered for a service of another shard, which forgets it
+                    let disconnect = vec![
+                        Message::from(identity.as_slice()),
+                        Message::from(WORKER_VERSION_HEADER),
+                        Message::from(&[WORKER_DISCONNECT][..]),
+                    ];
+                    send_to_shard(&self.links[previous_shard].workers, disconnect)?;
+                }
+                shard
+            }
+            Some(WORKER_DISCONNECT) => self.worker_routes.forget(&identity).unwrap_or(0),
+            // unknown and expired workers are answered by the first shard, as a single broker
+            // would
+            _ => self.worker_routes.shard(&identity, now).unwrap_or(0),
+        };
+        send_to_shard(&self.links[shard].workers, frames)
+    }
+
+    fn forward_shard_answer(&mut self, shard: usize) -> Result<(), RustydomoError> {
+        let frames = receive_frames(&self.links[shard].clients)?;
+        if self.discoveries.contains_key(&frames[0][..]) {
+            return self.collect_discovery_answer(frames);
+        }
+        self.send_to_client(frames)
+    }
+
+    fn forward_shard_command(&mut self, shard: usize) -> Result<(), RustydomoError> {
+        let frames = receive_frames(&self.links[shard].workers)?;
+        if worker_command(&frames) == Some(WORKER_DISCONNECT) {
+            self.worker_routes.forget_in(&frames[0], shard);
+        }
+        send_frames(&self.workers_connection.connection, frames, 0)
+            .map_err(|err| RustydomoError::CommunicationError(err.to_string()))
+    }
+
+    ///
+    /// Forwards the messages available on the polled sockets, returns how many were forwarded
+    ///
+    fn forward_messages(&mut self, timeout_ms: i64) -> Result<usize, RustydomoError> {
+        let mut poll_list = vec![
+            self.clients_connection.connection.as_poll_item(zmq::POLLIN),
+            self.clients_connection
+                .monitor_connection
+                .as_poll_item(zmq::POLLIN),
+            self.workers_connection.connection.as_poll_item(zmq::POLLIN),
+            self.workers_connection
+                .monitor_connection
+                .as_poll_item(zmq::POLLIN),
+        ];
+        for link in &self.links {
+            poll_list.push(link.clients.as_poll_item(zmq::POLLIN));
+            poll_list.push(link.workers.as_poll_item(zmq::POLLIN));
+        }
+        match zmq::poll(&mut poll_list, timeout_ms) {
+            // interrupted by the signal requesting the shutdown
+            Err(zmq::Error::EINTR) => return Ok(0),
+            result => result.map_err(|err| RustydomoError::Unknown(err.to_string()))?,
+        };
+        let ready: Vec<usize> = poll_list
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.is_readable())
+            .map(|(idx, _)| idx)
+            .collect();
+
+        for &idx in &ready {
+            let result = match idx {
+                0 => self.forward_client_message(),
+                1 => handlers::handle_client_monitor_messages(self.clients_connection),
+                2 => self.forward_worker_message(),
+                3 => handlers::handle_worker_monitor_messages(self.workers_connection),
+                idx if idx % 2 == 0 => self.forward_shard_answer((idx - 4) / 2),
+                idx => self.forward_shard_command((idx - 4) / 2),
+            };
+            result.unwrap_or_else(|err| log::error!("Failed to forward message : {}", err));
+        }
+        self.worker_routes.forget_expired(Instant::now());
+        Ok(ready.len())
+    }
+}
+
+///
+/// Creates the connections of a shard to the front, the links of the front being already bound
+///
+/// Connecting before the front forwards anything, so that no message is taken for one sent to
+/// a stopped shard
+///
+fn shard_connections(
+    zmq_ctx: &Context,
+    broker_id: usize,
+    index: usize,
+) -> Result<(ConnectionData, ConnectionData), RustydomoError> {
+    let connect = |endpoint: &str, receive_high_water_mark: i32| {
+        let socket = link_socket(zmq_ctx, 0, receive_high_water_mark)?;
+        socket
+            .connect(endpoint)
+            .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
+        Ok::<Socket, RustydomoError>(socket)
+    };
+    // connections are monitored by the front, the monitor sockets of the shards are never
+    // connected
+    let unmonitored = || {
+        zmq_ctx
+            .socket(SocketType::PAIR)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))
+    };
+    let clients_connection = ConnectionData {
+        connection: connect(
+            &format!("inproc://broker-{broker_id}-shard-{index}-clients"),
+            LINK_HIGH_WATER_MARK,
+        )?,
+        monitor_connection: unmonitored()?,
+    };
+    let workers_connection = ConnectionData {
+        connection: connect(
+            &format!("inproc://broker-{broker_id}-shard-{index}-workers"),
+            0,
+        )?,
+        monitor_connection: unmonitored()?,
+    };
+    Ok((clients_connection, workers_connection))
+}
+
+///
+/// Runs a shard : a broker loop handling the services of the shard, whose clients and workers
+/// are reached through the front
+///
+fn run_shard(
+    zmq_ctx: &Context,
+    config: &BrokerConfig,
+    broker_id: usize,
+    index: usize,
+    (clients_connection, workers_connection): (ConnectionData, ConnectionData),
+    shutdown_requested: &AtomicBool,
+) -> Result<(), RustydomoError> {
+    let components = Components {
+        federation: Federation::new(zmq_ctx, &[], config.peer_refresh_interval)?,
+        metrics_server: None,
+        admin_server: None,
+        binary_star: None,
+        event_publisher: None,
+    };
+    let shard_config = BrokerConfig {
+        shards: 1,
+        ..config.clone()
+    };
+    let mut shard = Broker::assemble(
+        broker_id,
+        shard_config,
+        zmq_ctx.clone(),
+        clients_connection,
+        workers_connection,
+        components,
+    )?;
+
+    shard.serve_until(shutdown_requested)?;
+    log::info!("Shard {} stopped", index);
+    Ok(())
+}
+
+///
+/// Runs a broker split into shards, each running in its own thread and handling a subset of the
+/// services, until a shutdown is requested and every shard stopped
+///
+/// # Arguments
+///
+/// * `zmq_ctx` - context the connections of the front and of the shards are created in
+/// * `config` - configuration of the broker, applied to each shard
+/// * `broker_id` - number telling the `inproc://` endpoints of the broker apart
+/// * `clients_connection` - connection requests are received from and answers sent to
+/// * `workers_connection` - connection workers are reached through
+/// * `shutdown_requested` - set once the broker has to stop
+///
+pub fn serve_sharded(
+    zmq_ctx: &Context,
+    config: &BrokerConfig,
+    broker_id: usize,
+    clients_connection: &ConnectionData,
+    workers_connection: &ConnectionData,
+    shutdown_requested: &AtomicBool,
+) -> Result<(), RustydomoError> {
+    let bind = |endpoint: String, send_high_water_mark: i32| {
+        let socket = link_socket(zmq_ctx, send_high_water_mark, 0)?;
+        // messages still sent to the shards once they stopped are never read
+        socket
+            .set_linger(0)
+            .map_err(|err| RustydomoError::SocketCreationError(err.to_string()))?;
+        socket
+            .bind(&endpoint)
+            .map_err(|err| RustydomoError::SocketBindingError(err.to_string()))?;
+        Ok::<Socket, RustydomoError>(socket)
+    };
+    let links = (0..config.shards)
+        .map(|index| {
+            Ok(ShardLink {
+                clients: bind(
+                    format!("inproc://broker-{broker_id}-shard-{index}-clients"),
+                    LINK_HIGH_WATER_MARK,
+                )?,
+                workers: bind(
+                    format!("inproc://broker-{broker_id}-shard-{index}-workers"),
+                    0,
+                )?,
+            })
+        })
+        .collect::<Result<Vec<ShardLink>, RustydomoError>>()?;
+    let mut front = Front {
+        clients_connection,
+        workers_connection,
+        links,
+        worker_routes: WorkerRoutes::new(Instant::now()),
+        discoveries: HashMap::new(),
+        next_discovery: 0,
+    };
+    log::info!("Splitting services between {} shards", config.shards);
+
+    let connections = (0..config.shards)
+        .map(|index| shard_connections(zmq_ctx, broker_id, index))
+        .collect::<Result<Vec<_>, RustydomoError>>()?;
+
+    thread::scope(|scope| {
+        let shards = connections
+            .into_iter()
+            .enumerate()
+            .map(|(index, connections)| {
+                thread::Builder::new()
+                    .name(format!("shard-{index}"))
+                    .spawn_scoped(scope, move || {
+                        run_shard(
+                            zmq_ctx,
+                            config,
+                            broker_id,
+                            index,
+                            connections,
+                            shutdown_requested,
+                        )
+                    })
+                    .map_err(|err| {
+                        RustydomoError::Unknown(format!("Failed to start shard {index} : {err}"))
+                    })
+            })
+            .collect::<Result<Vec<_>, RustydomoError>>()
+            // the shards already started stop before the scope ends
+            .inspect_err(|_| shutdown_requested.store(true, Ordering::Relaxed))?;
+
+        // shards handle the shutdown themselves, the front forwards their last messages
+        while !shards.iter().all(|shard| shard.is_finished()) {
+            if shards.iter().any(|shard| shard.is_finished()) {
+                // a shard failed, the services it handles would not be handled anymore
+                shutdown_requested.store(true, Ordering::Relaxed);
+            }
+            front
+                .forward_messages(FRONT_POLL_TIMEOUT_MS)
+                .unwrap_or_else(|err| {
+                    log::error!("Failed to poll connections : {}", err);
+                    0
+                });
+        }
+        while front.forward_messages(0).unwrap_or_default() > 0 {}
+
+        shards
+            .into_iter()
+            .enumerate()
+            .try_for_each(|(index, shard)| {
+                shard.join().unwrap_or_else(|_| {
+                    Err(RustydomoError::Unknown(format!("Shard {index} panicked")))
+                })
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_links_take_a_bounded_number_of_messages() {
+        let zmq_ctx = Context::new();
+        let front = link_socket(&zmq_ctx, LINK_HIGH_WATER_MARK, 0).unwrap();
+        front.bind("inproc://saturated-shard").unwrap();
+        let shard = link_socket(&zmq_ctx, 0, LINK_HIGH_WATER_MARK).unwrap();
+        shard.connect("inproc://saturated-shard").unwrap();
+
+        let mut sent = 0;
+        while can_send(&front).unwrap() {
+            front.send("request", zmq::DONTWAIT).unwrap();
+            sent += 1;
+            assert!(sent <= 2 * LINK_HIGH_WATER_MARK, "Link never saturated");
+        }
+        assert!(sent >= LINK_HIGH_WATER_MARK);
+        assert_eq!(shard.recv_bytes(0).unwrap(), b"request");
+    }
+
+    #[test]
+    fn stopped_shards_take_no_message() {
+        let zmq_ctx = Context::new();
+        let front = link_socket(&zmq_ctx, LINK_HIGH_WATER_MARK, 0).unwrap();
+        front.bind("inproc://stopped-shard").unwrap();
+
+        assert!(!can_send(&front).unwrap());
+    }
+
+    #[test]
+    fn routes_of_silent_workers_are_forgotten() {
+        let start = Instant::now();
+        let mut routes = WorkerRoutes::new(start);
+        assert_eq!(routes.register(b"silent".to_vec(), 1, start), None);
+        assert_eq!(routes.register(b"active".to_vec(), 2, start), None);
+
+        let later = start + WORKER_ROUTE_EXPIRATION / 2;
+        assert_eq!(routes.shard(b"active", later), Some(2));
+        routes.forget_expired(start + WORKER_ROUTE_EXPIRATION);
+
+        assert_eq!(routes.shard(b"silent", later), None);
+        assert_eq!(routes.shard(b"active", later), Some(2));
+    }
+
+    #[test]
+    fn routes_of_workers_moved_to_another_shard_are_kept() {
+        let now = Instant::now();
+        let mut routes = WorkerRoutes::new(now);
+        routes.register(b"worker".to_vec(), 1, now);
+        assert_eq!(routes.register(b"worker".to_vec(), 3, now), Some(1));
+
+        routes.forget_in(b"worker", 1);
+        assert_eq!(routes.shard(b"worker", now), Some(3));
+        routes.forget_in(b"worker", 3);
+        assert_eq!(routes.shard(b"worker", now), None);
+    }
+
+    #[test]
+    fn overloaded_shards_answer_requests_but_not_cancellations() {
+        let request: Vec<Message> = [
+            &b"client"[..],
+            b"tag",
+            CLIENT_VERSION_HEADER,
+            &[ClientInteractionType::Request as u8],
+            b"echo",
+            b"body",
+        ]
+        .into_iter()
+        .map(Message::from)
+        .collect();
+        let error: Vec<Vec<u8>> = client_error(&request, "503", "Broker overloaded")
+            .unwrap()
+            .iter()
+            .map(|frame| frame.to_vec())
+            .collect();
+        assert_eq!(
+            error,
+            vec![
+                b"client".to_vec(),
+                b"tag".to_vec(),
+                CLIENT_VERSION_HEADER.to_vec(),
+                vec![ClientInteractionType::Error as u8],
+                b"echo".to_vec(),
+                b"503".to_vec(),
+                b"Broker overloaded".to_vec(),
+            ]
+        );
+
+        let cancel: Vec<Message> = [
+            &b"client"[..],
+            CLIENT_VERSION_HEADER,
+            &[ClientInteractionType::Cancel as u8],
+            b"echo",
+        ]
+        .into_iter()
+        .map(Message::from)
+        .collect();
+        assert!(client_error(&cancel, "503", "Broker overloaded").is_none());
+    }
+}
@@ -0,0 +1,43 @@
+///
+/// Limits on the size of the requests handled by the broker, none being set by default
+///
+/// The size of each frame is limited by ZeroMQ itself (`--max-message-size`), peers sending a
+/// larger frame being disconnected. These limits apply to whole requests and to the requests the
+/// broker keeps queued.
+///
+#[derive(Clone, Copy, Default)]
+pub struct SizeLimits {
+    /// Frames of the body of a request
+    pub request_frames: Option<usize>,
+    /// Bytes of the body of a request
+    pub request_bytes: Option<usize>,
+    /// Bytes of the bodies of the requests queued for a service
+    pub service_queued_bytes: Option<usize>,
+    /// Bytes of the bodies of all queued requests
+    pub queued_bytes: Option<usize>,
+}
+
+fn within(limit: Option<usize>, value: usize) -> bool {
+    limit.is_none_or(|limit| value <= limit)
+}
+
+impl SizeLimits {
+    ///
+    /// Indicates whether or not a request body of the given size is accepted
+    ///
+    pub fn request_fits(&self, frames: usize, bytes: usize) -> bool {
+        within(self.request_frames, frames) && within(self.request_bytes, bytes)
+    }
+
+    ///
+    /// Indicates whether or not a request can be queued, given the bytes queued once it is
+    ///
+    /// # Arguments
+    ///
+    /// * `service_bytes` - bytes queued for the service of the request, the request included
+    /// * `total_bytes` - bytes queued for all services, the request included
+    ///
+    pub fn queue_fits(&self, service_bytes: usize, total_bytes: usize) -> bool {
+        within(self.service_queued_bytes, service_bytes) && within(self.queued_bytes, total_bytes)
+    }
+}
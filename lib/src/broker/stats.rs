@@ -0,0 +1,310 @@
+use crate::broker::data_structures::parse_service_requirement;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Label of the statistics of the requests for services no worker registered
+const UNKNOWN_SERVICE_LABEL: &str = "unknown";
+
+/// Upper bounds (in seconds) of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+///
+/// Latency histogram, as exposed to Prometheus (cumulative buckets)
+///
+#[derive(Default)]
+struct LatencyHistogram {
+    /// Number of observations per bucket, the last entry being the +Inf bucket
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl LatencyHistogram {
+    fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let idx = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[idx] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+///
+/// Counters kept for each service
+///
+#[derive(Default)]
+struct ServiceStats {
+    requests: u64,
+    responses: u64,
+    errors: u64,
+    rate_limited: u64,
+    expired: u64,
+    too_large: u64,
+    overloaded: u64,
+    latency: LatencyHistogram,
+}
+
+///
+/// Gauges computed from the broker state when metrics are rendered
+///
+pub struct ServiceGauges<'a> {
+    pub service_name: &'a str,
+    /// Requests accepted for the service and still waiting for their FINAL answer
+    pub queue_depth: usize,
+    pub workers: usize,
+}
+
+///
+/// Statistics collected while the broker runs, exposed on the metrics endpoint
+///
+#[derive(Default)]
+pub struct BrokerStats {
+    services: HashMap<String, ServiceStats>,
+    heartbeat_expirations: u64,
+    malformed_frames: u64,
+}
+
+/// Name, description and accessor of a per service counter
+type ServiceCounter = (&'static str, &'static str, fn(&ServiceStats) -> u64);
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl BrokerStats {
+    ///
+    /// Keeps statistics for the given service, as registered by a worker, from now on
+    ///
+    /// Versions of a service share the statistics of its base name.
+    ///
+    pub fn register_service(&mut self, service_name: &str) {
+        let (base_name, _) = parse_service_requirement(service_name);
+        self.services.entry(base_name.to_string()).or_default();
+    }
+
+    ///
+    /// Returns the statistics of the given service, the ones of all the services no worker
+    /// registered being kept under a single label so that clients can not make them grow without
+    /// bound
+    ///
+    fn service(&mut self, service_name: &str) -> &mut ServiceStats {
+        let (base_name, _) = parse_service_requirement(service_name);
+        let key = if self.services.contains_key(base_name) {
+            base_name
+        } else {
+            UNKNOWN_SERVICE_LABEL
+        };
+        self.services.entry(key.to_string()).or_default()
+    }
+
+    pub fn record_request(&mut self, service_name: &str) {
+        self.service(service_name).requests += 1;
+    }
+
+    pub fn record_response(&mut self, service_name: &str, latency: Duration) {
+        let stats = self.service(service_name);
+        stats.responses += 1;
+        stats.latency.observe(latency);
+    }
+
+    pub fn record_error(&mut self, service_name: &str) {
+        self.service(service_name).errors += 1;
+    }
+
+    pub fn record_rate_limited(&mut self, service_name: &str) {
+        self.service(service_name).rate_limited += 1;
+    }
+
+    pub fn record_expired(&mut self, service_name: &str) {
+        self.service(service_name).expired += 1;
+    }
+
+    pub fn record_too_large(&mut self, service_name: &str) {
+        self.service(service_name).too_large += 1;
+    }
+
+    pub fn record_overloaded(&mut self, service_name: &str) {
+        self.service(service_name).overloaded += 1;
+    }
+
+    pub fn record_heartbeat_expiration(&mut self) {
+        self.heartbeat_expirations += 1;
+    }
+
+    pub fn record_malformed_frame(&mut self) {
+        self.malformed_frames += 1;
+    }
+
+    ///
+    /// Renders all metrics in the Prometheus text exposition format
+    ///
+    /// # Arguments
+    ///
+    /// * `gauges` - current state of every known service
+    ///
+    pub fn render(&self, gauges: &[ServiceGauges]) -> String {
+        let mut output = String::new();
+        let mut services: Vec<(&String, &ServiceStats)> = self.services.iter().collect();
+        services.sort_by(|left, right| left.0.cmp(right.0));
+
+        let counters: [ServiceCounter; 7] = [
+            (
+                "rustydomo_requests_total",
+                "Requests received from clients",
+                |stats| stats.requests,
+            ),
+            (
+                "rustydomo_responses_total",
+                "FINAL answers sent back to clients",
+                |stats| stats.responses,
+            ),
+            (
+                "rustydomo_errors_total",
+                "Requests that could not be answered",
+                |stats| stats.errors,
+            ),
+            (
+                "rustydomo_rate_limited_total",
+                "Requests rejected by rate limits or concurrency caps",
+                |stats| stats.rate_limited,
+            ),
+            (
+                "rustydomo_expired_total",
+                "Requests dropped because their deadline passed before dispatch",
+                |stats| stats.expired,
+            ),
+            (
+                "rustydomo_too_large_total",
+                "Requests rejected because of their number of frames or bytes",
+                |stats| stats.too_large,
+            ),
+            (
+                "rustydomo_overloaded_total",
+                "Requests rejected because too many bytes were queued",
+                |stats| stats.overloaded,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(output, "# HELP {name} {help}\n# TYPE {name} counter");
+            for (service_name, stats) in services.iter() {
+                let _ = writeln!(
+                    output,
+                    "{name}{{service=\"{}\"}} {}",
+                    escape_label(service_name),
+                    value(stats)
+                );
+            }
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP rustydomo_queue_depth Requests waiting for their FINAL answer\n\
+             # TYPE rustydomo_queue_depth gauge"
+        );
+        for entry in gauges {
+            let _ = writeln!(
+                output,
+                "rustydomo_queue_depth{{service=\"{}\"}} {}",
+                escape_label(entry.service_name),
+                entry.queue_depth
+            );
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP rustydomo_workers Registered workers\n# TYPE rustydomo_workers gauge"
+        );
+        for entry in gauges {
+            let _ = writeln!(
+                output,
+                "rustydomo_workers{{service=\"{}\"}} {}",
+                escape_label(entry.service_name),
+                entry.workers
+            );
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP rustydomo_heartbeat_expirations_total Workers removed after missing their heartbeats\n\
+             # TYPE rustydomo_heartbeat_expirations_total counter\n\
+             rustydomo_heartbeat_expirations_total {}",
+            self.heartbeat_expirations
+        );
+        let _ = writeln!(
+            output,
+            "# HELP rustydomo_malformed_frames_total Messages rejected because of unexpected frames\n\
+             # TYPE rustydomo_malformed_frames_total counter\n\
+             rustydomo_malformed_frames_total {}",
+            self.malformed_frames
+        );
+
+        let _ = writeln!(
+            output,
+            "# HELP rustydomo_request_duration_seconds Time between dispatch to a worker and its FINAL answer\n\
+             # TYPE rustydomo_request_duration_seconds histogram"
+        );
+        for (service_name, stats) in services.iter() {
+            let service_name = escape_label(service_name);
+            let mut cumulated = 0;
+            for (idx, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                cumulated += stats.latency.buckets[idx];
+                let _ = writeln!(
+                    output,
+                    "rustydomo_request_duration_seconds_bucket{{service=\"{service_name}\",le=\"{bound}\"}} {cumulated}"
+                );
+            }
+            let _ = writeln!(
+                output,
+                "rustydomo_request_duration_seconds_bucket{{service=\"{service_name}\",le=\"+Inf\"}} {}\n\
+                 rustydomo_request_duration_seconds_sum{{service=\"{service_name}\"}} {}\n\
+                 rustydomo_request_duration_seconds_count{{service=\"{service_name}\"}} {}",
+                stats.latency.count,
+                stats.latency.sum,
+                stats.latency.count
+            );
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unregistered_services_share_a_single_label() {
+        let mut stats = BrokerStats::default();
+        stats.register_service("echo");
+        for idx in 0..100 {
+            stats.record_request(&format!("random-{idx}"));
+        }
+        stats.record_request("echo");
+
+        assert_eq!(stats.services.len(), 2);
+        assert_eq!(stats.services[UNKNOWN_SERVICE_LABEL].requests, 100);
+        assert_eq!(stats.services["echo"].requests, 1);
+    }
+
+    #[test]
+    fn versions_share_the_label_of_their_base_name() {
+        let mut stats = BrokerStats::default();
+        stats.register_service("resize@1.4.2");
+        stats.record_request("resize");
+        stats.record_request("resize@^1.3");
+        stats.record_error("resize@1.4.2");
+
+        assert_eq!(stats.services.len(), 1);
+        assert_eq!(stats.services["resize"].requests, 2);
+        assert_eq!(stats.services["resize"].errors, 1);
+    }
+}
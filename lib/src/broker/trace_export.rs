@@ -0,0 +1,164 @@
+use crate::errors::RustydomoError;
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Name of the service the exported spans are attributed to
+const SERVICE_NAME: &str = "rustydomo-broker";
+
+///
+/// Span being recorded, kept in the span extensions until it is closed
+///
+struct SpanRecord {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    attributes: Vec<(String, String)>,
+}
+
+struct AttributesVisitor<'a>(&'a mut Vec<(String, String)>);
+
+impl Visit for AttributesVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .push((field.name().to_string(), format!("{:?}", value)));
+    }
+}
+
+fn random_id(bytes: usize) -> String {
+    (0..bytes)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+///
+/// Layer writing every closed span to a file, one OTLP/JSON `ExportTraceServiceRequest` per line
+///
+/// The file can be inspected offline, or loaded by any tool reading the OpenTelemetry file
+/// exporter format
+///
+pub struct OtlpFileExporter {
+    output: Mutex<LineWriter<File>>,
+}
+
+impl OtlpFileExporter {
+    pub fn create(path: &str) -> Result<Self, RustydomoError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| {
+                RustydomoError::ConfigurationError(format!("Can not open '{path}' : {err}"))
+            })?;
+        log::info!("Exporting request traces to '{}'", path);
+
+        Ok(OtlpFileExporter {
+            output: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    fn export(&self, name: &str, record: SpanRecord, end: SystemTime) {
+        let attributes: Vec<Value> = record
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+            .collect();
+        let mut span = json!({
+            "traceId": record.trace_id,
+            "spanId": record.span_id,
+            "name": name,
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(record.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": attributes,
+        });
+        if let Some(parent_span_id) = record.parent_span_id {
+            span["parentSpanId"] = json!(parent_span_id);
+        }
+        let request = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{"key": "service.name", "value": {"stringValue": SERVICE_NAME}}]
+                },
+                "scopeSpans": [{
+                    "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                    "spans": [span],
+                }],
+            }],
+        });
+
+        if let Ok(mut output) = self.output.lock() {
+            writeln!(output, "{}", request)
+                .unwrap_or_else(|err| log::error!("Failed to export span : {}", err));
+        }
+    }
+}
+
+impl<S> Layer<S> for OtlpFileExporter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        // spans of a request share the trace id of its root span
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanRecord>()
+                .map(|record| (record.trace_id.clone(), record.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (random_id(16), None),
+        };
+
+        let mut attributes = Vec::new();
+        attrs.record(&mut AttributesVisitor(&mut attributes));
+        span.extensions_mut().insert(SpanRecord {
+            trace_id,
+            span_id: random_id(8),
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(record) = span.extensions_mut().get_mut::<SpanRecord>() {
+                values.record(&mut AttributesVisitor(&mut record.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(record) = span.extensions_mut().remove::<SpanRecord>() {
+                self.export(span.name(), record, SystemTime::now());
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::broker::data_structures::Identity;
+use crate::broker::dispatch::WorkerStats;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt::{Display, Error, Formatter};
+use std::time::Instant;
+
+///
+/// Handle of a registered worker
+///
+/// Slots of the registry are reused once their worker is gone, the generation telling the
+/// handles of the previous workers apart so that they are never mistaken for the new one
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WorkerId {
+    index: usize,
+    generation: u64,
+}
+
+pub struct Worker {
+    pub service_name: String,
+    pub identity: Identity,
+    pub expiration_date: Instant,
+    /// Labels declared by the worker when registering
+    pub labels: BTreeMap<String, String>,
+    /// Statistics used to select the worker handling a request
+    pub stats: WorkerStats,
+    /// Deadline of the expiration timer of the worker, which may be earlier than its expiration
+    /// date when the worker was refreshed since the timer was set
+    scheduled: Instant,
+}
+
+impl Worker {
+    pub fn new(
+        service_name: &str,
+        identity: Identity,
+        expiration_date: Instant,
+        labels: BTreeMap<String, String>,
+        stats: WorkerStats,
+    ) -> Self {
+        Worker {
+            service_name: service_name.to_string(),
+            identity,
+            expiration_date,
+            labels,
+            stats,
+            scheduled: expiration_date,
+        }
+    }
+
+    ///
+    /// Indicates whether or not the worker declared all the given labels
+    ///
+    pub fn has_labels(&self, labels: &BTreeMap<String, String>) -> bool {
+        labels
+            .iter()
+            .all(|(key, value)| self.labels.get(key) == Some(value))
+    }
+}
+
+impl Display for Worker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{} : {:?}", &self.service_name, self.identity.value)?;
+        Ok(())
+    }
+}
+
+struct Slot {
+    generation: u64,
+    worker: Option<Worker>,
+}
+
+///
+/// Registered workers, looked up by handle or by identity in constant time
+///
+/// Expiration dates are kept in a binary heap holding one timer per worker. Refreshing a worker
+/// only updates its expiration date : its timer is set again to the new date when it goes off,
+/// unless the new date is earlier, a new timer being set right away in that case. Timers of the
+/// workers that are gone are dropped when they go off.
+///
+#[derive(Default)]
+pub struct WorkerRegistry {
+    slots: Vec<Slot>,
+    /// Slots whose worker is gone, reused first
+    free_slots: Vec<usize>,
+    identities: HashMap<Identity, WorkerId>,
+    timers: BinaryHeap<Reverse<(Instant, WorkerId)>>,
+}
+
+impl WorkerRegistry {
+    ///
+    /// Registers a worker, whose identity must not be registered already
+    ///
+    pub fn insert(&mut self, worker: Worker) -> WorkerId {
+        let index = match self.free_slots.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    worker: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        let id = WorkerId {
+            index,
+            generation: slot.generation,
+        };
+        self.identities.insert(worker.identity.clone(), id);
+        self.timers.push(Reverse((worker.scheduled, id)));
+        slot.worker = Some(worker);
+        id
+    }
+
+    ///
+    /// Forgets a worker, its handle becoming invalid
+    ///
+    pub fn remove(&mut self, id: WorkerId) -> Option<Worker> {
+        let slot = self.slots.get_mut(id.index)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        let worker = slot.worker.take()?;
+        slot.generation += 1;
+        self.free_slots.push(id.index);
+        self.identities.remove(&worker.identity);
+        Some(worker)
+    }
+
+    pub fn id(&self, identity: &Identity) -> Option<WorkerId> {
+        self.identities.get(identity).copied()
+    }
+
+    pub fn get(&self, id: WorkerId) -> Option<&Worker> {
+        self.slots
+            .get(id.index)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.worker.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: WorkerId) -> Option<&mut Worker> {
+        self.slots
+            .get_mut(id.index)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.worker.as_mut())
+    }
+
+    ///
+    /// Sets the expiration date of the worker with the given identity, if registered
+    ///
+    pub fn refresh(&mut self, identity: &Identity, expiration_date: Instant) {
+        let Some(id) = self.id(identity) else {
+            return;
+        };
+        let Some(worker) = self.get_mut(id) else {
+            return;
+        };
+        worker.expiration_date = expiration_date;
+        if expiration_date < worker.scheduled {
+            worker.scheduled = expiration_date;
+            self.timers.push(Reverse((expiration_date, id)));
+        }
+    }
+
+    ///
+    /// Removes and returns the next worker whose expiration date passed, if any
+    ///
+    pub fn pop_expired(&mut self, now: Instant) -> Option<(WorkerId, Worker)> {
+        while let Some(Reverse((deadline, id))) = self.timers.peek().copied() {
+            if deadline > now {
+                return None;
+            }
+            self.timers.pop();
+            let Some(worker) = self.get_mut(id) else {
+                // the worker is gone
+                continue;
+            };
+            if worker.scheduled != deadline {
+                // an earlier timer replaced this one
+                continue;
+            }
+            if worker.expiration_date <= now {
+                return self.remove(id).map(|worker| (id, worker));
+            }
+            // the worker was refreshed since the timer was set
+            worker.scheduled = worker.expiration_date;
+            let expiration_date = worker.expiration_date;
+            self.timers.push(Reverse((expiration_date, id)));
+        }
+        None
+    }
+
+    pub fn ids(&self) -> Vec<WorkerId> {
+        self.iter().map(|(id, _)| id).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (WorkerId, &Worker)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.worker.as_ref().map(|worker| {
+                (
+                    WorkerId {
+                        index,
+                        generation: slot.generation,
+                    },
+                    worker,
+                )
+            })
+        })
+    }
+}
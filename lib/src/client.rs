@@ -1,13 +1,28 @@
 use crate::errors::ClientError;
+use crate::options::RequestOptions;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 use zmq::SocketType;
 
 const EXPECTED_CLIENT_VERSION_HEADER: &str = "MDPC02";
 
+/// Time to wait for an answer before sending a request to the next broker, when several brokers
+/// are known
+const DEFAULT_FAILOVER_TIMEOUT: Duration = Duration::from_millis(2500);
+/// Number of times a request is sent again to another broker before giving up
+const DEFAULT_MAX_RETRIES: usize = 3;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ClientRequestState {
     REQUEST = 1,
     PARTIAL = 2,
     FINAL = 3,
+    /// The broker could not handle the request, the payload holding the service name, a status
+    /// code and the reason
+    ERROR = 4,
+    /// Sent to abandon a request, the payload holding the service name and the request ID
+    CANCEL = 5,
 }
 
 pub struct ClientRequestResult {
@@ -15,102 +30,470 @@ pub struct ClientRequestResult {
     pub payload: Vec<Vec<u8>>,
 }
 
+///
+/// Client sending requests to a broker, possibly from several threads at once
+///
+/// The socket and the mailboxes are guarded by mutexes, the socket being locked while answers are
+/// read and the mailboxes while they are filled or emptied, so the socket is always locked first.
+///
 pub struct Client {
-    client_connection: Option<zmq::Socket>,
+    connection: Mutex<BrokerConnection>,
+    /// Brokers the client can send its requests to (e.g. both brokers of a Binary Star pair)
+    broker_endpoints: Vec<String>,
+    failover_timeout: Duration,
+    max_retries: usize,
+    /// Answers received for each ongoing request, by request tag, until read through its handle
+    mailboxes: Mutex<HashMap<Vec<u8>, VecDeque<ClientRequestResult>>>,
+}
+
+///
+/// Socket of a client, and the broker it is connected to
+///
+struct BrokerConnection {
+    socket: zmq::Socket,
+    /// Index of the broker currently connected to
+    current_endpoint: usize,
+    /// Number of failovers so far
+    failovers: usize,
 }
 
+///
+/// Handle of a request sent by a `Client`, iterating over its answers
+///
+/// Several requests can be ongoing at the same time on one client : each request is sent with a
+/// tag (its request ID) as envelope frame, which the broker sends back with every answer so that
+/// answers are delivered to the right handle.
+///
 pub struct ClientRequest<'a> {
     client: &'a Client,
     request_ongoing: bool,
+    service_name: String,
+    payload: Vec<Vec<u8>>,
+    options: RequestOptions,
+    /// Last time the request was sent or an answer was received for it
+    last_activity: Instant,
+    retries_left: usize,
+    /// Number of client failovers when the request was last sent
+    sent_after_failovers: usize,
 }
 
 impl Client {
     pub fn new(broker_connection_string: &str) -> Result<Self, ClientError> {
-        let ctx = zmq::Context::new();
-        let result = Client {
-            client_connection: Some(ctx.socket(SocketType::DEALER).unwrap()),
-        };
+        Client::with_endpoints(&[broker_connection_string])
+    }
 
-        match &result.client_connection {
-            Some(connection) => {
-                connection.connect(&broker_connection_string).unwrap();
-            }
-            _ => {
-                log::error!("Failed to create connection to the broker");
-            }
+    ///
+    /// Creates a client able to fail over between several brokers
+    ///
+    /// The client connects to the first endpoint. When more than one endpoint is given, a request
+    /// that is not answered within the failover timeout is sent again to the next broker of the
+    /// list.
+    ///
+    /// # Arguments
+    ///
+    /// * `broker_connection_strings` - endpoints of the brokers clients connect to
+    ///
+    pub fn with_endpoints(broker_connection_strings: &[&str]) -> Result<Self, ClientError> {
+        Client::with_context(&zmq::Context::new(), broker_connection_strings)
+    }
+
+    ///
+    /// Creates a client whose socket belongs to the given context, so that it can reach brokers
+    /// bound to `inproc://` endpoints of the same context
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - context the socket of the client is created in
+    /// * `broker_connection_strings` - endpoints of the brokers clients connect to
+    ///
+    pub fn with_context(
+        ctx: &zmq::Context,
+        broker_connection_strings: &[&str],
+    ) -> Result<Self, ClientError> {
+        if broker_connection_strings.is_empty() {
+            return Err(ClientError::InitializationError(
+                "No broker endpoint given".into(),
+            ));
         }
 
-        return Ok(result);
+        let socket = ctx
+            .socket(SocketType::DEALER)
+            .map_err(|err| ClientError::InitializationError(err.to_string()))?;
+        socket
+            .connect(broker_connection_strings[0])
+            .map_err(|err| ClientError::InitializationError(err.to_string()))?;
+
+        Ok(Client {
+            connection: Mutex::new(BrokerConnection {
+                socket,
+                current_endpoint: 0,
+                failovers: 0,
+            }),
+            broker_endpoints: broker_connection_strings
+                .iter()
+                .map(|endpoint| endpoint.to_string())
+                .collect(),
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            mailboxes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, BrokerConnection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn mailboxes(&self) -> MutexGuard<'_, HashMap<Vec<u8>, VecDeque<ClientRequestResult>>> {
+        self.mailboxes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    ///
+    /// Sets the identity (routing id) the client is known with by the broker, so that limits
+    /// configured for this identity apply to its requests
+    ///
+    /// The client is reconnected to its broker for the new identity to be used
+    ///
+    pub fn set_identity(&self, identity: &[u8]) -> Result<(), ClientError> {
+        let connection = self.connection();
+        let endpoint = &self.broker_endpoints[connection.current_endpoint];
+        connection
+            .socket
+            .disconnect(endpoint)
+            .map_err(|err| ClientError::CommunicationError(err.to_string()))?;
+        connection
+            .socket
+            .set_identity(identity)
+            .map_err(|err| ClientError::InitializationError(err.to_string()))?;
+        connection
+            .socket
+            .connect(endpoint)
+            .map_err(|err| ClientError::CommunicationError(err.to_string()))
+    }
+
+    ///
+    /// Sets how long an answer is waited for before failing over to the next broker
+    ///
+    pub fn set_failover_timeout(&mut self, timeout: Duration) {
+        self.failover_timeout = timeout;
+    }
+
+    ///
+    /// Sets how many times a request is sent again to another broker before being abandoned
+    ///
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.max_retries = max_retries;
+    }
+
+    fn failover(&self, connection: &mut BrokerConnection) -> Result<(), ClientError> {
+        let current = connection.current_endpoint;
+        let next = (current + 1) % self.broker_endpoints.len();
+        log::warn!(
+            "No answer from broker '{}', failing over to '{}'",
+            self.broker_endpoints[current],
+            self.broker_endpoints[next]
+        );
+
+        connection
+            .socket
+            .disconnect(&self.broker_endpoints[current])
+            .unwrap_or_else(|err| log::debug!("Failed to disconnect from broker : {}", err));
+        connection.current_endpoint = next;
+        connection.failovers += 1;
+        connection
+            .socket
+            .connect(&self.broker_endpoints[next])
+            .map_err(|err| ClientError::CommunicationError(err.to_string()))
+    }
+
+    ///
+    /// Reads the answers received within the given timeout (in milliseconds), and stores them in
+    /// the mailbox of their request
+    ///
+    fn receive_answers(
+        &self,
+        connection: &BrokerConnection,
+        timeout: i64,
+    ) -> Result<(), ClientError> {
+        let mut timeout = timeout;
+        while connection
+            .socket
+            .poll(zmq::POLLIN, timeout)
+            .map_err(|err| ClientError::CommunicationError(err.to_string()))?
+            > 0
+        {
+            // only wait for the first answer, then read the ones already there
+            timeout = 0;
+            match receive_and_check_broker_response(&connection.socket)? {
+                Some((Some(tag), entry)) => match self.mailboxes().get_mut(&tag) {
+                    Some(mailbox) => mailbox.push_back(entry),
+                    None => log::debug!("Dropping answer to a finished request"),
+                },
+                Some((None, _)) => log::debug!("Dropping answer without request tag"),
+                None => (),
+            }
+        }
+        Ok(())
     }
 }
 
+fn send_request_frames(
+    connection: &zmq::Socket,
+    service_name: &str,
+    options: &RequestOptions,
+    payload: &[Vec<u8>],
+) -> Result<(), ClientError> {
+    let request_type: [u8; 1] = [ClientRequestState::REQUEST as u8];
+    let service_frame = options.to_service_frame(service_name);
+    // the request ID is used as request tag, sent back by the broker with every answer
+    let header: [&[u8]; 4] = [
+        options.request_id.as_deref().unwrap_or_default().as_bytes(),
+        EXPECTED_CLIENT_VERSION_HEADER.as_bytes(),
+        request_type.as_slice(),
+        service_frame.as_bytes(),
+    ];
+    // sent at once, so that the last frame is the one ending the message even without body
+    connection
+        .send_multipart(
+            header.into_iter().chain(payload.iter().map(Vec::as_slice)),
+            0,
+        )
+        .map_err(|err| ClientError::CommunicationError(err.to_string()))
+}
+
 impl Client {
     pub fn send_request(
         &self,
         service_name: &str,
-        payload: &Vec<Vec<u8>>,
-    ) -> Option<ClientRequest> {
-        let result = ClientRequest {
-            client: &self,
+        payload: &[Vec<u8>],
+    ) -> Option<ClientRequest<'_>> {
+        self.send_request_with_options(service_name, payload, &RequestOptions::default())
+    }
+
+    ///
+    /// Sends a request along with options such as its deadline
+    ///
+    /// Once the deadline passed, the broker drops the request if it was not dispatched yet and
+    /// the client stops waiting for its answer
+    ///
+    /// # Arguments
+    ///
+    /// * `service_name` - name of the service handling the request
+    /// * `payload` - frames of the request body
+    /// * `options` - options of the request
+    ///
+    pub fn send_request_with_options(
+        &self,
+        service_name: &str,
+        payload: &[Vec<u8>],
+        options: &RequestOptions,
+    ) -> Option<ClientRequest<'_>> {
+        // requests are identified so that answers can be told apart, and requests cancelled
+        let options = match options.request_id {
+            Some(_) => options.clone(),
+            None => options
+                .clone()
+                .with_request_id(&format!("{:016x}", rand::random::<u64>())),
+        };
+        let connection = self.connection();
+        let mut result = ClientRequest {
+            client: self,
             request_ongoing: true,
+            service_name: service_name.to_string(),
+            payload: payload.to_vec(),
+            options: options.clone(),
+            last_activity: Instant::now(),
+            retries_left: self.max_retries,
+            sent_after_failovers: connection.failovers,
         };
-        if let Some(connection) = &self.client_connection {
-            let request_type: [u8; 1] = [ClientRequestState::REQUEST as u8];
-            connection
-                .send(EXPECTED_CLIENT_VERSION_HEADER, zmq::SNDMORE)
-                .unwrap();
-            connection
-                .send(request_type.as_slice(), zmq::SNDMORE)
-                .unwrap();
-            connection.send(service_name, zmq::SNDMORE).unwrap();
-            connection.send_multipart(payload, 0).unwrap();
-            return Some(result);
+        self.mailboxes()
+            .insert(result.tag().to_vec(), VecDeque::new());
+        match send_request_frames(&connection.socket, service_name, &options, payload) {
+            Ok(()) => Some(result),
+            Err(err) => {
+                log::error!("Failed to send request : {:?}", err);
+                // nothing to cancel, the mailbox is removed when the handle is dropped
+                result.request_ongoing = false;
+                None
+            }
         }
-
-        None
     }
 }
 
-fn receive_and_check_broker_response(sock: &zmq::Socket) -> Option<ClientRequestResult> {
-    // we assume here that we have data waiting for us
-    let mut msg = zmq::Message::new();
+impl ClientRequest<'_> {
+    ///
+    /// Returns the identifier of the request, as known by the broker and the worker
+    ///
+    pub fn request_id(&self) -> &str {
+        self.options.request_id.as_deref().unwrap_or_default()
+    }
 
-    sock.recv(&mut msg, 0)
-        .expect("Failed to receive response header");
+    fn tag(&self) -> &[u8] {
+        self.request_id().as_bytes()
+    }
 
-    // ensure that we received the MDPC02 client header
+    ///
+    /// Returns the next answer if it was already received, without waiting for it
+    ///
+    /// Useful to follow many requests at once, iterating would wait for the answers of each
+    /// request in turn
+    ///
+    pub fn try_next(&mut self) -> Option<ClientRequestResult> {
+        if !self.request_ongoing {
+            return None;
+        }
+        self.receive_answers(0);
+        self.take_answer()
+    }
 
-    if let Some(obtained) = msg.as_str() {
-        match obtained {
-            EXPECTED_CLIENT_VERSION_HEADER => sock.recv(&mut msg, 0).unwrap(),
-            _ => {
-                log::error!("Unrecognized client version received");
-                return None;
-            }
+    ///
+    /// Reads the answers received within the given timeout (in milliseconds), the request being
+    /// abandoned when the connection fails
+    ///
+    fn receive_answers(&mut self, timeout: i64) {
+        let connection = self.client.connection();
+        if let Err(err) = self.client.receive_answers(&connection, timeout) {
+            log::error!(
+                "Failed to receive answers, abandoning request '{}' : {:?}",
+                self.request_id(),
+                err
+            );
+            self.request_ongoing = false;
+        }
+    }
+
+    ///
+    /// Takes the next answer from the mailbox of the request, if any
+    ///
+    fn take_answer(&mut self) -> Option<ClientRequestResult> {
+        let entry = self
+            .client
+            .mailboxes()
+            .get_mut(self.tag())
+            .and_then(VecDeque::pop_front)?;
+
+        self.last_activity = Instant::now();
+        // update request status based on returned state
+        if entry.state == ClientRequestState::FINAL || entry.state == ClientRequestState::ERROR {
+            // if it is the final answer (or an error), we consider this step the final one
+            log::debug!("End of the current loop");
+            self.request_ongoing = false;
         }
+        Some(entry)
     }
 
-    // now check the type of command (PARTIAL/FINAL)
-    // if they are found, just push the payload by receiving the rest of the frames
-    match msg.into_iter().nth(0) {
-        Some(&x) if x == ClientRequestState::PARTIAL as u8 => {
-            return Some(ClientRequestResult {
-                state: ClientRequestState::PARTIAL,
-                payload: sock.recv_multipart(0).unwrap(),
-            })
+    ///
+    /// Sends the request to the next broker, unless another request already failed over since
+    /// this one was sent
+    ///
+    fn send_again(&mut self) -> Result<(), ClientError> {
+        let mut connection = self.client.connection();
+        if self.sent_after_failovers == connection.failovers {
+            self.client.failover(&mut connection)?;
         }
-        Some(&x) if x == ClientRequestState::FINAL as u8 => {
-            return Some(ClientRequestResult {
-                state: ClientRequestState::FINAL,
-                payload: sock.recv_multipart(0).unwrap(),
-            })
+        self.sent_after_failovers = connection.failovers;
+        self.last_activity = Instant::now();
+        send_request_frames(
+            &connection.socket,
+            &self.service_name,
+            &self.options,
+            &self.payload,
+        )
+    }
+
+    ///
+    /// Abandons the request : the broker forgets it if it is still queued, or asks the worker
+    /// handling it to stop
+    ///
+    /// Requests dropped before their FINAL answer are cancelled automatically
+    ///
+    pub fn cancel(&mut self) {
+        if !self.request_ongoing {
+            return;
         }
+        self.request_ongoing = false;
+        log::debug!("Cancelling request '{}'", self.request_id());
+        let request_type: [u8; 1] = [ClientRequestState::CANCEL as u8];
+        self.client
+            .connection()
+            .socket
+            .send_multipart(
+                [
+                    self.tag(),
+                    EXPECTED_CLIENT_VERSION_HEADER.as_bytes(),
+                    request_type.as_slice(),
+                    self.service_name.as_bytes(),
+                    self.request_id().as_bytes(),
+                ],
+                0,
+            )
+            .unwrap_or_else(|err| log::error!("Failed to cancel request : {}", err));
+    }
+}
+
+impl Drop for ClientRequest<'_> {
+    fn drop(&mut self) {
+        self.cancel();
+        self.client.mailboxes().remove(self.tag());
+    }
+}
+
+/// Answer of the broker, along with the tag of the request it answers, if any
+type TaggedAnswer = (Option<Vec<u8>>, ClientRequestResult);
+
+///
+/// Reads an answer from the broker
+///
+/// Returns the request tag the answer was sent with, if any, and the answer itself, or nothing
+/// when the answer is malformed
+///
+fn receive_and_check_broker_response(
+    sock: &zmq::Socket,
+) -> Result<Option<TaggedAnswer>, ClientError> {
+    // we assume here that we have data waiting for us
+    let frames = sock
+        .recv_multipart(0)
+        .map_err(|err| ClientError::CommunicationError(err.to_string()))?;
+    Ok(check_broker_response(frames))
+}
+
+fn check_broker_response(frames: Vec<Vec<u8>>) -> Option<TaggedAnswer> {
+    let mut frames = frames.into_iter();
+
+    // ensure that we received the MDPC02 client header, optionally preceded by the request tag
+    let mut tag = None;
+    let mut header = frames.next()?;
+    if header.as_slice() != EXPECTED_CLIENT_VERSION_HEADER.as_bytes() {
+        tag = Some(header);
+        header = frames.next()?;
+    }
+    if header.as_slice() != EXPECTED_CLIENT_VERSION_HEADER.as_bytes() {
+        log::error!("Unrecognized client version received");
+        return None;
+    }
+
+    // now check the type of command (PARTIAL/FINAL/ERROR), the next frames being the payload
+    let command = frames.next()?;
+    let state = match command.first() {
+        Some(&x) if x == ClientRequestState::PARTIAL as u8 => ClientRequestState::PARTIAL,
+        Some(&x) if x == ClientRequestState::FINAL as u8 => ClientRequestState::FINAL,
+        Some(&x) if x == ClientRequestState::ERROR as u8 => ClientRequestState::ERROR,
         Some(state) => {
             log::error!("Unrecognized state : {}", state);
             return None;
         }
         _ => return None,
     };
+    Some((
+        tag,
+        ClientRequestResult {
+            state,
+            payload: frames.collect(),
+        },
+    ))
 }
 
 impl<'a> Iterator for ClientRequest<'a> {
@@ -122,43 +505,167 @@ impl<'a> Iterator for ClientRequest<'a> {
             return None;
         };
 
-        match &self.client.client_connection {
-            Some(connection) => loop {
-                let mut poll_list = [connection.as_poll_item(zmq::POLLIN)];
-
-                // time to poll events for all sockets
-                match zmq::poll(&mut poll_list, 100) {
-                    Ok(nbitemspolled) => {
-                        if nbitemspolled > 0 {
-                            // we only have one socket to monitor, no need to over engineer this
-                            let returned_state = receive_and_check_broker_response(&connection);
-                            match &returned_state {
-                                Some(entry) => {
-                                    // update request status based on returned state
-                                    if entry.state == ClientRequestState::FINAL {
-                                        // if it is the final answer, we consider this step the final
-                                        // one
-                                        log::debug!("End of the current loop");
-                                        self.request_ongoing = false;
-                                    }
-                                }
-                                None => (),
-                            }
-                            return returned_state;
-                        }
-
-                        // request is actually ongoing, just continue looping
-                        if self.request_ongoing {
-                            // nothing to do, just continue looping
-                            continue;
-                        }
-                    }
-                    Err(_) => (),
+        loop {
+            // answers may have been received while waiting for another request
+            if let Some(entry) = self.take_answer() {
+                return Some(entry);
+            }
+            self.receive_answers(100);
+            if let Some(entry) = self.take_answer() {
+                return Some(entry);
+            }
+            if !self.request_ongoing {
+                return None;
+            }
+
+            if self.options.remaining_budget() == Some(Duration::ZERO) {
+                log::warn!(
+                    "Deadline of request for service '{}' exceeded, abandoning it",
+                    self.service_name
+                );
+                self.request_ongoing = false;
+                return None;
+            }
+
+            // the broker may be gone, send the request to the next one if any
+            if self.client.broker_endpoints.len() > 1
+                && self.last_activity.elapsed() > self.client.failover_timeout
+            {
+                if self.retries_left == 0 {
+                    log::error!(
+                        "No broker answered request for service '{}', abandoning it",
+                        self.service_name
+                    );
+                    self.request_ongoing = false;
+                    return None;
                 }
-            },
-            None => (),
+                self.retries_left -= 1;
+                if let Err(err) = self.send_again() {
+                    log::error!(
+                        "Failed to send request for service '{}' again, abandoning it : {:?}",
+                        self.service_name,
+                        err
+                    );
+                    self.request_ongoing = false;
+                    return None;
+                }
+            }
         }
+    }
+}
 
-        return None;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_BROKER_ID: AtomicUsize = AtomicUsize::new(0);
+
+    ///
+    /// Creates a client connected to a ROUTER socket playing the broker
+    ///
+    fn client_and_broker() -> (Client, zmq::Socket) {
+        let ctx = zmq::Context::new();
+        let endpoint = format!(
+            "inproc://client-test-{}",
+            NEXT_BROKER_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let broker = ctx.socket(SocketType::ROUTER).unwrap();
+        broker.bind(&endpoint).unwrap();
+        let client = Client::with_context(&ctx, &[&endpoint]).unwrap();
+        (client, broker)
+    }
+
+    fn receive_request(broker: &zmq::Socket) -> Vec<Vec<u8>> {
+        assert!(broker.poll(zmq::POLLIN, 1000).unwrap() > 0, "No request");
+        broker.recv_multipart(0).unwrap()
+    }
+
+    ///
+    /// Sends a FINAL answer to the given request, whose body is sent back with a prefix
+    ///
+    fn answer(broker: &zmq::Socket, request: &[Vec<u8>], prefix: &str) {
+        let final_state = [ClientRequestState::FINAL as u8];
+        let body: Vec<Vec<u8>> = request[5..]
+            .iter()
+            .map(|frame| [prefix.as_bytes(), frame].concat())
+            .collect();
+        let header: [&[u8]; 4] = [
+            &request[0],
+            &request[1],
+            EXPECTED_CLIENT_VERSION_HEADER.as_bytes(),
+            &final_state,
+        ];
+        broker
+            .send_multipart(header.into_iter().chain(body.iter().map(Vec::as_slice)), 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn client_is_shareable_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Client>();
+        assert_send_sync::<ClientRequest<'_>>();
+    }
+
+    #[test]
+    fn answers_received_out_of_order_reach_their_request() {
+        let (client, broker) = client_and_broker();
+        let mut first = client.send_request("echo", &[b"first".to_vec()]).unwrap();
+        let mut second = client.send_request("echo", &[b"second".to_vec()]).unwrap();
+        let first_request = receive_request(&broker);
+        let second_request = receive_request(&broker);
+
+        answer(&broker, &second_request, "re:");
+        answer(&broker, &first_request, "re:");
+
+        let answer = first.next().unwrap();
+        assert_eq!(answer.state, ClientRequestState::FINAL);
+        assert_eq!(answer.payload, vec![b"re:first".to_vec()]);
+        assert!(first.next().is_none());
+        // read while waiting for the first answer
+        assert_eq!(
+            second.try_next().unwrap().payload,
+            vec![b"re:second".to_vec()]
+        );
+        assert!(second.next().is_none());
+    }
+
+    #[test]
+    fn requests_without_body_are_terminated() {
+        let (client, broker) = client_and_broker();
+        let _request = client.send_request("echo", &[]).unwrap();
+        let request = receive_request(&broker);
+        assert_eq!(request.len(), 5);
+        assert!(request[4].starts_with(b"echo?"));
+    }
+
+    #[test]
+    fn requests_are_sent_from_several_threads() {
+        let (client, broker) = client_and_broker();
+        const THREADS: usize = 4;
+        const REQUESTS: usize = 10;
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for _ in 0..THREADS * REQUESTS {
+                    let request = receive_request(&broker);
+                    answer(&broker, &request, "re:");
+                }
+            });
+            for thread in 0..THREADS {
+                let client = &client;
+                scope.spawn(move || {
+                    for idx in 0..REQUESTS {
+                        let body = format!("{thread}-{idx}").into_bytes();
+                        let mut request = client
+                            .send_request("echo", std::slice::from_ref(&body))
+                            .unwrap();
+                        let answer = request.next().unwrap();
+                        assert_eq!(answer.payload, vec![[b"re:".as_slice(), &body].concat()]);
+                    }
+                });
+            }
+        });
     }
 }
@@ -9,6 +9,7 @@ pub enum RustydomoError {
     UnrecognizedCommandType(u8),
     ServiceNotAvailable(String),
     ConversionError(String),
+    ConfigurationError(String),
     Unknown(String),
 }
 
@@ -37,6 +38,9 @@ impl fmt::Display for RustydomoError {
             Self::ConversionError(value) => {
                 write!(f, "Failed to during value conversion: {}", value)
             }
+            Self::ConfigurationError(value) => {
+                write!(f, "Invalid broker configuration : {}", value)
+            }
             Self::Unknown(value) => {
                 write!(f, "Unknown error occured : '{}'", value)
             }
@@ -1,4 +1,8 @@
+pub mod broker;
 pub mod client;
 pub mod errors;
+pub mod options;
 pub mod structures;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod worker;
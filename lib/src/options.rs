@@ -0,0 +1,406 @@
+use crate::errors::RustydomoError;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Separator between the service name and the request options in the service frame
+pub const OPTIONS_SEPARATOR: char = '?';
+
+/// Prefix of the options holding the labels selecting the worker handling a request
+const LABEL_PREFIX: &str = "label.";
+
+/// Priority of the requests that do not set any
+pub const DEFAULT_PRIORITY: u8 = 0;
+
+/// Highest priority a request can have
+pub const MAX_PRIORITY: u8 = 9;
+
+///
+/// Percent-encodes every byte of the given option key or value but alphanumeric characters, `-`,
+/// `_`, `.` and `~`
+///
+fn encode_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+///
+/// Decodes an option key or value encoded with `encode_component`
+///
+fn decode_component(value: &str) -> Result<String, RustydomoError> {
+    let invalid =
+        || RustydomoError::ConversionError(format!("Invalid option encoding : {}", value));
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [
+                bytes.next().ok_or_else(invalid)?,
+                bytes.next().ok_or_else(invalid)?,
+            ];
+            let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+///
+/// How the answers of the workers a request is broadcast to are gathered
+///
+/// Every worker answer is relayed to the client as a PARTIAL whose first frame is the hexadecimal
+/// identity of the worker, but the one meeting the policy which is relayed as a FINAL
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GatherPolicy {
+    /// Waits for the FINAL answers of all the workers
+    All,
+    /// Stops at the first FINAL answer
+    First,
+    /// Stops once the given number of workers sent their FINAL answer
+    Quorum(usize),
+}
+
+impl GatherPolicy {
+    ///
+    /// Returns the number of FINAL answers meeting the policy, for a request broadcast to the
+    /// given number of workers
+    ///
+    pub fn required_answers(&self, workers_count: usize) -> usize {
+        match self {
+            GatherPolicy::All => workers_count,
+            GatherPolicy::First => 1,
+            GatherPolicy::Quorum(quorum) => *quorum,
+        }
+    }
+}
+
+impl FromStr for GatherPolicy {
+    type Err = RustydomoError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "all" => Ok(GatherPolicy::All),
+            "first" => Ok(GatherPolicy::First),
+            quorum => match quorum.parse::<usize>() {
+                Ok(quorum) if quorum > 0 => Ok(GatherPolicy::Quorum(quorum)),
+                _ => Err(RustydomoError::ConversionError(format!(
+                    "Invalid gather policy : {}",
+                    value
+                ))),
+            },
+        }
+    }
+}
+
+impl Display for GatherPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GatherPolicy::All => write!(f, "all"),
+            GatherPolicy::First => write!(f, "first"),
+            GatherPolicy::Quorum(quorum) => write!(f, "{}", quorum),
+        }
+    }
+}
+
+///
+/// Options attached to a request, on top of the service name and body defined by MDP
+///
+/// Options travel as a query string appended to the service frame of the client request
+/// (`service?ttl_ms=1500`). The broker forwards them to the worker as an extra envelope frame
+/// starting with `?`, which it removes from the worker answers.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestOptions {
+    /// Time after which nobody waits for the answer anymore
+    pub deadline: Option<SystemTime>,
+    /// Identifier correlating the logs and traces of the client, the brokers and the worker
+    pub request_id: Option<String>,
+    /// Set by brokers forwarding the request to a peer broker, so that it is not forwarded again
+    pub forwarded: bool,
+    /// Labels the worker handling the request must have been registered with
+    pub labels: BTreeMap<String, String>,
+    /// Key (e.g. a user ID) whose requests are all sent to the same worker of the service
+    pub affinity_key: Option<String>,
+    /// Broadcasts the request to every worker of the service, gathering their answers
+    pub gather: Option<GatherPolicy>,
+    /// Priority of the request among those queued for its service, from 0 to `MAX_PRIORITY`
+    pub priority: Option<u8>,
+}
+
+impl RequestOptions {
+    ///
+    /// Sets the absolute time after which the request is not worth handling anymore
+    ///
+    pub fn with_deadline(mut self, deadline: SystemTime) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    ///
+    /// Sets how long the request is worth handling, from now on
+    ///
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.with_deadline(SystemTime::now() + ttl)
+    }
+
+    ///
+    /// Sets the identifier of the request, assigned by the broker when not set by the client
+    ///
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+
+    ///
+    /// Only sends the request to workers registered with the given label
+    ///
+    pub fn with_label(mut self, key: &str, value: &str) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    ///
+    /// Sends the request to the worker handling all the requests with the same affinity key, as
+    /// long as it is registered
+    ///
+    pub fn with_affinity_key(mut self, affinity_key: &str) -> Self {
+        self.affinity_key = Some(affinity_key.to_string());
+        self
+    }
+
+    ///
+    /// Broadcasts the request to every worker of the service instead of a single one, the
+    /// broker relaying their answers until the policy is met
+    ///
+    pub fn with_gather(mut self, policy: GatherPolicy) -> Self {
+        self.gather = Some(policy);
+        self
+    }
+
+    ///
+    /// Sets the priority of the request, from 0 (the default) to `MAX_PRIORITY` (the most urgent)
+    ///
+    /// Requests queued by the broker for a service are dispatched by priority, then in their
+    /// order of arrival
+    ///
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = Some(priority.min(MAX_PRIORITY));
+        self
+    }
+
+    ///
+    /// Returns the priority of the request
+    ///
+    pub fn priority(&self) -> u8 {
+        self.priority.unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    ///
+    /// Returns the time left before the deadline, if any (zero once it passed)
+    ///
+    pub fn remaining_budget(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == RequestOptions::default()
+    }
+
+    ///
+    /// Encodes the options as a query string (without the leading separator)
+    ///
+    /// Keys and values are percent-encoded, so that they can hold any character. The deadline is
+    /// sent as the time left before it, so that clocks do not have to be synchronized
+    ///
+    pub fn to_query(&self) -> String {
+        let mut entries: Vec<String> = Vec::new();
+        if let Some(budget) = self.remaining_budget() {
+            entries.push(format!("ttl_ms={}", budget.as_millis()));
+        }
+        if let Some(request_id) = &self.request_id {
+            entries.push(format!("request_id={}", encode_component(request_id)));
+        }
+        if self.forwarded {
+            entries.push("forwarded=1".into());
+        }
+        if let Some(affinity_key) = &self.affinity_key {
+            entries.push(format!("affinity={}", encode_component(affinity_key)));
+        }
+        if let Some(priority) = self.priority {
+            entries.push(format!("priority={}", priority));
+        }
+        if let Some(policy) = &self.gather {
+            entries.push(format!("gather={}", policy));
+        }
+        for (key, value) in &self.labels {
+            entries.push(format!(
+                "{}{}={}",
+                LABEL_PREFIX,
+                encode_component(key),
+                encode_component(value)
+            ));
+        }
+        entries.join("&")
+    }
+
+    ///
+    /// Decodes options encoded with `to_query`, unknown options being rejected
+    ///
+    pub fn from_query(query: &str) -> Result<Self, RustydomoError> {
+        let mut options = RequestOptions::default();
+        for entry in query.split('&').filter(|entry| !entry.is_empty()) {
+            let entry = match entry.split_once('=') {
+                Some((key, value)) => Some((decode_component(key)?, decode_component(value)?)),
+                None => None,
+            };
+            match entry
+                .as_ref()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+            {
+                Some(("ttl_ms", value)) => {
+                    let ttl = value.parse::<u64>().map_err(|err| {
+                        RustydomoError::ConversionError(format!("Invalid ttl_ms : {}", err))
+                    })?;
+                    options.deadline = Some(SystemTime::now() + Duration::from_millis(ttl));
+                }
+                Some(("request_id", value)) => options.request_id = Some(value.to_string()),
+                Some(("forwarded", value)) => options.forwarded = value == "1",
+                Some(("affinity", value)) => options.affinity_key = Some(value.to_string()),
+                Some(("gather", value)) => options.gather = Some(value.parse()?),
+                Some(("priority", value)) => match value.parse::<u8>() {
+                    Ok(priority) if priority <= MAX_PRIORITY => options.priority = Some(priority),
+                    _ => {
+                        return Err(RustydomoError::ConversionError(format!(
+                            "Invalid priority : {}",
+                            value
+                        )))
+                    }
+                },
+                Some((key, value)) if key.starts_with(LABEL_PREFIX) => {
+                    options
+                        .labels
+                        .insert(key[LABEL_PREFIX.len()..].to_string(), value.to_string());
+                }
+                Some((key, _)) => {
+                    return Err(RustydomoError::ConversionError(format!(
+                        "Unknown request option : {}",
+                        key
+                    )))
+                }
+                None => {
+                    return Err(RustydomoError::ConversionError(
+                        "Request option without value".into(),
+                    ))
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    ///
+    /// Builds the service frame of a client request
+    ///
+    pub fn to_service_frame(&self, service_name: &str) -> String {
+        if self.is_empty() {
+            service_name.to_string()
+        } else {
+            format!("{}{}{}", service_name, OPTIONS_SEPARATOR, self.to_query())
+        }
+    }
+
+    ///
+    /// Splits the service frame of a client request into the service name and its options
+    ///
+    pub fn from_service_frame(frame: &str) -> Result<(&str, Self), RustydomoError> {
+        match frame.split_once(OPTIONS_SEPARATOR) {
+            Some((service_name, query)) => Ok((service_name, RequestOptions::from_query(query)?)),
+            None => Ok((frame, RequestOptions::default())),
+        }
+    }
+
+    ///
+    /// Builds the envelope frame forwarding the options to a worker
+    ///
+    pub fn to_envelope_frame(&self) -> Vec<u8> {
+        format!("{}{}", OPTIONS_SEPARATOR, self.to_query()).into_bytes()
+    }
+
+    ///
+    /// Decodes the options from an envelope frame, if it is one built by `to_envelope_frame`
+    ///
+    pub fn from_envelope_frame(frame: &[u8]) -> Option<Self> {
+        let query = std::str::from_utf8(frame)
+            .ok()?
+            .strip_prefix(OPTIONS_SEPARATOR)?;
+        RequestOptions::from_query(query).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_round_trip() {
+        let options = RequestOptions::default()
+            .with_request_id("req-1")
+            .with_affinity_key("user.42")
+            .with_label("zone", "eu-west")
+            .with_gather(GatherPolicy::Quorum(2))
+            .with_priority(7);
+        let frame = options.to_service_frame("echo");
+        let (service_name, decoded) = RequestOptions::from_service_frame(&frame).unwrap();
+        assert_eq!(service_name, "echo");
+        assert_eq!(decoded, options);
+    }
+
+    #[test]
+    fn reserved_characters_are_escaped() {
+        let options = RequestOptions::default()
+            .with_request_id("a?b")
+            .with_affinity_key("a&b=c")
+            .with_label("k=1&x", "50% é");
+        let query = options.to_query();
+        assert!(!query.contains('?'), "{query}");
+        assert_eq!(query.matches('&').count(), 2, "{query}");
+        assert_eq!(query.matches('=').count(), 3, "{query}");
+
+        let (_, decoded) =
+            RequestOptions::from_service_frame(&options.to_service_frame("echo")).unwrap();
+        assert_eq!(decoded.affinity_key.as_deref(), Some("a&b=c"));
+        assert_eq!(decoded, options);
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        assert!(RequestOptions::from_query("ttl=100").is_err());
+        assert!(RequestOptions::from_query("affinity").is_err());
+        assert!(RequestOptions::from_query("affinity=%zz").is_err());
+        assert!(RequestOptions::from_query("affinity=%4").is_err());
+        assert!(RequestOptions::from_query("affinity=%ff").is_err());
+    }
+
+    #[test]
+    fn deadline_is_sent_as_time_left() {
+        let options = RequestOptions::default().with_ttl(Duration::from_secs(60));
+        let decoded = RequestOptions::from_query(&options.to_query()).unwrap();
+        let budget = decoded.remaining_budget().unwrap();
+        assert!(budget > Duration::from_secs(59) && budget <= Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,516 @@
+use crate::broker::{Broker, BrokerBuilder};
+use crate::client::{Client, ClientRequestResult, ClientRequestState};
+use crate::options::RequestOptions;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use zmq::{Context, Socket, SocketType};
+
+const WORKER_VERSION_HEADER: &[u8] = b"MDPW02";
+const WORKER_READY: u8 = 1;
+const WORKER_REQUEST: u8 = 2;
+const WORKER_FINAL: u8 = 4;
+const WORKER_HEARTBEAT: u8 = 5;
+const WORKER_DISCONNECT: u8 = 6;
+const WORKER_CANCEL: u8 = 7;
+
+/// Period of the heartbeats sent by test workers, well below the expiration time of the broker
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Time given to test brokers to answer in-flight requests once stopped
+const GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Time given to test workers to hand their last messages over, so that a worker outliving its
+/// broker does not block the termination of the context
+const WORKER_LINGER: Duration = Duration::from_millis(100);
+
+/// Time between two checks of the services registered to the broker
+const SERVICE_CHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Number given to the next test broker, so that brokers of concurrent tests use distinct
+/// endpoints
+static NEXT_TEST_BROKER_ID: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Broker running in a thread of the test, on `inproc://` endpoints
+///
+/// Clients and workers are created in the context of the broker to reach these endpoints. The
+/// broker is stopped when dropped.
+///
+/// ```no_run
+/// use domolib::testing::TestBroker;
+/// use std::time::Duration;
+///
+/// let broker = TestBroker::start();
+/// let _worker = broker.spawn_worker("echo", |body| body);
+/// broker.wait_for_service("echo", Duration::from_secs(1));
+///
+/// let client = broker.client();
+/// let answer = broker.expect_reply(&client, "echo", &[b"hello"], Duration::from_secs(1));
+/// assert_eq!(answer, vec![b"hello".to_vec()]);
+/// ```
+///
+pub struct TestBroker {
+    zmq_ctx: Context,
+    clients_endpoint: String,
+    workers_endpoint: String,
+    shutdown_requested: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestBroker {
+    ///
+    /// Starts a broker with the default options, in-flight requests being only given 100ms to
+    /// complete when it is stopped
+    ///
+    pub fn start() -> Self {
+        TestBroker::start_with(Broker::builder().with_grace_period(GRACE_PERIOD))
+    }
+
+    ///
+    /// Starts a broker with the options of the given builder, its endpoints and context being
+    /// replaced by the ones of the test
+    ///
+    /// Panics if the broker can not be started
+    ///
+    pub fn start_with(builder: BrokerBuilder) -> Self {
+        TestBroker::start_in(&Context::new(), builder)
+    }
+
+    ///
+    /// Starts a broker in the given context, so that the brokers of a test can reach each other
+    /// on their `inproc://` endpoints (e.g. as peer brokers)
+    ///
+    /// Panics if the broker can not be started
+    ///
+    pub fn start_in(zmq_ctx: &Context, builder: BrokerBuilder) -> Self {
+        let id = NEXT_TEST_BROKER_ID.fetch_add(1, Ordering::Relaxed);
+        let zmq_ctx = zmq_ctx.clone();
+        let clients_endpoint = format!("inproc://test-broker-{id}-clients");
+        let workers_endpoint = format!("inproc://test-broker-{id}-workers");
+        let builder = builder
+            .with_context(zmq_ctx.clone())
+            .with_clients_endpoint(&clients_endpoint)
+            .with_workers_endpoint(&workers_endpoint);
+        let shutdown_requested = Arc::new(AtomicBool::new(false));
+
+        // the broker is built by its thread, which tells once its endpoints are bound
+        let (started_sender, started) = mpsc::channel();
+        let flag = Arc::clone(&shutdown_requested);
+        let thread = thread::spawn(move || match builder.build() {
+            Ok(broker) => {
+                started_sender.send(Ok(())).ok();
+                broker
+                    .run_until(&flag)
+                    .unwrap_or_else(|err| log::error!("Test broker failure : {}", err));
+            }
+            Err(err) => {
+                started_sender.send(Err(err)).ok();
+            }
+        });
+        started
+            .recv()
+            .expect("Test broker thread stopped")
+            .unwrap_or_else(|err| panic!("Failed to start test broker : {}", err));
+
+        TestBroker {
+            zmq_ctx,
+            clients_endpoint,
+            workers_endpoint,
+            shutdown_requested,
+            thread: Some(thread),
+        }
+    }
+
+    ///
+    /// Returns the context of the broker, sockets reaching its endpoints having to be created in
+    /// it
+    ///
+    pub fn context(&self) -> &Context {
+        &self.zmq_ctx
+    }
+
+    pub fn clients_endpoint(&self) -> &str {
+        &self.clients_endpoint
+    }
+
+    pub fn workers_endpoint(&self) -> &str {
+        &self.workers_endpoint
+    }
+
+    ///
+    /// Returns a client connected to the broker
+    ///
+    pub fn client(&self) -> Client {
+        Client::with_context(&self.zmq_ctx, &[&self.clients_endpoint])
+            .expect("Failed to create test client")
+    }
+
+    ///
+    /// Starts a worker registering for the given service, in a thread of its own
+    ///
+    /// The handler is called with the body of each request, and returns the body of its FINAL
+    /// answer. Requests cancelled while their handler runs are not answered, as by workers
+    /// stopping on a cancellation, and requests cancelled before are not handled. The worker
+    /// sends DISCONNECT when dropped, `TestWorker::kill` stopping it without telling the broker.
+    ///
+    pub fn spawn_worker<F>(&self, service_name: &str, handler: F) -> TestWorker
+    where
+        F: FnMut(Vec<Vec<u8>>) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        let socket = self
+            .zmq_ctx
+            .socket(SocketType::DEALER)
+            .expect("Failed to create test worker socket");
+        socket
+            .set_linger(WORKER_LINGER.as_millis() as i32)
+            .expect("Failed to set test worker linger");
+        socket
+            .connect(&self.workers_endpoint)
+            .expect("Failed to connect test worker");
+        let stop = Arc::new(AtomicBool::new(false));
+        let killed = Arc::new(AtomicBool::new(false));
+        let service_name = service_name.to_string();
+        let thread = {
+            let stop = Arc::clone(&stop);
+            let killed = Arc::clone(&killed);
+            thread::spawn(move || {
+                run_worker(&socket, &service_name, handler, &stop, &killed).unwrap_or_else(|err| {
+                    log::error!("Test worker for '{}' failed : {}", service_name, err)
+                })
+            })
+        };
+        TestWorker {
+            stop,
+            killed,
+            thread: Some(thread),
+        }
+    }
+
+    ///
+    /// Waits until a worker is registered for the given service
+    ///
+    /// Panics if none is within the given time
+    ///
+    pub fn wait_for_service(&self, service_name: &str, timeout: Duration) {
+        if !self.wait_for_service_state(service_name, true, timeout) {
+            panic!(
+                "Service '{}' not registered within {:?}",
+                service_name, timeout
+            );
+        }
+    }
+
+    ///
+    /// Waits until no worker is registered for the given service anymore, e.g. once its workers
+    /// were killed
+    ///
+    /// Panics if some still are after the given time
+    ///
+    pub fn wait_for_service_gone(&self, service_name: &str, timeout: Duration) {
+        if !self.wait_for_service_state(service_name, false, timeout) {
+            panic!(
+                "Service '{}' still registered after {:?}",
+                service_name, timeout
+            );
+        }
+    }
+
+    fn wait_for_service_state(
+        &self,
+        service_name: &str,
+        registered: bool,
+        timeout: Duration,
+    ) -> bool {
+        let client = self.client();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let options = RequestOptions::default().with_ttl(
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .max(SERVICE_CHECK_INTERVAL),
+            );
+            let status = client
+                .send_request_with_options(
+                    "mmi.service",
+                    &[service_name.as_bytes().to_vec()],
+                    &options,
+                )
+                .and_then(|mut request| request.next())
+                .and_then(|answer| answer.payload.get(1).cloned());
+            if status.as_deref() == Some(if registered { b"200" } else { b"404" }) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(SERVICE_CHECK_INTERVAL);
+        }
+    }
+
+    ///
+    /// Sends a request and returns the body of its FINAL answer, PARTIAL answers being skipped
+    ///
+    /// Panics if the broker answers with an error, or if no FINAL answer comes within the given
+    /// time
+    ///
+    pub fn expect_reply(
+        &self,
+        client: &Client,
+        service_name: &str,
+        body: &[impl AsRef<[u8]>],
+        within: Duration,
+    ) -> Vec<Vec<u8>> {
+        match last_answer(client, service_name, body, within) {
+            Some(answer) if answer.state == ClientRequestState::FINAL => answer.payload,
+            Some(answer) => panic!(
+                "Request for service '{}' failed : {:?}",
+                service_name,
+                frames_to_strings(&answer.payload)
+            ),
+            None => panic!(
+                "No answer to request for service '{}' within {:?}",
+                service_name, within
+            ),
+        }
+    }
+
+    ///
+    /// Sends a request and returns the status code and the reason of the ERROR answering it
+    ///
+    /// Panics if the request is answered with a FINAL, or if no ERROR comes within the given time
+    ///
+    pub fn expect_error(
+        &self,
+        client: &Client,
+        service_name: &str,
+        body: &[impl AsRef<[u8]>],
+        within: Duration,
+    ) -> (String, String) {
+        match last_answer(client, service_name, body, within) {
+            Some(answer) if answer.state == ClientRequestState::ERROR => {
+                // the service name comes first, followed by the status code and the reason
+                match frames_to_strings(&answer.payload).as_slice() {
+                    [_, status_code, reason, ..] => (status_code.clone(), reason.clone()),
+                    frames => panic!("Malformed error answer : {:?}", frames),
+                }
+            }
+            Some(answer) => panic!(
+                "Request for service '{}' succeeded : {:?}",
+                service_name,
+                frames_to_strings(&answer.payload)
+            ),
+            None => panic!(
+                "No error for request for service '{}' within {:?}",
+                service_name, within
+            ),
+        }
+    }
+}
+
+///
+/// Sends a request and returns its FINAL or ERROR answer, PARTIAL answers being skipped
+///
+fn last_answer(
+    client: &Client,
+    service_name: &str,
+    body: &[impl AsRef<[u8]>],
+    within: Duration,
+) -> Option<ClientRequestResult> {
+    let body: Vec<Vec<u8>> = body.iter().map(|frame| frame.as_ref().to_vec()).collect();
+    let options = RequestOptions::default().with_ttl(within);
+    client
+        .send_request_with_options(service_name, &body, &options)
+        .expect("Failed to send request")
+        .find(|answer| answer.state != ClientRequestState::PARTIAL)
+}
+
+fn frames_to_strings(frames: &[Vec<u8>]) -> Vec<String> {
+    frames
+        .iter()
+        .map(|frame| String::from_utf8_lossy(frame).into_owned())
+        .collect()
+}
+
+impl Drop for TestBroker {
+    fn drop(&mut self) {
+        self.shutdown_requested.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+///
+/// Worker started by `TestBroker::spawn_worker`
+///
+pub struct TestWorker {
+    stop: Arc<AtomicBool>,
+    killed: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestWorker {
+    ///
+    /// Stops the worker as if its process died : it sends neither DISCONNECT nor the answer of
+    /// the request being handled, the broker only noticing once heartbeats stop coming
+    ///
+    /// Returns once the handler of the request being handled, if any, returned
+    ///
+    pub fn kill(mut self) {
+        self.killed.store(true, Ordering::Relaxed);
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for TestWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+///
+/// Sends a command to the broker, dropped if the broker is not reachable anymore
+///
+fn send_command(socket: &Socket, command: u8, frames: &[Vec<u8>]) -> Result<(), zmq::Error> {
+    let header: [&[u8]; 2] = [WORKER_VERSION_HEADER, &[command]];
+    match socket.send_multipart(
+        header.into_iter().chain(frames.iter().map(Vec::as_slice)),
+        zmq::DONTWAIT,
+    ) {
+        Err(zmq::Error::EAGAIN) => {
+            log::debug!("Test worker command dropped, the broker stopped");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+///
+/// Returns the request ID of the request carried by the given REQUEST frames, read from the
+/// options ending the envelope of the client
+///
+fn request_id(frames: &[Vec<u8>], separator: usize) -> Option<String> {
+    // the options follow the client identity
+    if separator < 4 {
+        return None;
+    }
+    RequestOptions::from_envelope_frame(&frames[separator - 1])
+        .and_then(|options| options.request_id)
+}
+
+///
+/// Reads the commands received so far, returns whether the request with the given ID was
+/// cancelled
+///
+/// Requests cancelled before being handled are dropped, the other commands being kept in the
+/// backlog
+///
+fn receive_cancellations(
+    socket: &Socket,
+    backlog: &mut VecDeque<Vec<Vec<u8>>>,
+    handled_request_id: Option<&str>,
+) -> Result<bool, zmq::Error> {
+    let mut cancelled = false;
+    while socket.poll(zmq::POLLIN, 0)? > 0 {
+        let frames = socket.recv_multipart(0)?;
+        if frames.get(1).and_then(|command| command.first()) != Some(&WORKER_CANCEL) {
+            backlog.push_back(frames);
+            continue;
+        }
+        let cancelled_id = frames
+            .get(2)
+            .map(|id| String::from_utf8_lossy(id).into_owned());
+        if cancelled_id.is_some() && cancelled_id.as_deref() == handled_request_id {
+            cancelled = true;
+        }
+        backlog.retain(|queued| {
+            let separator = queued
+                .iter()
+                .skip(2)
+                .position(Vec::is_empty)
+                .map(|pos| pos + 2);
+            separator.is_none_or(|separator| request_id(queued, separator) != cancelled_id)
+        });
+    }
+    Ok(cancelled)
+}
+
+///
+/// Registers the worker, then answers requests and sends heartbeats until stopped
+///
+fn run_worker<F>(
+    socket: &Socket,
+    service_name: &str,
+    mut handler: F,
+    stop: &AtomicBool,
+    killed: &AtomicBool,
+) -> Result<(), zmq::Error>
+where
+    F: FnMut(Vec<Vec<u8>>) -> Vec<Vec<u8>>,
+{
+    let service_frame = [service_name.as_bytes().to_vec()];
+    send_command(socket, WORKER_READY, &service_frame)?;
+    let mut last_heartbeat = Instant::now();
+    // commands received while a request was handled
+    let mut backlog = VecDeque::new();
+
+    while !stop.load(Ordering::Relaxed) {
+        let frames = match backlog.pop_front() {
+            Some(frames) => Some(frames),
+            None if socket.poll(zmq::POLLIN, 50)? > 0 => Some(socket.recv_multipart(0)?),
+            None => None,
+        };
+        if let Some(frames) = frames {
+            match frames.get(1).and_then(|command| command.first()) {
+                Some(&WORKER_REQUEST) => {
+                    // the envelope of the client ends with an empty frame, the body follows
+                    let Some(separator) = frames
+                        .iter()
+                        .skip(2)
+                        .position(Vec::is_empty)
+                        .map(|pos| pos + 2)
+                    else {
+                        log::error!("Test worker received a request without envelope");
+                        continue;
+                    };
+                    let body = handler(frames[separator + 1..].to_vec());
+                    if killed.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let request_id = request_id(&frames, separator);
+                    if !receive_cancellations(socket, &mut backlog, request_id.as_deref())? {
+                        let answer: Vec<Vec<u8>> =
+                            frames[2..=separator].iter().cloned().chain(body).collect();
+                        send_command(socket, WORKER_FINAL, &answer)?;
+                    }
+                }
+                // the broker forgot the worker, which registers again
+                Some(&WORKER_DISCONNECT) => send_command(socket, WORKER_READY, &service_frame)?,
+                // CANCELs of requests answered already need nothing
+                _ => (),
+            }
+        }
+        if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            send_command(socket, WORKER_HEARTBEAT, &[])?;
+            last_heartbeat = Instant::now();
+        }
+    }
+
+    if killed.load(Ordering::Relaxed) {
+        // nothing queued is sent anymore
+        socket.set_linger(0)?;
+    } else {
+        send_command(socket, WORKER_DISCONNECT, &[])?;
+    }
+    Ok(())
+}
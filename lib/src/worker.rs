@@ -1,10 +1,19 @@
 use crate::errors::WorkerError;
+use crate::options::RequestOptions;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 use zmq::SocketType;
 
 const EXPECTED_WORKER_VERSION_HEADER: &str = "MDPW02";
 
+/// Time without any heartbeat after which the broker is considered gone
+const BROKER_EXPIRATION: Duration = Duration::from_secs(1);
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum WorkerRequestState {
     READY = 1,
@@ -13,6 +22,8 @@ pub enum WorkerRequestState {
     FINAL = 4,
     HEARTBEAT = 5,
     DISCONNECT = 6,
+    /// The client of the request being handled cancelled it, the payload holding the request ID
+    CANCEL = 7,
 }
 
 pub struct WorkerRequestResult {
@@ -20,6 +31,25 @@ pub struct WorkerRequestResult {
     pub payload: Option<Vec<Vec<u8>>>,
 }
 
+///
+/// Flag raised when the client of the request being handled cancels it
+///
+/// The worker reads the cancellations sent by the broker when `Worker::poll_cancellation` is
+/// called, tokens being only updated then
+///
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+}
+
 pub type TaskHandlerFunction = fn(&Worker, &Option<Vec<Vec<u8>>>) -> ();
 
 pub struct Worker {
@@ -28,6 +58,20 @@ pub struct Worker {
     task_handler: TaskHandlerFunction,
     connected: bool,
     last_broker_keepalive_time: Instant,
+    /// Brokers the worker can register to (e.g. both brokers of a Binary Star pair)
+    broker_endpoints: Vec<String>,
+    /// Index of the broker currently connected to
+    current_endpoint: usize,
+    /// Weight declared to the broker, used by its weighted dispatch strategy
+    weight: Option<u32>,
+    /// Labels declared to the broker, matched against the labels requested by clients
+    labels: Vec<(String, String)>,
+    /// Options of the request being handled
+    current_options: RequestOptions,
+    /// Cancellation of the request being handled
+    cancellation: CancellationToken,
+    /// Commands received while looking for cancellations, handled once the current request is
+    backlog: RefCell<VecDeque<WorkerRequestResult>>,
 }
 
 impl Worker {
@@ -36,18 +80,82 @@ impl Worker {
         broker_connection_string: &str,
         handler: TaskHandlerFunction,
     ) -> Result<Self, WorkerError> {
-        let ctx = zmq::Context::new();
+        Worker::with_endpoints(task_name, &[broker_connection_string], handler)
+    }
+
+    ///
+    /// Creates a worker able to fail over between several brokers
+    ///
+    /// The worker connects to the first endpoint. When more than one endpoint is given, the
+    /// worker registers to the next broker of the list as soon as the current one sends a
+    /// DISCONNECT or stops sending heartbeats.
+    ///
+    /// # Arguments
+    ///
+    /// * `task_name` - name of the service handled by the worker
+    /// * `broker_connection_strings` - endpoints of the brokers workers connect to
+    /// * `handler` - function called for each request received
+    ///
+    pub fn with_endpoints(
+        task_name: String,
+        broker_connection_strings: &[&str],
+        handler: TaskHandlerFunction,
+    ) -> Result<Self, WorkerError> {
+        Worker::with_context(
+            &zmq::Context::new(),
+            task_name,
+            broker_connection_strings,
+            handler,
+        )
+    }
+
+    ///
+    /// Creates a worker whose connection is created in the given context, e.g. to reach a broker
+    /// running in the same process on an `inproc://` endpoint
+    ///
+    /// # Arguments
+    ///
+    /// * `zmq_ctx` - context the connection is created in
+    /// * `task_name` - name of the service handled by the worker
+    /// * `broker_connection_strings` - endpoints of the brokers workers connect to
+    /// * `handler` - function called for each request received
+    ///
+    pub fn with_context(
+        zmq_ctx: &zmq::Context,
+        task_name: String,
+        broker_connection_strings: &[&str],
+        handler: TaskHandlerFunction,
+    ) -> Result<Self, WorkerError> {
+        if broker_connection_strings.is_empty() {
+            return Err(WorkerError::InitializationError(
+                "No broker endpoint given".into(),
+            ));
+        }
+
+        let connection = zmq_ctx
+            .socket(SocketType::DEALER)
+            .map_err(|err| WorkerError::InitializationError(err.to_string()))?;
         let mut result = Worker {
-            worker_connection: Some(ctx.socket(SocketType::DEALER).unwrap()),
+            worker_connection: Some(connection),
             task_handled: task_name,
             task_handler: handler,
             connected: false,
             last_broker_keepalive_time: Instant::now(),
+            broker_endpoints: broker_connection_strings
+                .iter()
+                .map(|endpoint| endpoint.to_string())
+                .collect(),
+            current_endpoint: 0,
+            weight: None,
+            labels: Vec::new(),
+            current_options: RequestOptions::default(),
+            cancellation: CancellationToken::default(),
+            backlog: RefCell::new(VecDeque::new()),
         };
 
         match &result.worker_connection {
             Some(connection) => {
-                connection.connect(&broker_connection_string).unwrap();
+                connection.connect(&result.broker_endpoints[0]).unwrap();
                 result.connected = true;
             }
             _ => {
@@ -55,7 +163,52 @@ impl Worker {
             }
         }
 
-        return Ok(result);
+        Ok(result)
+    }
+
+    ///
+    /// Connects to the next known broker and registers to it
+    ///
+    fn failover(&mut self) -> Result<(), WorkerError> {
+        let next = (self.current_endpoint + 1) % self.broker_endpoints.len();
+        log::warn!(
+            "Lost broker '{}', failing over to '{}'",
+            self.broker_endpoints[self.current_endpoint],
+            self.broker_endpoints[next]
+        );
+
+        if let Some(connection) = &self.worker_connection {
+            connection
+                .disconnect(&self.broker_endpoints[self.current_endpoint])
+                .unwrap_or_else(|err| log::debug!("Failed to disconnect from broker : {}", err));
+            connection
+                .connect(&self.broker_endpoints[next])
+                .map_err(|err| WorkerError::CommunicationError(err.to_string()))?;
+        }
+        self.current_endpoint = next;
+        self.last_broker_keepalive_time = Instant::now();
+        self.register_to_broker()
+    }
+
+    ///
+    /// Sets the weight declared to the broker when registering
+    ///
+    /// Brokers using the weighted dispatch strategy send proportionally more requests to workers
+    /// with a higher weight (e.g. 4 for a worker running on a machine 4 times faster)
+    ///
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = Some(weight);
+    }
+
+    ///
+    /// Adds a label declared to the broker when registering (e.g. `zone`, `eu-west`)
+    ///
+    /// Clients requesting labels are only served by workers declaring all of them. Keys and values
+    /// should only contain alphanumeric characters, `-`, `_` and `.`, `weight` being reserved
+    ///
+    pub fn set_label(&mut self, key: &str, value: &str) {
+        self.labels.retain(|(label, _)| label != key);
+        self.labels.push((key.to_string(), value.to_string()));
     }
 
     pub fn register_to_broker(&mut self) -> Result<(), WorkerError> {
@@ -67,7 +220,30 @@ impl Worker {
             connection
                 .send(request_type.as_slice(), zmq::SNDMORE)
                 .unwrap();
-            connection.send(self.task_handled.as_str(), 0).unwrap();
+            // worker properties follow the service name, as key=value frames
+            let properties: Vec<String> = self
+                .weight
+                .map(|weight| format!("weight={}", weight))
+                .into_iter()
+                .chain(
+                    self.labels
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value)),
+                )
+                .collect();
+            connection
+                .send(
+                    self.task_handled.as_str(),
+                    if properties.is_empty() {
+                        0
+                    } else {
+                        zmq::SNDMORE
+                    },
+                )
+                .unwrap();
+            connection
+                .send_multipart(properties.iter().map(String::as_bytes), 0)
+                .unwrap();
 
             log::info!("Registered worker for task '{}'", self.task_handled);
             self.connected = true;
@@ -116,9 +292,138 @@ impl Worker {
         }
     }
 
+    ///
+    /// Sends the FINAL answer to a request
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - payload passed to the handler, whose envelope is sent back with the answer
+    /// * `body` - frames of the answer
+    ///
+    pub fn send_final(&self, request: &[Vec<u8>], body: &[Vec<u8>]) -> Result<(), WorkerError> {
+        let connection = self
+            .worker_connection
+            .as_ref()
+            .ok_or_else(|| WorkerError::InitializationError("No connection created".into()))?;
+        // the envelope of the client ends with an empty frame, the body of the request follows
+        let separator = request.iter().position(Vec::is_empty).ok_or_else(|| {
+            WorkerError::CommunicationError("Request received without envelope".into())
+        })?;
+        let request_type: [u8; 1] = [WorkerRequestState::FINAL as u8];
+        let header: [&[u8]; 2] = [
+            EXPECTED_WORKER_VERSION_HEADER.as_bytes(),
+            request_type.as_slice(),
+        ];
+        connection
+            .send_multipart(
+                header
+                    .into_iter()
+                    .chain(request[..=separator].iter().map(Vec::as_slice))
+                    .chain(body.iter().map(Vec::as_slice)),
+                0,
+            )
+            .map_err(|err| WorkerError::CommunicationError(err.to_string()))
+    }
+
     pub fn check_broker_connection_expired(&self) -> bool {
-        return (Instant::now() - self.last_broker_keepalive_time) > Duration::from_secs(1);
+        (Instant::now() - self.last_broker_keepalive_time) > BROKER_EXPIRATION
     }
+
+    ///
+    /// Returns the time after which the client stops waiting for the request being handled, if
+    /// it set a deadline
+    ///
+    pub fn request_deadline(&self) -> Option<SystemTime> {
+        self.current_options.deadline
+    }
+
+    ///
+    /// Returns the identifier the broker assigned to the request being handled (or the one set
+    /// by its client), to be used in logs
+    ///
+    pub fn request_id(&self) -> Option<&str> {
+        self.current_options.request_id.as_deref()
+    }
+
+    ///
+    /// Returns the time left to answer the request being handled, if its client set a deadline
+    ///
+    /// Handlers can abort early once it reaches zero, nobody waiting for the answer anymore
+    ///
+    pub fn remaining_budget(&self) -> Option<Duration> {
+        self.current_options.remaining_budget()
+    }
+
+    ///
+    /// Returns the token raised when the client of the request being handled cancels it
+    ///
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    ///
+    /// Reads the commands sent by the broker in the meantime and indicates whether or not the
+    /// request being handled was cancelled
+    ///
+    /// Long running handlers should call it regularly, and stop as soon as it returns true. Other
+    /// commands are kept to be handled by `process` afterwards.
+    ///
+    pub fn poll_cancellation(&self) -> bool {
+        while let Some(entry) = self.receive_command(0) {
+            if entry.state == WorkerRequestState::CANCEL {
+                let request_id = cancelled_request_id(&entry);
+                if request_id.is_some() && request_id.as_deref() == self.request_id() {
+                    log::info!(
+                        "Request '{}' cancelled by its client",
+                        self.request_id().unwrap_or_default()
+                    );
+                    self.cancellation.cancel();
+                    continue;
+                }
+            }
+            self.backlog.borrow_mut().push_back(entry);
+        }
+        self.cancellation.is_cancelled()
+    }
+
+    fn receive_command(&self, timeout: i64) -> Option<WorkerRequestResult> {
+        let connection = self.worker_connection.as_ref()?;
+        let mut poll_list = [connection.as_poll_item(zmq::POLLIN)];
+
+        // time to poll events for all sockets
+        match zmq::poll(&mut poll_list, timeout) {
+            // we only have one socket to monitor, no need to over engineer this
+            Ok(nbitemspolled) if nbitemspolled > 0 => receive_and_handle_broker_request(connection),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Reads the options the broker appended to the envelope of a request, if any
+///
+/// The options frame is left in the payload, so that handlers send it back with the envelope
+///
+fn request_options(payload: &Option<Vec<Vec<u8>>>) -> RequestOptions {
+    let frames = match payload {
+        Some(frames) => frames,
+        None => return RequestOptions::default(),
+    };
+    // options are the last envelope frame, after the client identity and before the empty frame
+    match frames.iter().position(|frame| frame.is_empty()) {
+        Some(separator) if separator >= 2 => {
+            RequestOptions::from_envelope_frame(&frames[separator - 1]).unwrap_or_default()
+        }
+        _ => RequestOptions::default(),
+    }
+}
+
+fn cancelled_request_id(entry: &WorkerRequestResult) -> Option<String> {
+    entry
+        .payload
+        .as_ref()
+        .and_then(|frames| frames.first())
+        .map(|request_id| String::from_utf8_lossy(request_id).into_owned())
 }
 
 fn receive_and_handle_broker_request(sock: &zmq::Socket) -> Option<WorkerRequestResult> {
@@ -142,75 +447,80 @@ fn receive_and_handle_broker_request(sock: &zmq::Socket) -> Option<WorkerRequest
 
     // now check the type of command (PARTIAL/FINAL)
     // if they are found, just push the payload by receiving the rest of the frames
-    match msg.into_iter().nth(0) {
-        Some(&x) if x == WorkerRequestState::REQUEST as u8 => {
-            return Some(WorkerRequestResult {
-                state: WorkerRequestState::REQUEST,
-                payload: Some(sock.recv_multipart(0).unwrap()),
-            })
-        }
-        Some(&x) if x == WorkerRequestState::HEARTBEAT as u8 => {
-            return Some(WorkerRequestResult {
-                state: WorkerRequestState::HEARTBEAT,
-                payload: None,
-            })
-        }
-        Some(&x) if x == WorkerRequestState::DISCONNECT as u8 => {
-            return Some(WorkerRequestResult {
-                state: WorkerRequestState::DISCONNECT,
-                payload: None,
-            })
-        }
+    match msg.first() {
+        Some(&x) if x == WorkerRequestState::REQUEST as u8 => Some(WorkerRequestResult {
+            state: WorkerRequestState::REQUEST,
+            payload: Some(sock.recv_multipart(0).unwrap()),
+        }),
+        Some(&x) if x == WorkerRequestState::HEARTBEAT as u8 => Some(WorkerRequestResult {
+            state: WorkerRequestState::HEARTBEAT,
+            payload: None,
+        }),
+        Some(&x) if x == WorkerRequestState::DISCONNECT as u8 => Some(WorkerRequestResult {
+            state: WorkerRequestState::DISCONNECT,
+            payload: None,
+        }),
+        Some(&x) if x == WorkerRequestState::CANCEL as u8 => Some(WorkerRequestResult {
+            state: WorkerRequestState::CANCEL,
+            payload: Some(sock.recv_multipart(0).unwrap()),
+        }),
         Some(state) => {
             log::error!("Unhandled state : {}", state);
-            return None;
+            None
         }
-        _ => return None,
-    };
+        _ => None,
+    }
 }
 
 impl Worker {
     pub fn process(&mut self) {
-        match &self.worker_connection {
-            Some(connection) => {
-                let mut poll_list = [connection.as_poll_item(zmq::POLLIN)];
-
-                // time to poll events for all sockets
-                match zmq::poll(&mut poll_list, 100) {
-                    Ok(nbitemspolled) => {
-                        if nbitemspolled > 0 {
-                            // we only have one socket to monitor, no need to over engineer this
-                            let returned_state = receive_and_handle_broker_request(&connection);
-                            match &returned_state {
-                                Some(entry) => match entry.state {
-                                    // update request status based on returned state
-                                    WorkerRequestState::REQUEST => {
-                                        if self.connected {
-                                            (self.task_handler)(&self, &entry.payload);
-                                        } else {
-                                            log::error!("Received request to execute task '{}' although the worker is not READY.", self.task_handled);
-                                        }
-                                    }
-                                    WorkerRequestState::DISCONNECT => {
-                                        // mark as not connected to ensure the READY signal will be
-                                        // sent next time a command has to be sent
-                                        self.connected = false;
-                                    }
-                                    WorkerRequestState::HEARTBEAT => {
-                                        self.last_broker_keepalive_time = Instant::now();
-                                    }
-                                    _ => {
-                                        log::error!("Unhandled state received : {:?}", entry.state);
-                                    }
-                                },
-                                None => (),
-                            }
-                        }
+        // commands read while a request was handled come first
+        let returned_state = match self.backlog.get_mut().pop_front() {
+            Some(entry) => Some(entry),
+            None => self.receive_command(100),
+        };
+        if let Some(entry) = &returned_state {
+            match entry.state {
+                // update request status based on returned state
+                WorkerRequestState::REQUEST => {
+                    if self.connected {
+                        self.current_options = request_options(&entry.payload);
+                        self.cancellation = CancellationToken::default();
+                        (self.task_handler)(self, &entry.payload);
+                    } else {
+                        log::error!("Received request to execute task '{}' although the worker is not READY.", self.task_handled);
                     }
-                    Err(_) => (),
+                }
+                WorkerRequestState::DISCONNECT => {
+                    // mark as not connected to ensure the READY signal will be
+                    // sent next time a command has to be sent
+                    self.connected = false;
+                    self.last_broker_keepalive_time = Instant::now() - BROKER_EXPIRATION;
+                }
+                WorkerRequestState::HEARTBEAT => {
+                    self.last_broker_keepalive_time = Instant::now();
+                }
+                WorkerRequestState::CANCEL => {
+                    // the request may not have been handled yet
+                    let request_id = cancelled_request_id(entry);
+                    self.backlog.get_mut().retain(|queued| {
+                        queued.state != WorkerRequestState::REQUEST
+                            || request_options(&queued.payload).request_id != request_id
+                    });
+                }
+                _ => {
+                    log::error!("Unhandled state received : {:?}", entry.state);
                 }
             }
-            None => (),
+        }
+
+        // with several brokers known, move to the next one as soon as the current one is gone
+        if self.broker_endpoints.len() > 1
+            && (!self.connected || self.check_broker_connection_expired())
+        {
+            self.failover().unwrap_or_else(|err| {
+                log::error!("Failed to fail over to the next broker : {:?}", err)
+            });
         }
     }
 }
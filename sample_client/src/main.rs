@@ -17,6 +17,16 @@ fn main() {
                 ClientRequestState::FINAL => {
                     log::debug!("Final answer received");
                 }
+                ClientRequestState::ERROR => {
+                    log::error!(
+                        "Request failed : {:?}",
+                        entry
+                            .payload
+                            .iter()
+                            .map(|frame| String::from_utf8_lossy(frame))
+                            .collect::<Vec<_>>()
+                    );
+                }
                 _ => (), // ignore other states
             }
         }
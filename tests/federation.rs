@@ -0,0 +1,76 @@
+use domolib::broker::Broker;
+use domolib::client::{Client, ClientRequestState};
+use domolib::options::RequestOptions;
+use domolib::testing::TestBroker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PEER_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+///
+/// Starts a broker handling the "echo" service and a broker only knowing it as a peer
+///
+fn federated_brokers() -> (TestBroker, TestBroker) {
+    let zmq_ctx = zmq::Context::new();
+    let serving = TestBroker::start_in(&zmq_ctx, Broker::builder());
+    let forwarding = TestBroker::start_in(
+        &zmq_ctx,
+        Broker::builder()
+            .with_peer(serving.clients_endpoint())
+            .with_peer_refresh_interval(PEER_REFRESH_INTERVAL),
+    );
+    (serving, forwarding)
+}
+
+///
+/// Waits until the forwarding broker learnt through `mmi.discovery` that its peer handles the
+/// "echo" service, requests being answered with 404 until then
+///
+fn wait_until_forwarded(client: &Client) {
+    let deadline = Instant::now() + TIMEOUT;
+    while client
+        .send_request("echo", &[b"probe".to_vec()])
+        .and_then(|mut request| request.next())
+        .is_none_or(|answer| answer.state != ClientRequestState::FINAL)
+    {
+        assert!(Instant::now() < deadline, "Peer services never discovered");
+        thread::sleep(PEER_REFRESH_INTERVAL);
+    }
+}
+
+#[test]
+fn requests_are_forwarded_to_the_peer_handling_the_service() {
+    let (serving, forwarding) = federated_brokers();
+    let _worker = serving.spawn_worker("echo", |body| body);
+    serving.wait_for_service("echo", TIMEOUT);
+    let client = forwarding.client();
+    wait_until_forwarded(&client);
+
+    assert_eq!(
+        forwarding.expect_reply(&client, "echo", &[b"hello", b"world"], TIMEOUT),
+        vec![b"hello".to_vec(), b"world".to_vec()]
+    );
+}
+
+#[test]
+fn forwarded_requests_are_not_forwarded_again() {
+    let (serving, forwarding) = federated_brokers();
+    let _worker = serving.spawn_worker("echo", |body| body);
+    serving.wait_for_service("echo", TIMEOUT);
+    let client = forwarding.client();
+    wait_until_forwarded(&client);
+
+    // a request already forwarded by another broker is only handled locally
+    let options = RequestOptions {
+        forwarded: true,
+        ..RequestOptions::default()
+    }
+    .with_ttl(TIMEOUT);
+    let answer = client
+        .send_request_with_options("echo", &[b"hello".to_vec()], &options)
+        .and_then(|mut request| request.next())
+        .expect("No answer");
+    assert_eq!(answer.state, ClientRequestState::ERROR);
+    assert_eq!(answer.payload[1], b"404");
+}
@@ -0,0 +1,57 @@
+use domolib::broker::Broker;
+use domolib::testing::TestBroker;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Services spread over the shards, their names having distinct first segments
+const SERVICES: [&str; 8] = [
+    "audio.mix",
+    "billing",
+    "echo",
+    "images.resize",
+    "mail",
+    "search",
+    "thumbnails",
+    "video.encode",
+];
+
+#[test]
+fn requests_reach_the_shard_of_their_service() {
+    let broker = TestBroker::start_with(Broker::builder().with_shards(4));
+    let _workers: Vec<_> = SERVICES
+        .iter()
+        .map(|service| broker.spawn_worker(service, |body| body))
+        .collect();
+    let client = broker.client();
+
+    for service in SERVICES {
+        broker.wait_for_service(service, TIMEOUT);
+        assert_eq!(
+            broker.expect_reply(&client, service, &[service], TIMEOUT),
+            vec![service.as_bytes().to_vec()]
+        );
+    }
+}
+
+#[test]
+fn discovery_lists_the_services_of_every_shard() {
+    let broker = TestBroker::start_with(Broker::builder().with_shards(4));
+    let _workers: Vec<_> = SERVICES
+        .iter()
+        .map(|service| broker.spawn_worker(service, |body| body))
+        .collect();
+    for service in SERVICES {
+        broker.wait_for_service(service, TIMEOUT);
+    }
+
+    let client = broker.client();
+    let answer = broker.expect_reply(&client, "mmi.discovery", &[] as &[&[u8]], TIMEOUT);
+    // the answer starts with the name of the MMI service
+    let services: Vec<&[u8]> = answer.iter().skip(1).map(Vec::as_slice).collect();
+    assert_eq!(
+        services,
+        SERVICES.map(str::as_bytes).to_vec(),
+        "Services of every shard are listed once, sorted"
+    );
+}
@@ -0,0 +1,373 @@
+use domolib::client::ClientRequestState;
+use domolib::options::{GatherPolicy, RequestOptions};
+use domolib::testing::{TestBroker, TestWorker};
+use domolib::worker::{TaskHandlerFunction, Worker};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Time given to the broker to expire a killed worker, workers that just registered being only
+/// expired after four seconds
+const EXPIRY_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Period of the heartbeats sent by the library workers, well below the broker expiration time
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+///
+/// Returns the body of a request received by a library worker, which follows the empty frame
+/// ending the envelope of the client
+///
+fn request_body(request: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    request
+        .iter()
+        .skip_while(|frame| !frame.is_empty())
+        .skip(1)
+        .cloned()
+        .collect()
+}
+
+///
+/// Answers each request with its own body
+///
+fn echo(worker: &Worker, request: &Option<Vec<Vec<u8>>>) {
+    let Some(request) = request else {
+        return;
+    };
+    worker
+        .send_final(request, &request_body(request))
+        .unwrap_or_else(|err| panic!("Failed to answer request : {:?}", err));
+}
+
+///
+/// Handles requests whose body is "hold" until their client cancels them, then stops without
+/// answering as long running handlers do, other requests being echoed
+///
+fn hold_until_cancelled(worker: &Worker, request: &Option<Vec<Vec<u8>>>) {
+    let Some(request) = request else {
+        return;
+    };
+    if request_body(request) != [b"hold"] {
+        return echo(worker, &Some(request.clone()));
+    }
+    let deadline = Instant::now() + TIMEOUT;
+    while !worker.poll_cancellation() {
+        assert!(Instant::now() < deadline, "Request never cancelled");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+///
+/// Creates a `domolib::worker::Worker` connected to the broker, not registered yet
+///
+fn library_worker(broker: &TestBroker, service_name: &str, handler: TaskHandlerFunction) -> Worker {
+    Worker::with_context(
+        broker.context(),
+        service_name.to_string(),
+        &[broker.workers_endpoint()],
+        handler,
+    )
+    .expect("Failed to create worker")
+}
+
+///
+/// Runs a `domolib::worker::Worker` handling requests until stopped, when it sends DISCONNECT
+///
+fn spawn_library_worker(
+    broker: &TestBroker,
+    service_name: &str,
+    handler: TaskHandlerFunction,
+    stop: &Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    run_library_worker(library_worker(broker, service_name, handler), stop)
+}
+
+///
+/// Registers the given worker, then lets it handle requests until stopped
+///
+fn run_library_worker(mut worker: Worker, stop: &Arc<AtomicBool>) -> JoinHandle<()> {
+    let stop = Arc::clone(stop);
+    thread::spawn(move || {
+        worker.register_to_broker().expect("Failed to register");
+        let mut last_heartbeat = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            worker.process();
+            if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+                worker.send_heartbeat().expect("Failed to send heartbeat");
+                last_heartbeat = Instant::now();
+            }
+        }
+        worker
+            .disconnect_from_broker()
+            .expect("Failed to disconnect");
+    })
+}
+
+///
+/// Starts a worker of the "slow" service telling each body it receives, whose handler waits until
+/// released for the requests whose body is "hold"
+///
+fn spawn_slow_worker(
+    broker: &TestBroker,
+) -> (TestWorker, mpsc::Receiver<Vec<u8>>, mpsc::Sender<()>) {
+    let (received_sender, received) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let worker = broker.spawn_worker("slow", move |body| {
+        let first = body.first().cloned().unwrap_or_default();
+        received_sender.send(first.clone()).ok();
+        if first == b"hold" {
+            released.recv_timeout(TIMEOUT).ok();
+        }
+        body
+    });
+    broker.wait_for_service("slow", TIMEOUT);
+    (worker, received, release)
+}
+
+#[test]
+fn requests_are_echoed_by_test_workers() {
+    let broker = TestBroker::start();
+    let _worker = broker.spawn_worker("echo", |body| body);
+    broker.wait_for_service("echo", TIMEOUT);
+
+    let client = broker.client();
+    assert_eq!(
+        broker.expect_reply(&client, "echo", &[b"hello", b"world"], TIMEOUT),
+        vec![b"hello".to_vec(), b"world".to_vec()]
+    );
+}
+
+#[test]
+fn requests_are_echoed_by_library_workers() {
+    let broker = TestBroker::start();
+    let stop = Arc::new(AtomicBool::new(false));
+    let worker = spawn_library_worker(&broker, "echo", echo, &stop);
+    broker.wait_for_service("echo", TIMEOUT);
+
+    let client = broker.client();
+    assert_eq!(
+        broker.expect_reply(&client, "echo", &[&b"hello"[..], b""], TIMEOUT),
+        vec![b"hello".to_vec(), Vec::new()]
+    );
+
+    stop.store(true, Ordering::Relaxed);
+    worker.join().expect("Library worker panicked");
+    broker.wait_for_service_gone("echo", TIMEOUT);
+}
+
+#[test]
+fn killed_workers_expire() {
+    let broker = TestBroker::start();
+    let worker = broker.spawn_worker("echo", |body| body);
+    broker.wait_for_service("echo", TIMEOUT);
+
+    worker.kill();
+    broker.wait_for_service_gone("echo", EXPIRY_TIMEOUT);
+
+    let client = broker.client();
+    let (status_code, _) = broker.expect_error(&client, "echo", &[b"hello"], TIMEOUT);
+    assert_eq!(status_code, "404");
+}
+
+#[test]
+fn cancelled_requests_are_not_handled() {
+    let broker = TestBroker::start();
+    let (_worker, received, release) = spawn_slow_worker(&broker);
+    let client = broker.client();
+
+    // the worker is busy, so the next request waits in the queue of the broker
+    let held = client
+        .send_request("slow", &[b"hold".to_vec()])
+        .expect("Failed to send request");
+    assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"hold");
+    let mut cancelled = client
+        .send_request("slow", &[b"cancelled".to_vec()])
+        .expect("Failed to send request");
+    cancelled.cancel();
+    // answered once the broker handled the cancellation, sent before on the same connection
+    client
+        .send_request("mmi.service", &[b"slow".to_vec()])
+        .and_then(|mut request| request.next())
+        .expect("No answer to MMI request");
+    release.send(()).unwrap();
+    assert_eq!(
+        held.last().map(|answer| answer.payload),
+        Some(vec![b"hold".to_vec()])
+    );
+
+    assert_eq!(
+        broker.expect_reply(&client, "slow", &[b"next"], TIMEOUT),
+        vec![b"next".to_vec()]
+    );
+    let handled: Vec<Vec<u8>> = received.try_iter().collect();
+    assert_eq!(handled, vec![b"next".to_vec()]);
+}
+
+#[test]
+fn requests_cancelled_while_handled_are_not_answered() {
+    let broker = TestBroker::start();
+    let (_worker, received, release) = spawn_slow_worker(&broker);
+    let client = broker.client();
+
+    let mut cancelled = client
+        .send_request("slow", &[b"hold".to_vec()])
+        .expect("Failed to send request");
+    assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"hold");
+    cancelled.cancel();
+    // answered once the broker sent CANCEL to the worker, which reads it once its handler returns
+    client
+        .send_request("mmi.service", &[b"slow".to_vec()])
+        .and_then(|mut request| request.next())
+        .expect("No answer to MMI request");
+    release.send(()).unwrap();
+
+    // the worker skipped the FINAL of the cancelled request, and is sent the next one anyway
+    assert_eq!(
+        broker.expect_reply(&client, "slow", &[b"next"], TIMEOUT),
+        vec![b"next".to_vec()]
+    );
+    assert_eq!(
+        received.try_iter().collect::<Vec<_>>(),
+        vec![b"next".to_vec()]
+    );
+}
+
+#[test]
+fn workers_stopping_cancelled_requests_get_the_next_ones() {
+    let broker = TestBroker::start();
+    let stop = Arc::new(AtomicBool::new(false));
+    let worker = spawn_library_worker(&broker, "slow", hold_until_cancelled, &stop);
+    broker.wait_for_service("slow", TIMEOUT);
+    let client = broker.client();
+
+    // the worker is idle, so the request is dispatched before its cancellation is received
+    client
+        .send_request("slow", &[b"hold".to_vec()])
+        .expect("Failed to send request")
+        .cancel();
+
+    // the worker sent no FINAL for the cancelled request, which must not use up its credit
+    assert_eq!(
+        broker.expect_reply(&client, "slow", &[b"next"], TIMEOUT),
+        vec![b"next".to_vec()]
+    );
+    stop.store(true, Ordering::Relaxed);
+    worker.join().expect("Library worker panicked");
+}
+
+#[test]
+fn requests_expire_while_queued() {
+    let broker = TestBroker::start();
+    let (_worker, received, release) = spawn_slow_worker(&broker);
+    let client = broker.client();
+
+    let held = client
+        .send_request("slow", &[b"hold".to_vec()])
+        .expect("Failed to send request");
+    assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"hold");
+    let options = RequestOptions::default().with_ttl(Duration::from_millis(200));
+    let expired = client
+        .send_request_with_options("slow", &[b"expired".to_vec()], &options)
+        .expect("Failed to send request");
+
+    // the client stops waiting at the deadline, whether or not the broker's ERROR came first
+    if let Some(answer) = expired.last() {
+        assert_eq!(answer.state, ClientRequestState::ERROR);
+        assert_eq!(answer.payload.get(1).map(Vec::as_slice), Some(&b"504"[..]));
+    }
+    release.send(()).unwrap();
+    assert_eq!(
+        held.last().map(|answer| answer.payload),
+        Some(vec![b"hold".to_vec()])
+    );
+
+    assert_eq!(
+        broker.expect_reply(&client, "slow", &[b"next"], TIMEOUT),
+        vec![b"next".to_vec()]
+    );
+    let handled: Vec<Vec<u8>> = received.try_iter().collect();
+    assert_eq!(handled, vec![b"next".to_vec()]);
+}
+
+#[test]
+fn broadcast_requests_are_answered_by_every_worker() {
+    let broker = TestBroker::start();
+    let _first = broker.spawn_worker("health", |_| vec![b"first".to_vec()]);
+    let _second = broker.spawn_worker("health", |_| vec![b"second".to_vec()]);
+    broker.wait_for_service("health", TIMEOUT);
+
+    let client = broker.client();
+    let options = RequestOptions::default()
+        .with_gather(GatherPolicy::All)
+        .with_ttl(TIMEOUT);
+    // the second worker may not be registered yet when the first one is
+    let deadline = Instant::now() + TIMEOUT;
+    let answers = loop {
+        let answers: Vec<_> = client
+            .send_request_with_options("health", &[b"status".to_vec()], &options)
+            .expect("Failed to send request")
+            .collect();
+        if answers.len() == 2 || Instant::now() >= deadline {
+            break answers;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    let states: Vec<_> = answers.iter().map(|answer| &answer.state).collect();
+    assert_eq!(
+        states,
+        vec![&ClientRequestState::PARTIAL, &ClientRequestState::FINAL]
+    );
+    // each answer starts with the identity of its worker
+    let bodies: HashSet<&[u8]> = answers
+        .iter()
+        .map(|answer| answer.payload[1].as_slice())
+        .collect();
+    assert_eq!(bodies, HashSet::from([&b"first"[..], &b"second"[..]]));
+}
+
+#[test]
+fn workers_stopping_gathered_requests_get_the_next_ones() {
+    let broker = TestBroker::start();
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut worker = library_worker(&broker, "health", hold_until_cancelled);
+    worker.set_label("kind", "library");
+    let worker = run_library_worker(worker, &stop);
+    broker.wait_for_service("health", TIMEOUT);
+    let _fast = broker.spawn_worker("health", |_| vec![b"fast".to_vec()]);
+    let client = broker.client();
+    // requests go to each worker in turn once both are registered
+    let deadline = Instant::now() + TIMEOUT;
+    while broker.expect_reply(&client, "health", &[b"probe"], TIMEOUT) != [b"fast"] {
+        assert!(Instant::now() < deadline, "Second worker never registered");
+    }
+
+    // the fast worker meets the gather policy, the library worker being cancelled
+    let options = RequestOptions::default()
+        .with_gather(GatherPolicy::First)
+        .with_ttl(TIMEOUT);
+    let answers: Vec<_> = client
+        .send_request_with_options("health", &[b"hold".to_vec()], &options)
+        .expect("Failed to send request")
+        .collect();
+    assert_eq!(answers.len(), 1);
+    assert_eq!(answers[0].state, ClientRequestState::FINAL);
+    assert_eq!(answers[0].payload[1], b"fast");
+
+    // the library worker sent no FINAL for the cancelled copy, which must not use up its credit
+    let options = RequestOptions::default()
+        .with_label("kind", "library")
+        .with_ttl(TIMEOUT);
+    let answer = client
+        .send_request_with_options("health", &[b"next".to_vec()], &options)
+        .expect("Failed to send request")
+        .last()
+        .expect("No answer from the library worker");
+    assert_eq!(answer.state, ClientRequestState::FINAL);
+    assert_eq!(answer.payload, vec![b"next".to_vec()]);
+    stop.store(true, Ordering::Relaxed);
+    worker.join().expect("Library worker panicked");
+}